@@ -1,5 +1,125 @@
 use clap::{App, Arg, AppSettings, SubCommand};
 
+/// Options shared by the query commands.
+fn query_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("field")
+            .short("f")
+            .long("field")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FIELD")
+            .help("Only search the given field: name, email, file, uid or one of MATES_INDEX_FIELDS. Can be repeated."),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("TERM")
+            .help("Leave out entries matching TERM, like -TERM in the query. Can be repeated."),
+        Arg::with_name("regex")
+            .short("e")
+            .long("regex")
+            .help("Interpret the query as a regular expression, like MATES_MATCH=regex."),
+        Arg::with_name("fuzzy")
+            .short("z")
+            .long("fuzzy")
+            .conflicts_with("regex")
+            .help("Also find entries with typos, best matches first, like MATES_MATCH=fuzzy."),
+        Arg::with_name("phonetic")
+            .long("phonetic")
+            .conflicts_with_all(&["regex", "fuzzy"])
+            .help("Find names sounding like the query, e.g. Meyer for meier, like MATES_MATCH=phonetic."),
+        Arg::with_name("case-sensitive")
+            .short("C")
+            .long("case-sensitive")
+            .conflicts_with_all(&["fuzzy", "phonetic"])
+            .help("Distinguish upper and lower case."),
+        Arg::with_name("sort")
+            .long("sort")
+            .takes_value(true)
+            .value_name("ORDER")
+            .possible_values(&["name", "email", "recent", "contacted"])
+            .help("Sort results by name, email, most recently changed or most recently written to first (see scan-sent). \
+                   By default they're in the order of the index."),
+        Arg::with_name("limit")
+            .long("limit")
+            .takes_value(true)
+            .value_name("N")
+            .help("Print at most N results."),
+        Arg::with_name("offset")
+            .long("offset")
+            .takes_value(true)
+            .value_name("N")
+            .help("Skip the first N results."),
+    ]
+}
+
+/// Option of `file-query` and `email-query` for looking up known addresses.
+fn exact_arg() -> Arg<'static, 'static> {
+    Arg::with_name("exact")
+        .short("x")
+        .long("exact")
+        .conflicts_with_all(&["regex", "fuzzy", "phonetic"])
+        .help("Only match entries whose email (or --field) equals the query. Fail if there's none.")
+}
+
+/// Option of the query commands and `list` for machine-readable output.
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .value_name("FORMAT")
+        .possible_values(&["json", "csv", "tsv", "alot", "notmuch", "khard", "table"])
+        .help("Print results in the given format: json, csv or tsv with one entry per contact, \
+               alot and notmuch (like `notmuch address --format=json`) with one per address, \
+               khard for the table of `khard list` or table for an aligned table fit to the terminal.")
+}
+
+/// Option of `list`, `show` and `search` for coloring output.
+fn color_arg() -> Arg<'static, 'static> {
+    Arg::with_name("color")
+        .long("color")
+        .takes_value(true)
+        .value_name("WHEN")
+        .possible_values(&["auto", "always", "never"])
+        .help("Color names and emails: always, never or auto, the default, when printing to a terminal \
+               and NO_COLOR isn't set.")
+}
+
+/// Option of `file-query` and `list` for ending results with NUL instead of a newline.
+fn print0_arg() -> Arg<'static, 'static> {
+    Arg::with_name("print0")
+        .short("0")
+        .long("print0")
+        .conflicts_with("format")
+        .help("End each result with a NUL character instead of a newline, e.g. for `xargs -0`.")
+}
+
+/// Option of the query commands for printing results in a line format of the user's choice.
+fn template_arg() -> Arg<'static, 'static> {
+    Arg::with_name("template")
+        .long("template")
+        .takes_value(true)
+        .value_name("TEMPLATE")
+        .help("Print each result like TEMPLATE, e.g. '{name} <{email}>\\t{org}'. Fields are name, email, file, uid, \
+               tel, types, categories, collection and those in MATES_INDEX_FIELDS.")
+}
+
+fn header_arg() -> Arg<'static, 'static> {
+    Arg::with_name("header")
+        .long("header")
+        .requires("format")
+        .help("Start csv and tsv output with a line naming the columns.")
+}
+
+fn record_arg() -> Arg<'static, 'static> {
+    Arg::with_name("record")
+        .long("record")
+        .help("Record the printed addresses as used, ranking them higher in future queries.")
+}
+
 pub fn app() -> App<'static, 'static> {
     App::new("mates")
         .version(env!("CARGO_PKG_VERSION"))
@@ -7,19 +127,364 @@ pub fn app() -> App<'static, 'static> {
         .about("A simple commandline addressbook")
         .setting(AppSettings::SubcommandRequired)
         .subcommand(SubCommand::with_name("index")
-                    .about("Rewrite/create the index"))
+                    .about("Rewrite/create the index")
+                    .arg(Arg::with_name("full")
+                         .long("full")
+                         .help("Parse all files again, even those unchanged since the last run."))
+                    .arg(Arg::with_name("verify")
+                         .long("verify")
+                         .conflicts_with_all(&["full", "watch"])
+                         .help("Don't rebuild the index, but report entries that don't match the contact files."))
+                    .arg(Arg::with_name("watch")
+                         .long("watch")
+                         .help("Keep running and update the index whenever contacts change. Requires the watch feature.")))
         .subcommand(SubCommand::with_name("mutt-query")
                     .about("Search for contact, output is usable for mutt's query_command.")
+                    .arg(Arg::with_name("category")
+                         .short("c")
+                         .long("category")
+                         .takes_value(true)
+                         .value_name("CATEGORY")
+                         .help("Only return contacts in this category."))
+                    .args(&query_args())
+                    .arg(record_arg())
+                    .arg(format_arg())
+                    .arg(header_arg())
+                    .arg(template_arg().conflicts_with("format"))
+                    .arg(Arg::with_name("query").index(1)))
+        .subcommand(SubCommand::with_name("aerc-query")
+                    .about("Search for contact, output is usable for aerc's address-book-cmd.")
+                    .arg(Arg::with_name("category")
+                         .short("c")
+                         .long("category")
+                         .takes_value(true)
+                         .value_name("CATEGORY")
+                         .help("Only return contacts in this category."))
+                    .args(&query_args())
                     .arg(Arg::with_name("query").index(1)))
         .subcommand(SubCommand::with_name("file-query")
                     .about("Search for contact, return just the filename.")
+                    .args(&query_args())
+                    .arg(exact_arg())
+                    .arg(format_arg())
+                    .arg(header_arg())
+                    .arg(template_arg().conflicts_with("format"))
+                    .arg(print0_arg())
+                    .arg(Arg::with_name("uid")
+                         .long("uid")
+                         .takes_value(true)
+                         .value_name("UID")
+                         .conflicts_with("query")
+                         .help("Find the contact with this UID. Fail if there's none."))
+                    .arg(Arg::with_name("query").index(1)))
+        .subcommand(SubCommand::with_name("vcf-query")
+                    .about("Search for contact, print the vCard files of all matches.")
+                    .args(&query_args())
+                    .arg(exact_arg())
                     .arg(Arg::with_name("query").index(1)))
         .subcommand(SubCommand::with_name("email-query")
                     .about("Search for contact, return 'name <email>'.")
+                    .arg(Arg::with_name("show-type")
+                         .short("t")
+                         .long("show-type")
+                         .help("Append the TYPE of each address, e.g. WORK, separated by a tab."))
+                    .arg(Arg::with_name("category")
+                         .short("c")
+                         .long("category")
+                         .takes_value(true)
+                         .value_name("CATEGORY")
+                         .help("Only return contacts in this category."))
+                    .arg(Arg::with_name("join")
+                         .short("j")
+                         .long("join")
+                         .conflicts_with("show-type")
+                         .help("Print all addresses on one line, separated by commas, e.g. to mail a whole category."))
+                    .args(&query_args())
+                    .arg(exact_arg())
+                    .arg(record_arg())
+                    .arg(format_arg().conflicts_with_all(&["show-type", "join", "stdin"]))
+                    .arg(header_arg())
+                    .arg(template_arg().conflicts_with_all(&["format", "show-type", "join"]))
+                    .arg(Arg::with_name("stdin")
+                         .long("stdin")
+                         .conflicts_with("query")
+                         .help("Read one query per line from stdin. Each result is printed after its query and a tab."))
+                    .arg(Arg::with_name("query").index(1)))
+        .subcommand(SubCommand::with_name("scan-sent")
+                    .about("Record when addresses were last written to, from the messages in Maildirs of sent mail.")
+                    .arg(Arg::with_name("maildir")
+                         .index(1)
+                         .multiple(true)
+                         .required(true)
+                         .help("Maildirs of sent mail, e.g. ~/Mail/Sent.")))
+        .subcommand(SubCommand::with_name("touch")
+                    .about("Record email addresses as used, ranking them higher in future queries.")
+                    .arg(Arg::with_name("address")
+                         .index(1)
+                         .multiple(true)
+                         .help("Addresses such as 'name <email>' or 'email'. Read one per line from stdin if none are given.")))
+        .subcommand(SubCommand::with_name("list")
+                    .about("List all contacts as 'name <email>'.")
+                    .arg(Arg::with_name("sort")
+                         .short("s")
+                         .long("sort")
+                         .help("Sort by name."))
+                    .arg(format_arg())
+                    .arg(header_arg())
+                    .arg(print0_arg())
+                    .arg(color_arg()))
+        .subcommand(SubCommand::with_name("pick")
+                    .about("Select a contact interactively, print its email address.")
+                    .arg(Arg::with_name("file")
+                         .long("file")
+                         .help("Print the contact's filepath instead.")))
+        .subcommand(SubCommand::with_name("search")
+                    .about("Search contacts by field, all given filters must match. Print 'name <email>'.")
+                    .arg(Arg::with_name("name").long("name").takes_value(true).multiple(true).number_of_values(1)
+                         .help("Name contains the given text."))
+                    .arg(Arg::with_name("email").long("email").takes_value(true).multiple(true).number_of_values(1)
+                         .help("An email address contains the given text."))
+                    .arg(Arg::with_name("org").long("org").takes_value(true).multiple(true).number_of_values(1)
+                         .help("Organization contains the given text."))
+                    .arg(Arg::with_name("category").long("category").takes_value(true).multiple(true).number_of_values(1)
+                         .help("Categories contain the given text."))
+                    .arg(Arg::with_name("tel").long("tel").takes_value(true).multiple(true).number_of_values(1)
+                         .help("A phone number contains the given text."))
+                    .arg(Arg::with_name("sort")
+                         .short("s")
+                         .long("sort")
+                         .help("Sort by name."))
+                    .arg(format_arg())
+                    .arg(header_arg())
+                    .arg(color_arg()))
+        .subcommand(SubCommand::with_name("group-query")
+                    .about("List contacts in the given category: name, email and filepath, tab-separated.")
+                    .arg(Arg::with_name("category").index(1).required(true)))
+        .subcommand(SubCommand::with_name("groups")
+                    .about("List all categories with the number of contacts in each."))
+        .subcommand(SubCommand::with_name("complete")
+                    .about("Print email addresses matching a partial name or email, for shell completion.")
+                    .arg(Arg::with_name("with-name")
+                         .short("n")
+                         .long("with-name")
+                         .help("Print 'name <email>' instead of only the address."))
+                    .arg(Arg::with_name("partial").index(1)))
+        .subcommand(SubCommand::with_name("phone-query")
+                    .about("Search for contact, return 'name <phone number>'.")
+                    .args(&query_args())
+                    .arg(template_arg())
                     .arg(Arg::with_name("query").index(1)))
         .subcommand(SubCommand::with_name("add")
-                    .about("Take mail from stdin, add sender to contacts. Print filename."))
+                    .about("Take mail from stdin, add sender to contacts. Print filename.")
+                    .arg(Arg::with_name("rev-from-date")
+                         .long("rev-from-date")
+                         .help("Use the mail's Date header as the contact's revision timestamp.")))
+        .subcommand(SubCommand::with_name("harvest")
+                    .about("Create contacts for the people in the From, To and Cc headers of the mail in a Maildir, \
+                            notmuch database or mbox file, unless they are in MATES_DIR already. Print new filenames.")
+                    .arg(Arg::with_name("maildir")
+                         .index(1)
+                         .required_unless_one(&["notmuch", "mbox"])
+                         .conflicts_with_all(&["notmuch", "mbox"])
+                         .help("A Maildir, e.g. ~/Mail. All of its folders are read unless --folder is given."))
+                    .arg(Arg::with_name("folder")
+                         .long("folder")
+                         .takes_value(true)
+                         .value_name("FOLDER")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .requires("maildir")
+                         .help("Only read this folder of the Maildir, e.g. Sent. May be given several times."))
+                    .arg(Arg::with_name("notmuch")
+                         .long("notmuch")
+                         .takes_value(true)
+                         .value_name("QUERY")
+                         .conflicts_with("mbox")
+                         .help("Read the messages matching a notmuch query instead, e.g. tag:sent, with the \
+                                notmuch program."))
+                    .arg(Arg::with_name("mbox")
+                         .long("mbox")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Read the messages in an mbox file instead, such as a mailing list archive or \
+                                a Gmail export. May be given several times."))
+                    .arg(Arg::with_name("threshold")
+                         .long("threshold")
+                         .takes_value(true)
+                         .value_name("N")
+                         .help("Only harvest addresses seen in more than N messages, 1 by default."))
+                    .arg(Arg::with_name("exclude")
+                         .long("exclude")
+                         .takes_value(true)
+                         .value_name("EMAIL")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Leave out this address, e.g. your own. May be given several times."))
+                    .arg(Arg::with_name("dry-run")
+                         .long("dry-run")
+                         .help("Print the addresses that would be harvested after the number of messages they \
+                                were seen in, instead of creating contacts.")))
+        .subcommand(SubCommand::with_name("new")
+                    .about("Create a new contact and open it interactively. Print filename.")
+                    .arg(Arg::with_name("name").index(1))
+                    .arg(Arg::with_name("email").index(2)))
         .subcommand(SubCommand::with_name("edit")
                     .about("Open contact (given by filepath or search-string) interactively.")
+                    .arg(Arg::with_name("no-interactive")
+                         .long("no-interactive")
+                         .help("Fail if several contacts match, instead of asking which one to edit."))
                     .arg(Arg::with_name("file-or-query").index(1)))
+        .subcommand(SubCommand::with_name("show")
+                    .about("Print contact (given by filepath or search-string) in a human-readable format.")
+                    .arg(Arg::with_name("file-or-query").index(1))
+                    .arg(color_arg()))
+        .subcommand(SubCommand::with_name("fmt")
+                    .about("Rewrite contact (given by filepath or search-string) with sorted properties and \
+                            normalized folding. Without one, or with -, format stdin to stdout.")
+                    .arg(Arg::with_name("file-or-query").index(1)))
+        .subcommand(SubCommand::with_name("qr")
+                    .about("Show contact (given by filepath or search-string) as a QR code, e.g. to scan it with a phone.")
+                    .arg(Arg::with_name("file-or-query").index(1))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write a PNG image to FILE instead of printing to the terminal.")))
+        .subcommand(SubCommand::with_name("remove")
+                    .about("Delete contact (given by filepath or search-string), after asking for confirmation.")
+                    .arg(Arg::with_name("file-or-query").index(1).required(true))
+                    .arg(Arg::with_name("force")
+                         .short("f")
+                         .long("force")
+                         .help("Don't ask for confirmation.")))
+        .subcommand(SubCommand::with_name("rename")
+                    .about("Change the name of a contact (given by filepath or search-string).")
+                    .arg(Arg::with_name("file-or-query").index(1).required(true))
+                    .arg(Arg::with_name("name").index(2).required(true)))
+        .subcommand(SubCommand::with_name("add-email")
+                    .about("Add an email address to an existing contact (given by filepath or search-string).")
+                    .arg(Arg::with_name("file-or-query").index(1).required(true))
+                    .arg(Arg::with_name("email").index(2))
+                    .arg(Arg::with_name("from-stdin")
+                         .long("from-stdin")
+                         .conflicts_with("email")
+                         .help("Take mail from stdin and use the sender's address.")))
+        .subcommand(SubCommand::with_name("get")
+                    .about("Print the values of a property of a contact (given by filepath or search-string).")
+                    .arg(Arg::with_name("file-or-query").index(1).required(true))
+                    .arg(Arg::with_name("property").index(2).required(true)))
+        .subcommand(SubCommand::with_name("set")
+                    .about("Set a property of a contact (given by filepath or search-string), e.g. TEL. The parts of N, ADR and ORG are separated by ';', those of CATEGORIES and NICKNAME by ','.")
+                    .arg(Arg::with_name("file-or-query").index(1).required(true))
+                    .arg(Arg::with_name("property").index(2).required(true))
+                    .arg(Arg::with_name("value").index(3).required(true))
+                    .arg(Arg::with_name("append")
+                         .short("a")
+                         .long("append")
+                         .help("Add the property instead of replacing existing ones.")))
+        .subcommand(SubCommand::with_name("mv")
+                    .about("Move contact (given by filepath or search-string) to another directory. Print new filename.")
+                    .arg(Arg::with_name("file-or-query").index(1).required(true))
+                    .arg(Arg::with_name("target-dir").index(2).required(true))
+                    .arg(Arg::with_name("target-index")
+                         .long("target-index")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Index of the target directory to add the contact to. Defaults to MATES_INDEX if the target directory is one of the collections in MATES_DIR.")))
+        .subcommand(SubCommand::with_name("cp")
+                    .about("Copy contact (given by filepath or search-string) to another directory, with a new UID. Print new filename.")
+                    .arg(Arg::with_name("file-or-query").index(1).required(true))
+                    .arg(Arg::with_name("target-dir").index(2).required(true))
+                    .arg(Arg::with_name("target-index")
+                         .long("target-index")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Index of the target directory to add the contact to. Defaults to MATES_INDEX if the target directory is one of the collections in MATES_DIR.")))
+        .subcommand(SubCommand::with_name("birthdays")
+                    .about("List upcoming birthdays with date, name and age, tab-separated.")
+                    .arg(Arg::with_name("next")
+                         .long("next")
+                         .takes_value(true)
+                         .value_name("N")
+                         .help("Only show the next N birthdays.")))
+        .subcommand(SubCommand::with_name("export")
+                    .about("Write contacts (all, or those matching the query) into one vCard file.")
+                    .arg(Arg::with_name("query").index(1))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .takes_value(true)
+                         .value_name("FORMAT")
+                         .possible_values(&["vcf", "ldif", "org", "html", "ics"])
+                         .help("Write vCards (the default), LDIF, e.g. for Thunderbird or an LDAP directory, \
+                                org-contacts entries for Emacs, an HTML page for printing or an iCalendar \
+                                file of birthdays and anniversaries."))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write to FILE instead of stdout."))
+                    .arg(Arg::with_name("vcard3")
+                         .long("vcard3")
+                         .help("Convert contacts to vCard 3.0 for compatibility.")))
+        .subcommand(SubCommand::with_name("alias-export")
+                    .about("Write a mutt alias file with an alias for every email address of all contacts.")
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write to FILE instead of stdout, e.g. ~/.mutt/aliases.")))
+        .subcommand(SubCommand::with_name("pine-export")
+                    .about("Write an (al)pine address book with all contacts that have an email address.")
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write to FILE instead of stdout, e.g. ~/.addressbook.")))
+        .subcommand(SubCommand::with_name("import")
+                    .about("Import the contacts in a vCard, CSV or LDIF file into MATES_DIR and rebuild the index. Print new filenames.")
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .takes_value(true)
+                         .value_name("FORMAT")
+                         .possible_values(&["vcf", "csv", "outlook-csv", "ldif", "google", "abook", "mutt-alias"])
+                         .help("Read FILE as vcf (the default), csv with a header line naming the columns, \
+                                outlook-csv for the CSV export of Outlook, ldif as exported by Thunderbird and \
+                                LDAP directories, google for the CSV or vCard export of Google Contacts, merging \
+                                contacts into existing ones with the same email address, abook for abook's \
+                                addressbook file or mutt-alias for a file of mutt aliases."))
+                    .arg(Arg::with_name("map")
+                         .long("map")
+                         .takes_value(true)
+                         .value_name("MAPPING")
+                         .help("Which CSV columns to import, e.g. \"name=Full Name,email=E-mail,phone.cell=Mobile\". \
+                                By default columns named after fields are imported."))
+                    .arg(Arg::with_name("file").index(1).required(true)))
+        .subcommand(SubCommand::with_name("normalize")
+                    .about("Rewrite contacts (all, or those matching the query) in a canonical format. Print changed filenames.")
+                    .arg(Arg::with_name("query").index(1)))
+        .subcommand(SubCommand::with_name("stats")
+                    .about("Print statistics about the address book.")
+                    .arg(Arg::with_name("since")
+                         .long("since")
+                         .takes_value(true)
+                         .value_name("DATE")
+                         .help("Also count contacts not modified since DATE (YYYY-MM-DD), according to REV.")))
+        .subcommand(SubCommand::with_name("check")
+                    .about("Check all contacts for parse errors, missing FN or UID, duplicate UIDs and invalid emails."))
+        .subcommand(SubCommand::with_name("dupes")
+                    .about("Find contacts sharing an email address or name. Print each group's filepaths on one line."))
+        .subcommand(SubCommand::with_name("merge")
+                    .about("Merge the second contact into the first one and delete the second one.")
+                    .arg(Arg::with_name("query-a").index(1).required(true))
+                    .arg(Arg::with_name("query-b").index(2).required(true)))
+        .subcommand(SubCommand::with_name("doctor")
+                    .about("Check the configuration and environment for common problems."))
+        .subcommand(SubCommand::with_name("undo")
+                    .about("Restore the most recently deleted contact from the trash."))
 }
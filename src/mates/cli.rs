@@ -1,18 +1,35 @@
 use std::borrow::ToOwned;
+use std::cmp;
+use std::collections::{BTreeMap,HashMap,HashSet};
 use std::env;
 use std::error::Error;
 use std::fmt;use std::fs;
 use std::io::{Read,Write};
 use std::io;
+use std::iter;
 use std::path;
 use std::process;
+use std::usize;
 
 use atomicwrites::{AtomicFile,AllowOverwrite};
+use chrono::{DateTime,Datelike,Local,NaiveDate,Utc};
+use clap::{self,ArgMatches};
+use vobject;
 
 use utils;
-use utils::CustomPathExt;
+use utils::{NameOrder,VcardVersion};
 use app;
 use editor;
+use export::{self,ExportFormat};
+use qr::QrCode;
+use color::Colors;
+use import::{self,ImportFormat};
+use harvest::{self,Harvest};
+use query::{MatchMode,Query,QueryField,QueryOptions};
+use output::{self,OutputFormat};
+use usage;
+#[cfg(feature = "sqlite")]
+use sqlite_index;
 
 
 #[inline]
@@ -29,223 +46,2491 @@ fn get_envvar(key: &str) -> Option<String> {
     }
 }
 
-fn build_index(outfile: &path::Path, dir: &path::Path) -> MainResult<()> {
-    if !dir.is_dir() {
-        return Err(MainError::new("MATES_DIR must be a directory.").into());
+/// A program and its arguments from the environment variable `key`, split like a shell would.
+/// `None` if it's unset or empty.
+fn command_from_envvar(key: &str) -> Result<Option<Vec<String>>, String> {
+    match get_envvar(key) {
+        Some(x) => {
+            let words = try!(utils::split_shell_words(&x).map_err(|e| format!("{}: {}", key, e)));
+            Ok(if words.is_empty() { None } else { Some(words) })
+        },
+        None => Ok(None)
+    }
+}
+
+fn build_index(config: &Configuration, full: bool) -> MainResult<()> {
+    match config.index_backend {
+        IndexBackend::Text | IndexBackend::Binary => build_file_index(config, full),
+        IndexBackend::Sqlite => build_sqlite_index(config, full)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn build_sqlite_index(config: &Configuration, full: bool) -> MainResult<()> {
+    try!(check_collections(config));
+
+    let conn = try!(sqlite_index::open(&config.index_path));
+    let fpaths = try!(config.contact_files());
+    let mut errors = false;
+
+    let mut changed = vec![];
+    for pathbuf in fpaths.iter() {
+        let mtime = utils::file_mtime(&pathbuf).unwrap_or_else(|_| String::new());
+        if full || mtime.is_empty() || !try!(sqlite_index::is_current(&conn, &config.indexed_path(pathbuf), &mtime)) {
+            changed.push(pathbuf.clone());
+        }
+    }
+
+    for (pathbuf, contact) in changed.iter().zip(utils::read_contacts_parallel(&changed)) {
+        let contact = match contact {
+            Ok(x) => x,
+            Err(e) => {
+                writeln!(&mut io::stderr(), "Error while reading {}: {}", pathbuf.display(), e).unwrap();
+                errors = true;
+                continue
+            }
+        };
+        if let Err(e) = sqlite_index::upsert_contact(&conn, &contact, &config.indexed_path(pathbuf),
+                                                     &config.collection_name(pathbuf), &config.index_fields,
+                                                     config.name_order) {
+            writeln!(&mut io::stderr(), "Error while indexing {}: {}", pathbuf.display(), e).unwrap();
+            errors = true;
+        }
+    }
+    let filepaths: Vec<_> = fpaths.iter().map(|x| config.indexed_path(x)).collect();
+    try!(sqlite_index::retain_contacts(&conn, &filepaths));
+
+    if errors {
+        Err(MainError::new("Several errors happened while generating the index.").into())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn build_sqlite_index(_config: &Configuration, _full: bool) -> MainResult<()> {
+    unreachable!("Configuration rejects the SQLite backend without the sqlite feature.")
+}
+
+fn check_collections(config: &Configuration) -> MainResult<()> {
+    for dir in config.collections.iter() {
+        if !dir.is_dir() {
+            return Err(MainError::new(format!("MATES_DIR: {} must be a directory.", dir.display()))
+                       .with_kind(ErrorKind::Config).with_path(dir.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+/// The index lines of a contact in one of the configured collections.
+fn index_entries(config: &Configuration, contact: &utils::Contact) -> io::Result<String> {
+    utils::index_item_from_contact(contact, &config.indexed_path(&contact.path),
+                                   &config.collection_name(&contact.path), &config.index_fields,
+                                   config.name_order)
+}
+
+/// Write the text or binary index for all contacts. Unless `full` is set, entries of files that
+/// haven't been modified since the last run are reused instead of parsing the files again.
+fn build_file_index(config: &Configuration, full: bool) -> MainResult<()> {
+    try!(check_collections(config));
+
+    let mut previous: HashMap<path::PathBuf, Vec<utils::IndexItem>> = HashMap::new();
+    if !full && config.index_path.is_file() {
+        // An unreadable index is simply built from scratch.
+        let index = utils::read_index_file(&config.index_path).unwrap_or_else(|_| String::new());
+        // Lines written by older versions lack some columns, so their files are parsed again.
+        let columns = utils::INDEX_COLUMNS + config.index_fields.len();
+        for line in index.lines().filter(|x| x.split('\t').count() == columns) {
+            let item = utils::IndexItem::new(line.to_owned());
+            if let Some(filepath) = item.filepath.clone() {
+                previous.entry(filepath).or_insert_with(Vec::new).push(item);
+            }
+        }
+    }
+
+    let fpaths = try!(config.contact_files());
+    let reused: Vec<Option<&Vec<utils::IndexItem>>> = fpaths.iter().map(|pathbuf| {
+        let items = match previous.get(&config.indexed_path(pathbuf)) {
+            Some(x) => x,
+            None => return None
+        };
+        let mtime = utils::file_mtime(pathbuf).unwrap_or_else(|_| String::new());
+        if !mtime.is_empty() && items.iter().all(|x| x.mtime == mtime) {
+            Some(items)
+        } else {
+            None
+        }
+    }).collect();
+
+    let changed: Vec<path::PathBuf> = fpaths.iter().zip(reused.iter())
+        .filter(|&(_, items)| items.is_none())
+        .map(|(pathbuf, _)| pathbuf.clone())
+        .collect();
+    let mut contacts = utils::read_contacts_parallel(&changed).into_iter();
+
+    let mut output = String::new();
+    let mut errors = false;
+
+    // Merge both in the order of the files, so the index doesn't depend on thread scheduling.
+    for (pathbuf, items) in fpaths.iter().zip(reused.into_iter()) {
+        if let Some(items) = items {
+            for item in items {
+                output.push_str(&item.to_line());
+            }
+            continue;
+        }
+
+        let contact = match contacts.next().expect("Internal error.") {
+            Ok(x) => x,
+            Err(e) => {
+                writeln!(&mut io::stderr(), "Error while reading {}: {}", pathbuf.display(), e).unwrap();
+                errors = true;
+                continue
+            }
+        };
+
+        match index_entries(config, &contact) {
+            Ok(index_string) => output.push_str(&index_string),
+            Err(e) => {
+                writeln!(&mut io::stderr(), "Error while indexing {}: {}", pathbuf.display(), e).unwrap();
+                errors = true;
+                continue
+            }
+        };
+    };
+
+    try!(utils::write_index_file(&config.index_path, &output,
+                                 config.index_backend == IndexBackend::Binary));
+
+    if errors {
+        Err(MainError::new("Several errors happened while generating the index.").into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Update the index whenever a contact file is created, modified or removed. Never returns unless
+/// watching fails.
+#[cfg(feature = "watch")]
+fn watch_index(config: &Configuration) -> MainResult<()> {
+    use notify::{RecursiveMode, Watcher};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = try!(notify::recommended_watcher(tx));
+    for dir in config.collections.iter() {
+        try!(watcher.watch(dir, RecursiveMode::NonRecursive));
+        println!("Watching \"{}\"...", dir.display());
+    }
+
+    let is_contact = |event: &notify::Event| {
+        event.paths.iter().any(|x| x.extension().map_or(false, |ext| ext == "vcf"))
+    };
+
+    loop {
+        let event = try!(try!(rx.recv().map_err(|_| MainError::new("Watcher stopped."))));
+        if !is_contact(&event) {
+            continue;
+        }
+        // Editors and vdirsyncer touch many files at once, index them in one go.
+        while let Ok(event) = rx.recv_timeout(Duration::from_millis(200)) {
+            try!(event);
+        }
+        if let Err(e) = build_index(config, false) {
+            writeln!(&mut io::stderr(), "{}", e).unwrap();
+        }
+    }
+}
+
+#[cfg(not(feature = "watch"))]
+fn watch_index(_config: &Configuration) -> MainResult<()> {
+    Err(MainError::new("mates was built without the watch feature, needed for --watch.").into())
+}
+
+/// Update the index if any contacts changed since it was written, so queries don't return
+/// outdated results after syncing. Errors in single contacts are reported but don't stop the
+/// command.
+fn refresh_index(config: &Configuration) -> MainResult<()> {
+    let stale = match utils::index_is_stale(config) {
+        Ok(x) => x,
+        Err(e) => return Err(MainError::new(format!("Error while checking the index: {}", e))
+                             .with_kind(ErrorKind::Index).with_path(config.index_path.clone()).into())
+    };
+    if stale {
+        if let Err(e) = build_index(config, false) {
+            writeln!(&mut io::stderr(), "{}", e).unwrap();
+        }
+    }
+    Ok(())
+}
+
+pub fn cli_main() {
+    let matches = match app::app().get_matches_safe() {
+        Ok(x) => x,
+        Err(ref e) if e.kind == clap::ErrorKind::HelpDisplayed || e.kind == clap::ErrorKind::VersionDisplayed => e.exit(),
+        Err(e) => {
+            writeln!(&mut io::stderr(), "{}", e).unwrap();
+            process::exit(ErrorKind::InvalidArgument.exit_status());
+        }
+    };
+    if let Err(e) = run_command(&matches) {
+        let (kind, path) = match e.downcast_ref::<MainError>() {
+            Some(x) => (x.kind(), x.path().map(|p| p.display().to_string())),
+            None => (ErrorKind::Other, None)
+        };
+        let json = matches.subcommand_name()
+            .and_then(|x| matches.subcommand_matches(x))
+            .map_or(false, |x| x.value_of("format") == Some("json"));
+        if json {
+            writeln!(&mut io::stderr(), "{{\"kind\": {}, \"message\": {}, \"path\": {}}}",
+                     output::json_string(kind.name()), output::json_string(&e.to_string()),
+                     path.map_or("null".to_owned(), |x| output::json_string(&x))).unwrap();
+        } else {
+            writeln!(&mut io::stderr(), "{}", e).unwrap();
+        }
+        process::exit(kind.exit_status());
+    }
+}
+
+/// The query given to a query command. Unless it names a column itself, like `email:gmail.com`,
+/// it's restricted to the columns given with `--field`, or the email with `--exact`.
+fn query_from_matches(config: &Configuration, submatches: &ArgMatches) -> MainResult<Query> {
+    query_from_text(config, submatches, submatches.value_of("query").unwrap_or(""))
+}
+
+/// Parse `text` as a query, interpreted according to the options in `submatches`.
+fn query_from_text(config: &Configuration, submatches: &ArgMatches, text: &str) -> MainResult<Query> {
+    let mut options = QueryOptions::default();
+    options.mode = if submatches.is_present("exact") {
+        MatchMode::Exact
+    } else if submatches.is_present("regex") {
+        MatchMode::Regex
+    } else if submatches.is_present("fuzzy") {
+        MatchMode::Fuzzy
+    } else if submatches.is_present("phonetic") {
+        MatchMode::Phonetic
+    } else {
+        config.match_mode
+    };
+    options.case_sensitive = submatches.is_present("case-sensitive");
+
+    let mut fields = vec![];
+    for name in submatches.values_of("field").into_iter().flat_map(|x| x) {
+        match QueryField::parse(name, &config.index_fields) {
+            Some(x) => fields.push(x),
+            None => return Err(MainError::new(format!(
+                "Invalid field: {}, must be name, email, file, uid or one of MATES_INDEX_FIELDS.", name))
+                               .with_kind(ErrorKind::InvalidArgument).into())
+        }
+    }
+    if fields.is_empty() && options.mode == MatchMode::Exact {
+        fields.push(QueryField::Email);
+    }
+    let mut query = try!(Query::parse(text, &options, &config.index_fields).map_err(invalid_query));
+    for text in submatches.values_of("exclude").into_iter().flat_map(|x| x) {
+        query = try!(query.exclude(text, &options, &config.index_fields).map_err(invalid_query));
+    }
+    Ok(query.in_fields(fields))
+}
+
+fn invalid_query(e: io::Error) -> MainError {
+    MainError::new(e.to_string()).with_kind(ErrorKind::InvalidArgument)
+}
+
+/// An error reading the index.
+fn index_error(config: &Configuration, e: io::Error) -> MainError {
+    MainError::new(format!("Error while reading the index: {}", e))
+        .with_kind(ErrorKind::Index).with_path(config.index_path.clone())
+}
+
+pub fn cli_main_raw() -> MainResult<()> {
+    run_command(&app::app().get_matches())
+}
+
+fn run_command(matches: &ArgMatches) -> MainResult<()> {
+    let command = matches.subcommand_name().unwrap();
+
+    if command == "doctor" {
+        return doctor();
+    }
+
+    let config = match Configuration::new() {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(MainError::new(format!("Error while reading configuration: {}", e))
+                       .with_kind(ErrorKind::Config).into());
+        }
+    };
+
+    let submatches = matches.subcommand_matches(command).expect("Internal error.");
+
+    if command != "index" && config.auto_index {
+        try!(refresh_index(&config));
+    }
+
+    match command {
+        "index" if submatches.is_present("verify") => {
+            try!(verify_index(&config));
+        },
+        "index" => {
+            println!("Rebuilding index file \"{}\"...", config.index_path.display());
+            try!(build_index(&config, submatches.is_present("full")));
+            if submatches.is_present("watch") {
+                try!(watch_index(&config));
+            }
+        },
+        "mutt-query" => {
+            let query = try!(query_from_matches(&config, submatches));
+            try!(mutt_query(&config, &query, submatches.value_of("category"), submatches.is_present("record"),
+                            &try!(ResultFormat::from_matches(&config, submatches)),
+                            &try!(ResultOptions::from_matches(&config, submatches)).with_usage(&config)));
+        },
+        "aerc-query" => {
+            let query = try!(query_from_matches(&config, submatches));
+            try!(aerc_query(&config, &query, submatches.value_of("category"),
+                            &try!(ResultOptions::from_matches(&config, submatches)).with_usage(&config)));
+        },
+        "file-query" => {
+            let query = match submatches.value_of("uid") {
+                Some(uid) => {
+                    let options = QueryOptions { mode: MatchMode::Exact, case_sensitive: true };
+                    try!(Query::with_options(uid, &options).map_err(invalid_query)).in_fields(vec![QueryField::Uid])
+                },
+                None => try!(query_from_matches(&config, submatches))
+            };
+            let count = try!(file_query(&config, &query, &try!(ResultFormat::from_matches(&config, submatches)),
+                                        &try!(ResultOptions::from_matches(&config, submatches)), line_end(submatches)));
+            if count == 0 && (submatches.is_present("exact") || submatches.is_present("uid")) {
+                return Err(MainError::new("No such contact.").with_kind(ErrorKind::NotFound).into());
+            }
+        },
+        "vcf-query" => {
+            let query = try!(query_from_matches(&config, submatches));
+            let count = try!(vcf_query(&config, &query, &try!(ResultOptions::from_matches(&config, submatches))));
+            if count == 0 && submatches.is_present("exact") {
+                return Err(MainError::new("No such contact.").with_kind(ErrorKind::NotFound).into());
+            }
+        },
+        "email-query" => {
+            let output = EmailOutput {
+                show_type: submatches.is_present("show-type"),
+                join: submatches.is_present("join"),
+                record: submatches.is_present("record"),
+                format: try!(ResultFormat::from_matches(&config, submatches))
+            };
+            let category = submatches.value_of("category");
+            let results = try!(ResultOptions::from_matches(&config, submatches)).with_usage(&config);
+            if submatches.is_present("stdin") {
+                let mut s = String::new();
+                try!(io::stdin().read_to_string(&mut s));
+                let mut missing = false;
+                for text in s.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                    let query = try!(query_from_text(&config, submatches, text));
+                    let count = try!(email_query(&config, &query, category, &output, Some(text), &results));
+                    missing = missing || count == 0;
+                }
+                if missing && submatches.is_present("exact") {
+                    return Err(MainError::new("No such contact for some of the queries.").with_kind(ErrorKind::NotFound).into());
+                }
+            } else {
+                let query = try!(query_from_matches(&config, submatches));
+                let count = try!(email_query(&config, &query, category, &output, None, &results));
+                if count == 0 && submatches.is_present("exact") {
+                    return Err(MainError::new("No such contact.").with_kind(ErrorKind::NotFound).into());
+                }
+            }
+        },
+        "scan-sent" => {
+            let maildirs: Vec<&str> = submatches.values_of("maildir").map_or(vec![], |x| x.collect());
+            try!(scan_sent(&config, &maildirs));
+        },
+        "touch" => {
+            let addresses = submatches.values_of("address").map_or(vec![], |x| x.map(|x| x.to_owned()).collect());
+            try!(touch_addresses(&config, addresses));
+        },
+        "list" => {
+            try!(list_contacts(&config, submatches.is_present("sort"), try!(output_format(submatches)),
+                               line_end(submatches), Colors::from_setting(submatches.value_of("color"))));
+        },
+        "phone-query" => {
+            let query = try!(query_from_matches(&config, submatches));
+            try!(phone_query(&config, &query, &try!(ResultFormat::from_matches(&config, submatches)), &try!(ResultOptions::from_matches(&config, submatches))));
+        },
+        "pick" => {
+            try!(pick_contact(&config, submatches.is_present("file")));
+        },
+        "search" => {
+            let mut filters = vec![];
+            for &(arg, prop_name) in [("name", "FN"), ("email", "EMAIL"), ("org", "ORG"),
+                                      ("category", "CATEGORIES"), ("tel", "TEL")].iter() {
+                if let Some(values) = submatches.values_of(arg) {
+                    for value in values {
+                        filters.push((prop_name, value));
+                    }
+                }
+            }
+            try!(search_contacts(&config, &filters, submatches.is_present("sort"), try!(output_format(submatches)),
+                                 Colors::from_setting(submatches.value_of("color"))));
+        },
+        "group-query" => {
+            let category = submatches.value_of("category").unwrap_or("");
+            try!(group_query(&config, &category[..]));
+        },
+        "groups" => {
+            try!(list_groups(&config));
+        },
+        "complete" => {
+            let partial = submatches.value_of("partial").unwrap_or("");
+            try!(complete(&config, &partial[..], submatches.is_present("with-name")));
+        },
+        "add" => {
+            let stdin = io::stdin();
+            let mut email = String::new();
+            try!(stdin.lock().read_to_string(&mut email));
+            let contact = try!(utils::add_contact_from_email(
+                &config,
+                &email[..],
+                submatches.is_present("rev-from-date")
+            ));
+            println!("{}", contact.path.display());
+            try!(append_to_index(&config, &contact));
+        },
+        "harvest" => {
+            let threshold = match submatches.value_of("threshold") {
+                Some(x) => try!(x.parse().map_err(|_| MainError::new(format!("Invalid number: {}", x))
+                                                     .with_kind(ErrorKind::InvalidArgument))),
+                None => 1
+            };
+            let mut harvest = Harvest::new();
+            if let Some(query) = submatches.value_of("notmuch") {
+                for fpath in try!(harvest::notmuch_files(query)) {
+                    harvest.add_message(&try!(utils::read_email_header(&fpath)));
+                }
+            } else if let Some(mboxes) = submatches.values_of("mbox") {
+                for mbox in mboxes {
+                    let mbox = path::Path::new(mbox);
+                    try!(harvest::read_mbox_headers(mbox, |x| harvest.add_message(x)).map_err(|e| {
+                        let kind = if e.kind() == io::ErrorKind::NotFound { ErrorKind::NotFound } else { ErrorKind::Other };
+                        MainError::new(format!("Error while reading {}: {}", mbox.display(), e)).with_kind(kind).with_path(mbox)
+                    }));
+                }
+            } else {
+                let root = path::Path::new(submatches.value_of("maildir").unwrap());
+                let folders: Vec<&str> = submatches.values_of("folder").map_or(vec![], |x| x.collect());
+                for maildir in try!(harvest::maildir_folders(root, &folders)
+                                    .map_err(|e| MainError::new(e.to_string()).with_kind(ErrorKind::NotFound).with_path(root))) {
+                    for fpath in try!(utils::list_maildir_messages(&maildir)) {
+                        harvest.add_message(&try!(utils::read_email_header(&fpath)));
+                    }
+                }
+            }
+            let exclude: Vec<&str> = submatches.values_of("exclude").map_or(vec![], |x| x.collect());
+            try!(harvest_contacts(&config, harvest, threshold, &exclude, submatches.is_present("dry-run")));
+        },
+        "fmt" => {
+            try!(format_contact(&config, submatches.value_of("file-or-query")));
+        },
+        "qr" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            try!(qr_contact(&config, query, submatches.value_of("output")));
+        },
+        "show" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            try!(show_contact(&config, &query[..], Colors::from_setting(submatches.value_of("color"))));
+        },
+        "new" => {
+            try!(new_contact(&config, submatches.value_of("name"), submatches.value_of("email")));
+        },
+        "rename" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            let new_name = submatches.value_of("name").unwrap_or("");
+            try!(rename_contact(&config, query, new_name));
+        },
+        "add-email" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            let email = if submatches.is_present("from-stdin") {
+                let mut input = String::new();
+                try!(io::stdin().read_to_string(&mut input));
+                let from_header = match utils::read_sender_from_email(&input[..]) {
+                    Some(x) => x,
+                    None => return Err(MainError::new("Couldn't find From-header in email.").into())
+                };
+                match utils::parse_from_header(&from_header) {
+                    (_, Some(x)) => x.to_owned(),
+                    (_, None) => return Err(MainError::new("Couldn't find address in From-header.").into())
+                }
+            } else {
+                match submatches.value_of("email") {
+                    Some(x) => x.to_owned(),
+                    None => return Err(MainError::new("Either an email address or --from-stdin is required.").with_kind(ErrorKind::InvalidArgument).into())
+                }
+            };
+            try!(add_email(&config, query, &email[..]));
+        },
+        "get" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            let prop_name = submatches.value_of("property").unwrap_or("");
+            try!(get_property(&config, query, prop_name));
+        },
+        "set" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            let prop_name = submatches.value_of("property").unwrap_or("");
+            let value = submatches.value_of("value").unwrap_or("");
+            try!(set_property(&config, query, prop_name, value, submatches.is_present("append")));
+        },
+        "mv" | "cp" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            let target_dir = path::PathBuf::from(submatches.value_of("target-dir").unwrap_or(""));
+            let target_index = submatches.value_of("target-index").map(path::PathBuf::from);
+            try!(transfer_contact(&config, query, &target_dir,
+                                  target_index.as_ref().map(|x| x.as_path()), command == "cp"));
+        },
+        "birthdays" => {
+            let limit = match submatches.value_of("next") {
+                Some(x) => match x.parse() {
+                    Ok(x) => Some(x),
+                    Err(_) => return Err(MainError::new(format!("Invalid number: {}", x)).with_kind(ErrorKind::InvalidArgument).into())
+                },
+                None => None
+            };
+            try!(print_birthdays(&config, limit));
+        },
+        "export" => {
+            let format_name = submatches.value_of("format").unwrap_or("vcf");
+            let vcard3 = submatches.is_present("vcard3");
+            if vcard3 && format_name != "vcf" {
+                return Err(MainError::new("--vcard3 only applies to --format vcf.").with_kind(ErrorKind::InvalidArgument).into());
+            }
+            let format = match ExportFormat::parse(format_name, vcard3) {
+                Some(x) => x,
+                None => return Err(MainError::new(format!("Unknown export format: {}", format_name)).with_kind(ErrorKind::InvalidArgument).into())
+            };
+            try!(export_contacts(&config, submatches.value_of("query"), submatches.value_of("output"), format));
+        },
+        "alias-export" => {
+            try!(export_aliases(&config, submatches.value_of("output")));
+        },
+        "pine-export" => {
+            try!(export_pine(&config, submatches.value_of("output")));
+        },
+        "import" => {
+            let infile = submatches.value_of("file").unwrap_or("");
+            let format = try!(ImportFormat::parse(submatches.value_of("format").unwrap_or("vcf"), submatches.value_of("map"))
+                              .map_err(|e| MainError::new(e).with_kind(ErrorKind::InvalidArgument)));
+            try!(import_contacts(&config, infile, &format));
+        },
+        "normalize" => {
+            try!(normalize_contacts(&config, submatches.value_of("query")));
+        },
+        "stats" => {
+            let since = match submatches.value_of("since") {
+                Some(x) => match utils::parse_timestamp(x) {
+                    Some(x) => Some(x),
+                    None => return Err(MainError::new(format!("Invalid date: {}", x)).with_kind(ErrorKind::InvalidArgument).into())
+                },
+                None => None
+            };
+            try!(print_stats(&config, since));
+        },
+        "check" => {
+            try!(check_contacts(&config));
+        },
+        "dupes" => {
+            try!(find_duplicates(&config));
+        },
+        "merge" => {
+            let query_a = submatches.value_of("query-a").unwrap_or("");
+            let query_b = submatches.value_of("query-b").unwrap_or("");
+            try!(merge_contacts(&config, query_a, query_b));
+        },
+        "edit" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            try!(edit_contact(&config, &query[..], !submatches.is_present("no-interactive")));
+        },
+        "remove" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            try!(remove_contact(&config, &query[..], submatches.is_present("force")));
+        },
+        "undo" => {
+            let path = match try!(utils::restore_from_trash(&config)) {
+                Some(x) => x,
+                None => return Err(MainError::new("Nothing to undo, the trash is empty.").into())
+            };
+            println!("Restored {}", path.display());
+            let contact = try!(utils::Contact::from_file(&path));
+            try!(append_to_index(&config, &contact));
+        },
+        _ => {
+            return Err(MainError::new(format!("Invalid command: {}", command)).into());
+        }
+    };
+    Ok(())
+}
+
+fn append_to_index(config: &Configuration, contact: &utils::Contact) -> MainResult<()> {
+    #[cfg(feature = "sqlite")]
+    {
+        if config.index_backend == IndexBackend::Sqlite {
+            let conn = try!(sqlite_index::open(&config.index_path));
+            try!(sqlite_index::upsert_contact(&conn, contact, &config.indexed_path(&contact.path),
+                                              &config.collection_name(&contact.path), &config.index_fields,
+                                              config.name_order));
+            return Ok(());
+        }
+    }
+
+    let index_entry = try!(index_entries(config, contact));
+    try!(utils::append_to_index_file(&config.index_path, &index_entry,
+                                     config.index_backend == IndexBackend::Binary));
+    Ok(())
+}
+
+/// Replace the index entries of the given contact with up-to-date ones.
+fn update_index(config: &Configuration, contact: &utils::Contact) -> MainResult<()> {
+    try!(remove_from_index(config, &contact.path));
+    append_to_index(config, contact)
+}
+
+/// Rewrite the index without the entries pointing to the given file.
+fn remove_from_index(config: &Configuration, fpath: &path::Path) -> MainResult<()> {
+    #[cfg(feature = "sqlite")]
+    {
+        if config.index_backend == IndexBackend::Sqlite {
+            let conn = try!(sqlite_index::open(&config.index_path));
+            try!(sqlite_index::remove_contact(&conn, &config.indexed_path(fpath)));
+            return Ok(());
+        }
+    }
+
+    let fpath = fs::canonicalize(fpath).unwrap_or_else(|_| fpath.to_owned());
+    let index = try!(utils::read_index_file(&config.index_path).map_err(|e| index_error(config, e)));
+
+    let mut output = String::new();
+    for line in index.lines() {
+        let item = utils::IndexItem::new(line.to_owned());
+        if let Some(p) = item.resolved_path(config) {
+            if fs::canonicalize(&p).unwrap_or(p) == fpath {
+                continue;
+            }
+        }
+        output.push_str(line);
+        output.push('\n');
+    }
+    try!(utils::write_index_file(&config.index_path, &output,
+                                 config.index_backend == IndexBackend::Binary));
+    Ok(())
+}
+
+/// Find the contact files for a filepath or search-string, sorted.
+fn find_contacts(config: &Configuration, query: &str) -> MainResult<Vec<path::PathBuf>> {
+    if get_pwd().join(query).is_file() {
+        return Ok(vec![path::PathBuf::from(query)]);
+    }
+    let mut rv: Vec<_> = try!(utils::file_query(config, &Query::new(query)).map_err(|e| index_error(config, e))).into_iter().collect();
+    rv.sort();
+    Ok(rv)
+}
+
+/// Find the contact file for a filepath or search-string. Fails unless there's exactly one match.
+fn resolve_contact(config: &Configuration, query: &str) -> MainResult<path::PathBuf> {
+    let mut results = try!(find_contacts(config, query));
+
+    if results.len() < 1 {
+        return Err(MainError::new("No such contact.").with_kind(ErrorKind::NotFound).into());
+    } else if results.len() > 1 {
+        return Err(MainError::new("Ambiguous query.").with_kind(ErrorKind::Ambiguous).into());
+    }
+
+    Ok(results.remove(0))
+}
+
+/// Like `resolve_contact`, but if several contacts match and the user is at a terminal, let them
+/// choose one from a numbered menu.
+fn choose_contact(config: &Configuration, query: &str) -> MainResult<path::PathBuf> {
+    let results = try!(find_contacts(config, query));
+    if results.len() <= 1 || !utils::is_interactive() {
+        return resolve_contact(config, query);
+    }
+
+    let stderr = &mut io::stderr();
+    for (i, fpath) in results.iter().enumerate() {
+        let name = utils::Contact::from_file(fpath).ok()
+            .and_then(|c| c.component.get_only("FN").map(|x| x.value_as_string()))
+            .unwrap_or_else(String::new);
+        try!(writeln!(stderr, "{:3}) {} ({})", i + 1, name, fpath.display()));
+    }
+    try!(write!(stderr, "Several contacts match, select one: "));
+    let mut answer = String::new();
+    try!(io::stdin().read_line(&mut answer));
+    match answer.trim().parse::<usize>() {
+        Ok(i) if i >= 1 && i <= results.len() => Ok(results.into_iter().nth(i - 1).unwrap()),
+        _ => Err(MainError::new("No contact selected.").into())
+    }
+}
+
+fn remove_contact(config: &Configuration, query: &str, force: bool) -> MainResult<()> {
+    let fpath = try!(resolve_contact(config, query));
+
+    if !force {
+        let name = utils::Contact::from_file(&fpath).ok()
+            .and_then(|c| c.component.get_only("FN").map(|x| x.value_as_string()))
+            .unwrap_or_else(String::new);
+        print!("Remove {} ({})? [y/N] ", name, fpath.display());
+        try!(io::stdout().flush());
+
+        let mut answer = String::new();
+        try!(io::stdin().read_line(&mut answer));
+        if answer.trim().to_lowercase() != "y" {
+            return Err(MainError::new("Aborted.").into());
+        }
+    }
+
+    try!(remove_from_index(config, &fpath));
+    try!(utils::trash_file(config, &fpath));
+    println!("Moved {} to trash. Use `mates undo` to restore it.", fpath.display());
+    Ok(())
+}
+
+/// Properties shown by `show`, in this order, with their labels. Others are shown afterwards.
+const SHOW_PROPS: &'static [(&'static str, &'static str)] = &[
+    ("NICKNAME", "Nickname"),
+    ("ORG", "Organization"),
+    ("TITLE", "Title"),
+    ("ROLE", "Role"),
+    ("EMAIL", "Email"),
+    ("TEL", "Phone"),
+    ("ADR", "Address"),
+    ("URL", "Website"),
+    ("BDAY", "Birthday"),
+    ("ANNIVERSARY", "Anniversary"),
+    ("CATEGORIES", "Categories"),
+    ("NOTE", "Note"),
+];
+
+/// Properties not worth showing to humans.
+const HIDDEN_PROPS: &'static [&'static str] = &["FN", "N", "VERSION", "PRODID"];
+
+/// Rewrite the contact given by a filepath or search-string in the layout mates writes contacts in:
+/// VERSION first, the other properties sorted by name, long lines folded. Without one, or with `-`,
+/// format a contact read from stdin to stdout.
+fn format_contact(config: &Configuration, file_or_query: Option<&str>) -> MainResult<()> {
+    let query = match file_or_query {
+        Some(x) if x != "-" => x,
+        _ => {
+            let mut input = String::new();
+            try!(io::stdin().read_to_string(&mut input));
+            let component = match vobject::parse_component(&input) {
+                Ok(x) => x,
+                Err(e) => return Err(MainError::new(format!("Error while parsing contact: {}", e)).into())
+            };
+            print!("{}", utils::write_component_sorted(&component));
+            return Ok(());
+        }
+    };
+
+    let fpath = try!(resolve_contact(config, query));
+    let contact = try!(utils::Contact::from_file(&fpath));
+    let mut original = String::new();
+    try!(try!(fs::File::open(&fpath)).read_to_string(&mut original));
+    if utils::write_component_sorted(&contact.component) != original {
+        try!(contact.write_replace());
+        try!(update_index(config, &contact));
+    }
+    Ok(())
+}
+
+/// Show the contact matching `query` as a QR code, or write it as a PNG image to `outfile`.
+fn qr_contact(config: &Configuration, query: &str, outfile: Option<&str>) -> MainResult<()> {
+    let fpath = try!(resolve_contact(config, query));
+    let contact = try!(utils::Contact::from_file(&fpath));
+    let code = match QrCode::encode(export::to_compact_vcard(&contact.component).as_bytes()) {
+        Some(x) => x,
+        None => return Err(MainError::new("Contact is too large for a QR code.").into())
+    };
+    match outfile {
+        Some(x) => {
+            let af = AtomicFile::new(x, AllowOverwrite);
+            try!(af.write(|f| f.write_all(&code.to_png(8))));
+        },
+        None => print!("{}", code.to_terminal())
+    };
+    Ok(())
+}
+
+fn show_contact(config: &Configuration, query: &str, colors: Colors) -> MainResult<()> {
+    let fpath = try!(resolve_contact(config, query));
+    let contact = try!(utils::Contact::from_file(&fpath));
+    let comp = &contact.component;
+
+    let name = match comp.get_only("FN") {
+        Some(x) => x.value_as_string(),
+        None => "(no name)".to_owned()
+    };
+    println!("{}", colors.name(&name));
+
+    let mut other_props: Vec<&String> = comp.props.keys()
+        .filter(|x| !HIDDEN_PROPS.contains(&&x[..]) && !SHOW_PROPS.iter().any(|&(p, _)| p == &x[..]))
+        .collect();
+    other_props.sort();
+
+    let known = SHOW_PROPS.iter().map(|&(p, label)| (p, label.to_owned()));
+    let others = other_props.into_iter().map(|p| (&p[..], p.to_owned()));
+    for (prop_name, label) in known.chain(others) {
+        for prop in comp.get_all(prop_name) {
+            let label = match prop.params.get("TYPE") {
+                Some(t) => format!("{} ({})", label, t.to_lowercase()),
+                None => label.clone()
+            };
+            let value = match prop_name {
+                "PHOTO" | "LOGO" | "SOUND" | "KEY" if prop.params.contains_key("ENCODING") => "(embedded data)".to_owned(),
+                "ADR" | "ORG" => prop.raw_value.split(';')
+                    .map(|x| utils::split_list_value(x).join(" "))
+                    .filter(|x| !x.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                "CATEGORIES" | "NICKNAME" => utils::split_list_value(&prop.raw_value).join(", "),
+                "EMAIL" => colors.email(&prop.value_as_string()),
+                _ => prop.value_as_string()
+            };
+            println!("  {} {}", colors.label(&format!("{}:", label)), value.replace('\n', "\n      "));
+        }
+    }
+    println!("  {} {}", colors.label("File:"), fpath.display());
+    Ok(())
+}
+
+/// Create a contact with the given name and email, and open it in the editor.
+fn new_contact(config: &Configuration, name: Option<&str>, email: Option<&str>) -> MainResult<()> {
+    let email_type = config.default_email_type.as_ref().map(|x| &x[..]);
+    let mut contact = utils::Contact::generate(name, email, email_type, Utc::now(), config.vcard_version, &config.vdir_path);
+    try!(contact.check_roundtrip(&[("FN", name), ("EMAIL", email)]));
+    try!(contact.write_create());
+    println!("{}", contact.path.display());
+
+    try!(edit_file(config, &contact.path));
+    let contact = try!(utils::Contact::from_file(&contact.path));
+    try!(append_to_index(config, &contact));
+    Ok(())
+}
+
+/// Change the FN of the contact matching `query`, and regenerate its N from it.
+fn rename_contact(config: &Configuration, query: &str, new_name: &str) -> MainResult<()> {
+    let fpath = try!(resolve_contact(config, query));
+    let mut contact = try!(utils::Contact::from_file(&fpath));
+
+    let fn_prop = match contact.component.pop("FN") {
+        Some(mut x) => {
+            x.raw_value = utils::escape_strictly(new_name);
+            x
+        },
+        None => {
+            let mut x = vobject::Property::new("FN", "");
+            x.raw_value = utils::escape_strictly(new_name);
+            x
+        }
+    };
+    contact.component.set(fn_prop);
+
+    let mut n_prop = match contact.component.pop("N") {
+        Some(x) => x,
+        None => vobject::Property::new("N", "")
+    };
+    n_prop.raw_value = utils::structured_name(new_name);
+    contact.component.set(n_prop);
+
+    contact.component.set(vobject::Property::new("REV", &utils::format_timestamp(&Utc::now())[..]));
+    try!(contact.check_roundtrip(&[("FN", Some(new_name))]));
+    try!(contact.write_replace());
+    try!(update_index(config, &contact));
+    Ok(())
+}
+
+/// Add an email address to the contact matching `query`.
+fn add_email(config: &Configuration, query: &str, email: &str) -> MainResult<()> {
+    let fpath = try!(resolve_contact(config, query));
+    let mut contact = try!(utils::Contact::from_file(&fpath));
+    let email = email.trim();
+    if !utils::is_valid_email(email) {
+        return Err(MainError::new(format!("Invalid email address: {}", email)).with_kind(ErrorKind::InvalidArgument).into());
+    }
+
+    let exists = contact.component.get_all("EMAIL").iter()
+        .any(|x| x.value_as_string().trim().to_lowercase() == email.to_lowercase());
+    if exists {
+        return Err(MainError::new(format!("{} already has the address {}.", fpath.display(), email)).into());
+    }
+
+    let mut prop = vobject::Property::new("EMAIL", email);
+    if let Some(ref t) = config.default_email_type {
+        utils::set_property_types(&mut prop, t, VcardVersion::of(&contact.component));
+    }
+    contact.component.push(prop);
+    contact.component.set(vobject::Property::new("REV", &utils::format_timestamp(&Utc::now())[..]));
+    try!(contact.write_replace());
+    try!(update_index(config, &contact));
+    Ok(())
+}
+
+/// Print the values of a property of the contact matching `query`, one per line.
+fn get_property(config: &Configuration, query: &str, prop_name: &str) -> MainResult<()> {
+    let fpath = try!(resolve_contact(config, query));
+    let contact = try!(utils::Contact::from_file(&fpath));
+    let props = contact.component.get_all(prop_name.to_uppercase());
+    if props.is_empty() {
+        return Err(MainError::new(format!("Contact has no {} property.", prop_name.to_uppercase())).into());
+    }
+    for prop in props {
+        println!("{}", prop.value_as_string());
+    }
+    Ok(())
+}
+
+/// Set a property of the contact matching `query`, replacing all existing properties of that name
+/// unless `append` is set.
+fn set_property(config: &Configuration, query: &str, prop_name: &str, value: &str,
+                append: bool) -> MainResult<()> {
+    let fpath = try!(resolve_contact(config, query));
+    let mut contact = try!(utils::Contact::from_file(&fpath));
+    let prop_name = prop_name.to_uppercase();
+    let mut prop = vobject::Property::new(&prop_name[..], "");
+    prop.raw_value = match &prop_name[..] {
+        "N" | "ADR" | "ORG" => escape_parts(value, ';'),
+        "CATEGORIES" | "NICKNAME" => escape_parts(value, ','),
+        _ => utils::escape_strictly(value)
+    };
+    if append {
+        contact.component.push(prop);
+    } else {
+        contact.component.set(prop);
+    }
+    contact.component.set(vobject::Property::new("REV", &utils::format_timestamp(&Utc::now())[..]));
+    try!(contact.check_roundtrip(&[(&prop_name[..], Some(value))]));
+    try!(contact.write_replace());
+    try!(update_index(config, &contact));
+    Ok(())
+}
+
+/// Escape the parts of a structured or list value separated by `sep`, keeping the separators.
+fn escape_parts(value: &str, sep: char) -> String {
+    value.split(sep)
+        .map(utils::escape_strictly)
+        .collect::<Vec<_>>()
+        .join(&sep.to_string())
+}
+
+/// Move (or with `copy`, duplicate under a new UID) the contact matching `query` into another
+/// directory. The contact is added to `target_index` if given, otherwise to the index of mates if
+/// the directory is one of its collections.
+fn transfer_contact(config: &Configuration, query: &str, target_dir: &path::Path,
+                    target_index: Option<&path::Path>, copy: bool) -> MainResult<()> {
+    if !target_dir.is_dir() {
+        return Err(MainError::new(format!("Not a directory: {}", target_dir.display())).into());
+    }
+    let canonical_target = fs::canonicalize(target_dir).ok();
+    let collection = config.collections.iter()
+        .find(|x| fs::canonicalize(x).ok() == canonical_target)
+        .map(|x| x.as_path());
+    let target_dir = collection.unwrap_or(target_dir);
+    let fpath = try!(resolve_contact(config, query));
+    let mut contact = try!(utils::Contact::from_file(&fpath));
+
+    if copy {
+        let (uid, new_path) = utils::Contact::new_uid_path(target_dir);
+        contact.component.set(vobject::Property::new("UID", &uid[..]));
+        contact.component.set(vobject::Property::new("REV", &utils::format_timestamp(&Utc::now())[..]));
+        contact.path = new_path;
+        try!(contact.write_create());
+    } else {
+        let filename = match fpath.file_name() {
+            Some(x) => x.to_owned(),
+            None => return Err(MainError::new(format!("Not a file: {}", fpath.display())).into())
+        };
+        contact.path = target_dir.join(filename);
+        if contact.path.exists() {
+            return Err(MainError::new(format!("File already exists: {}", contact.path.display())).into());
+        }
+        try!(remove_from_index(config, &fpath));
+        if fs::rename(&fpath, &contact.path).is_err() {
+            // Probably a different filesystem
+            try!(fs::copy(&fpath, &contact.path));
+            try!(fs::remove_file(&fpath));
+        }
+    }
+
+    match (target_index, collection) {
+        (Some(index_path), _) => {
+            let index_entry = try!(utils::index_item_from_contact(&contact, &utils::relative_to_dir(&contact.path, target_dir),
+                                                                  "", &config.index_fields, config.name_order));
+            try!(utils::append_to_index_file(index_path, &index_entry,
+                                             config.index_backend == IndexBackend::Binary));
+        },
+        (None, Some(_)) => try!(append_to_index(config, &contact)),
+        (None, None) => ()
+    }
+    println!("{}", contact.path.display());
+    Ok(())
+}
+
+/// Print upcoming birthdays, sorted by date, limited to the next `limit` ones if given.
+fn print_birthdays(config: &Configuration, limit: Option<usize>) -> MainResult<()> {
+    let today = Local::today().naive_local();
+    let mut birthdays = vec![];
+
+    for fpath in try!(config.contact_files()) {
+        let contact = match utils::Contact::from_file(&fpath) {
+            Ok(x) => x,
+            Err(e) => {
+                writeln!(&mut io::stderr(), "Error while reading {}: {}", fpath.display(), e).unwrap();
+                continue;
+            }
+        };
+        let bday = match contact.component.get_only("BDAY") {
+            Some(x) => x.value_as_string(),
+            None => continue
+        };
+        let (year, month, day) = match utils::parse_date_value(&bday) {
+            Some(x) => x,
+            None => {
+                writeln!(&mut io::stderr(), "Invalid BDAY in {}: {}", fpath.display(), bday).unwrap();
+                continue;
+            }
+        };
+
+        // Birthdays on February 29 are celebrated on February 28 in other years.
+        let in_year = |y: i32| NaiveDate::from_ymd_opt(y, month, day)
+            .unwrap_or_else(|| NaiveDate::from_ymd(y, month, day - 1));
+        let mut next = in_year(today.year());
+        if next < today {
+            next = in_year(today.year() + 1);
+        }
+
+        let name = contact.component.get_only("FN").map(|x| x.value_as_string()).unwrap_or_else(String::new);
+        let age = year.map(|y| next.year() - y);
+        birthdays.push((next, name, age));
+    }
+
+    birthdays.sort();
+    for (date, name, age) in birthdays.into_iter().take(limit.unwrap_or(usize::MAX)) {
+        match age {
+            Some(age) => println!("{}\t{}\t{}", date.format("%Y-%m-%d"), name, age),
+            None => println!("{}\t{}", date.format("%Y-%m-%d"), name)
+        };
+    }
+    Ok(())
+}
+
+/// Filepaths of contacts matching `query`, or of all contacts in `MATES_DIR` if it's `None`.
+fn contact_files(config: &Configuration, query: Option<&str>) -> MainResult<Vec<path::PathBuf>> {
+    Ok(match query {
+        Some(q) => {
+            let mut x: Vec<_> = try!(utils::file_query(config, &Query::new(q)).map_err(|e| index_error(config, e))).into_iter().collect();
+            x.sort();
+            x
+        },
+        None => try!(config.contact_files())
+    })
+}
+
+/// Parse the given contact files, skipping (and reporting) broken ones.
+fn read_contacts(fpaths: Vec<path::PathBuf>) -> Vec<utils::Contact> {
+    let mut rv = vec![];
+    for fpath in fpaths {
+        match utils::Contact::from_file(&fpath) {
+            Ok(x) => rv.push(x),
+            Err(e) => writeln!(&mut io::stderr(), "Error while reading {}: {}", fpath.display(), e).unwrap()
+        };
+    }
+    rv
+}
+
+/// Write `output` to the given file, or stdout if there is none.
+fn write_output(outfile: Option<&str>, output: &str) -> MainResult<()> {
+    match outfile {
+        Some(x) => {
+            let af = AtomicFile::new(x, AllowOverwrite);
+            try!(af.write(|f| f.write_all(output.as_bytes())));
+        },
+        None => try!(io::stdout().write_all(output.as_bytes()))
+    };
+    Ok(())
+}
+
+fn export_contacts(config: &Configuration, query: Option<&str>, outfile: Option<&str>,
+                   format: ExportFormat) -> MainResult<()> {
+    let contacts = read_contacts(try!(contact_files(config, query)));
+    let output = export::export(&contacts, format);
+    write_output(outfile, &output)
+}
+
+/// Write a mutt alias file for all contacts.
+fn export_aliases(config: &Configuration, outfile: Option<&str>) -> MainResult<()> {
+    let mut contacts = read_contacts(try!(config.contact_files()));
+    contacts.sort_by(|a, b| a.path.cmp(&b.path));
+    write_output(outfile, &export::to_mutt_aliases(&contacts))
+}
+
+/// Write an (al)pine address book for all contacts.
+fn export_pine(config: &Configuration, outfile: Option<&str>) -> MainResult<()> {
+    let mut contacts = read_contacts(try!(config.contact_files()));
+    contacts.sort_by(|a, b| a.path.cmp(&b.path));
+    write_output(outfile, &export::to_pine_addressbook(&contacts))
+}
+
+fn import_contacts(config: &Configuration, infile: &str, format: &ImportFormat) -> MainResult<()> {
+    let input = {
+        let mut x = vec![];
+        try!(try!(fs::File::open(infile)).read_to_end(&mut x));
+        try!(import::decode_input(x, format).map_err(|e| MainError::new(e).with_kind(ErrorKind::InvalidArgument)))
     };
 
-    let af = AtomicFile::new(&outfile, AllowOverwrite);
+    let (components, parse_errors) = try!(import::parse_input(&input, format)
+                                          .map_err(|e| MainError::new(e).with_kind(ErrorKind::InvalidArgument)));
+    for e in parse_errors.iter() {
+        writeln!(&mut io::stderr(), "{}", e).unwrap();
+    }
+
+    let stats = try!(import::import_components(components, &config.vdir_path, format.merges_by_email(),
+                                               config.vcard_version));
+    for fpath in stats.imported.iter().chain(stats.merged.iter()) {
+        println!("{}", fpath.display());
+    }
+    if format.merges_by_email() {
+        writeln!(&mut io::stderr(), "Imported {} contacts, merged {} into existing ones, skipped {} duplicates.",
+                 stats.imported.len(), stats.merged.len(), stats.duplicates).unwrap();
+    } else {
+        writeln!(&mut io::stderr(), "Imported {} contacts, skipped {} duplicates.",
+                 stats.imported.len(), stats.duplicates).unwrap();
+    }
+
+    try!(build_index(config, false));
+    if !parse_errors.is_empty() {
+        Err(MainError::new("Some contacts couldn't be imported.").into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Create contacts for the harvested addresses seen in more than `threshold` messages that no
+/// contact has yet, except for those in `exclude`, and print their filepaths. Addresses without a
+/// name are also used as name. With `dry_run`, print the addresses after the number of messages
+/// they were seen in instead.
+fn harvest_contacts(config: &Configuration, harvest: Harvest, threshold: usize, exclude: &[&str],
+                    dry_run: bool) -> MainResult<()> {
+    let exclude: HashSet<String> = exclude.iter().map(|x| x.trim().to_lowercase()).collect();
+    let mut known = HashSet::new();
+    for contact in read_contacts(try!(config.contact_files())) {
+        for email in contact.component.get_all("EMAIL") {
+            known.insert(email.value_as_string().trim().to_lowercase());
+        }
+    }
+
+    let messages = harvest.messages;
+    let (mut created, mut skipped) = (0, 0);
+    let email_type = config.default_email_type.as_ref().map(|x| &x[..]);
+    for correspondent in harvest.correspondents(threshold) {
+        if exclude.contains(&correspondent.email.to_lowercase()) {
+            continue;
+        }
+        if known.contains(&correspondent.email.to_lowercase()) {
+            skipped += 1;
+            continue;
+        }
+        if dry_run {
+            match correspondent.name {
+                Some(ref x) => println!("{}\t{} <{}>", correspondent.count, x, correspondent.email),
+                None => println!("{}\t{}", correspondent.count, correspondent.email)
+            }
+            continue;
+        }
+        let name = correspondent.name.as_ref().unwrap_or(&correspondent.email);
+        let mut contact = utils::Contact::generate(Some(name), Some(&correspondent.email), email_type, Utc::now(),
+                                                   config.vcard_version, &config.vdir_path);
+        try!(contact.check_roundtrip(&[("FN", Some(name)), ("EMAIL", Some(&correspondent.email))]));
+        try!(contact.write_create());
+        println!("{}", contact.path.display());
+        created += 1;
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+    writeln!(&mut io::stderr(), "Read {} messages, created {} contacts, skipped {} addresses already in contacts.",
+             messages, created, skipped).unwrap();
+    if created > 0 {
+        try!(build_index(config, false));
+    }
+    Ok(())
+}
+
+/// Rewrite contacts matching `query` (or all contacts) in a canonical format. Print the filepaths
+/// of changed files.
+fn normalize_contacts(config: &Configuration, query: Option<&str>) -> MainResult<()> {
     let mut errors = false;
+    for fpath in try!(contact_files(config, query)) {
+        let original = {
+            let mut x = vec![];
+            try!(try!(fs::File::open(&fpath)).read_to_end(&mut x));
+            x
+        };
+        let contact = match utils::Contact::from_file(&fpath) {
+            Ok(x) => x,
+            Err(e) => {
+                writeln!(&mut io::stderr(), "Error while reading {}: {}", fpath.display(), e).unwrap();
+                errors = true;
+                continue;
+            }
+        };
+        if utils::write_component_sorted(&contact.component).as_bytes() != &original[..] {
+            try!(contact.write_replace());
+            try!(update_index(config, &contact));
+            println!("{}", fpath.display());
+        }
+    }
+
+    if errors {
+        Err(MainError::new("Some contacts couldn't be normalized.").into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Print statistics about the contacts in `MATES_DIR`. If `since` is given, also count contacts
+/// whose REV is older than that.
+fn print_stats(config: &Configuration, since: Option<DateTime<Utc>>) -> MainResult<()> {
+    let mut contacts = 0;
+    let mut emails = 0;
+    let mut without_email = 0;
+    let mut without_tel = 0;
+    let mut untouched = 0;
+    let mut without_rev = 0;
+    let mut domains: HashMap<String, usize> = HashMap::new();
+
+    for fpath in try!(config.contact_files()) {
+        let contact = match utils::Contact::from_file(&fpath) {
+            Ok(x) => x,
+            Err(e) => {
+                writeln!(&mut io::stderr(), "Error while reading {}: {}", fpath.display(), e).unwrap();
+                continue;
+            }
+        };
+        let comp = &contact.component;
+        contacts += 1;
+
+        let contact_emails = comp.get_all("EMAIL");
+        emails += contact_emails.len();
+        if contact_emails.is_empty() {
+            without_email += 1;
+        }
+        for email in contact_emails {
+            // Values without an '@' have no domain.
+            let email = email.value_as_string();
+            let mut parts = email.rsplitn(2, '@');
+            if let (Some(domain), Some(_)) = (parts.next(), parts.next()) {
+                *domains.entry(domain.trim().to_lowercase()).or_insert(0) += 1;
+            }
+        }
+        if comp.get_all("TEL").is_empty() {
+            without_tel += 1;
+        }
+
+        if let Some(since) = since {
+            match comp.get_only("REV").and_then(|x| utils::parse_timestamp(&x.value_as_string())) {
+                Some(rev) if rev < since => untouched += 1,
+                Some(_) => (),
+                None => without_rev += 1
+            };
+        }
+    }
+
+    println!("Contacts: {}", contacts);
+    println!("Email addresses: {}", emails);
+    println!("Contacts without email: {}", without_email);
+    println!("Contacts without phone: {}", without_tel);
+    if let Some(since) = since {
+        println!("Contacts untouched since {}: {}", since.format("%Y-%m-%d"), untouched);
+        println!("Contacts without REV: {}", without_rev);
+    }
+
+    let mut domains: Vec<(String, usize)> = domains.into_iter().collect();
+    domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    if !domains.is_empty() {
+        println!("Most common email domains:");
+        for &(ref domain, count) in domains.iter().take(10) {
+            println!("  {}\t{}", count, domain);
+        }
+    }
+    Ok(())
+}
+
+/// Validate all files in `MATES_DIR` and print the problems found.
+fn check_contacts(config: &Configuration) -> MainResult<()> {
+    let mut problems = 0;
+    let mut uids: HashMap<String, path::PathBuf> = HashMap::new();
+
+    for fpath in try!(config.contact_files()) {
+        let mut report = |msg: String| {
+            println!("{}: {}", fpath.display(), msg);
+            problems += 1;
+        };
+
+        let contact = match utils::Contact::from_file(&fpath) {
+            Ok(x) => x,
+            Err(e) => {
+                report(format!("{}", e));
+                continue;
+            }
+        };
+        let comp = &contact.component;
+
+        if comp.name != "VCARD" {
+            report(format!("Expected VCARD component, got {}", comp.name));
+        }
+        match comp.get_only("FN") {
+            Some(x) if !x.value_as_string().trim().is_empty() => (),
+            Some(_) => report("Empty FN.".to_owned()),
+            None if comp.get_all("FN").is_empty() => report("Missing FN.".to_owned()),
+            None => report("Several FN properties.".to_owned())
+        };
+        match comp.get_only("UID") {
+            Some(x) => {
+                let uid = x.value_as_string();
+                match uids.get(&uid) {
+                    Some(other) => report(format!("Duplicate UID {}, also used by {}", uid, other.display())),
+                    None => ()
+                };
+                uids.insert(uid, fpath.clone());
+            },
+            None if comp.get_all("UID").is_empty() => report("Missing UID.".to_owned()),
+            None => report("Several UID properties.".to_owned())
+        };
+        for email in comp.get_all("EMAIL") {
+            let email = email.value_as_string();
+            if !utils::is_valid_email(&email) {
+                report(format!("Invalid email address: {:?}", email));
+            }
+        }
+    }
+
+    if problems > 0 {
+        Err(MainError::new(format!("Found {} problems.", problems)).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Compare the index against the contact files, listing entries of removed files, files missing
+/// from the index and files whose entries are outdated.
+fn verify_index(config: &Configuration) -> MainResult<()> {
+    let items = try!(utils::read_index(config).map_err(|e| index_error(config, e)));
+    let mut indexed: BTreeMap<path::PathBuf, Vec<(String, String, String)>> = BTreeMap::new();
+    for item in items.iter() {
+        if let Some(fpath) = item.resolved_path(config) {
+            indexed.entry(fpath).or_insert_with(Vec::new)
+                .push((item.email.clone(), item.name.clone(), item.tel.clone()));
+        }
+    }
+
+    let mut removed = 0;
+    let mut missing = 0;
+    let mut outdated = 0;
+
+    for fpath in indexed.keys() {
+        if !fpath.is_file() {
+            println!("{}: File doesn't exist anymore.", fpath.display());
+            removed += 1;
+        }
+    }
+
+    for fpath in try!(config.contact_files()) {
+        let contact = match utils::Contact::from_file(&fpath) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("{}: {}", fpath.display(), e);
+                outdated += 1;
+                continue;
+            }
+        };
+        let mut expected: Vec<_> = match utils::index_items_from_contact(&contact, &config.indexed_path(&fpath),
+                                                                        &config.collection_name(&fpath),
+                                                                        &config.index_fields, config.name_order) {
+            Ok(x) => x.into_iter().map(|x| (x.email, x.name, x.tel)).collect(),
+            Err(e) => {
+                println!("{}: {}", fpath.display(), e);
+                outdated += 1;
+                continue;
+            }
+        };
+        expected.sort();
+        // Contacts without email addresses and phone numbers have no entries.
+        let entries = match indexed.get_mut(&fpath) {
+            Some(x) => x,
+            None if expected.is_empty() => continue,
+            None => {
+                println!("{}: Not in the index.", fpath.display());
+                missing += 1;
+                continue;
+            }
+        };
+        entries.sort();
+        if expected != *entries {
+            println!("{}: Index entries are outdated.", fpath.display());
+            outdated += 1;
+        }
+    }
+
+    println!("Checked {} entries: {} of removed files, {} files not indexed, {} outdated.",
+             items.len(), removed, missing, outdated);
+    if removed + missing + outdated > 0 {
+        Err(MainError::new("The index is out of date, run `mates index` to update it.")
+            .with_kind(ErrorKind::Index).with_path(config.index_path.clone()).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Print groups of contacts that share an email address or have very similar names, one group
+/// per line with tab-separated filepaths.
+fn find_duplicates(config: &Configuration) -> MainResult<()> {
+    let mut paths = vec![];
+    let mut name_keys = vec![];
+    let mut by_email: HashMap<String, Vec<usize>> = HashMap::new();
+
+    for fpath in try!(config.contact_files()) {
+        let contact = match utils::Contact::from_file(&fpath) {
+            Ok(x) => x,
+            Err(e) => {
+                writeln!(&mut io::stderr(), "Error while reading {}: {}", fpath.display(), e).unwrap();
+                continue;
+            }
+        };
+        let i = paths.len();
+        for email in contact.component.get_all("EMAIL") {
+            let email = email.value_as_string().trim().to_lowercase();
+            if !email.is_empty() {
+                by_email.entry(email).or_insert_with(Vec::new).push(i);
+            }
+        }
+        name_keys.push(contact.component.get_only("FN")
+                       .map(|x| utils::name_key(&x.value_as_string()))
+                       .unwrap_or_else(String::new));
+        paths.push(fpath);
+    }
+
+    // Union-find over contact indices
+    let mut parents: Vec<usize> = (0..paths.len()).collect();
+    fn find(parents: &mut Vec<usize>, i: usize) -> usize {
+        let mut root = i;
+        while parents[root] != root {
+            root = parents[root];
+        }
+        parents[i] = root;
+        root
+    }
+    fn union(parents: &mut Vec<usize>, a: usize, b: usize) {
+        let (ra, rb) = (find(parents, a), find(parents, b));
+        parents[rb] = ra;
+    }
+
+    for indices in by_email.values() {
+        for &i in indices.iter().skip(1) {
+            union(&mut parents, indices[0], i);
+        }
+    }
+    for i in 0..name_keys.len() {
+        if name_keys[i].is_empty() {
+            continue;
+        }
+        for j in (i + 1)..name_keys.len() {
+            let similar = name_keys[i] == name_keys[j] ||
+                (name_keys[i].len() >= 5 && utils::edit_distance(&name_keys[i], &name_keys[j]) <= 1);
+            if similar {
+                union(&mut parents, i, j);
+            }
+        }
+    }
+
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..paths.len() {
+        let root = find(&mut parents, i);
+        groups.entry(root).or_insert_with(Vec::new).push(i);
+    }
+    for group in groups.values().filter(|x| x.len() > 1) {
+        let line: Vec<String> = group.iter().map(|&i| paths[i].display().to_string()).collect();
+        println!("{}", line.join("\t"));
+    }
+    Ok(())
+}
+
+/// Merge the contact matching `query_b` into the one matching `query_a`, and delete the former.
+fn merge_contacts(config: &Configuration, query_a: &str, query_b: &str) -> MainResult<()> {
+    let path_a = try!(resolve_contact(config, query_a));
+    let path_b = try!(resolve_contact(config, query_b));
+    if try!(fs::canonicalize(&path_a)) == try!(fs::canonicalize(&path_b)) {
+        return Err(MainError::new("Both queries match the same contact.").into());
+    }
+
+    let mut contact = try!(utils::Contact::from_file(&path_a));
+    let other = try!(utils::Contact::from_file(&path_b));
+    utils::merge_components(&mut contact.component, &other.component);
+    contact.component.set(vobject::Property::new("REV", &utils::format_timestamp(&Utc::now())[..]));
+    try!(contact.write_replace());
+
+    try!(remove_from_index(config, &path_a));
+    try!(remove_from_index(config, &path_b));
+    try!(utils::trash_file(config, &path_b));
+    try!(append_to_index(config, &contact));
+    println!("Merged {} into {}", path_b.display(), path_a.display());
+    Ok(())
+}
+
+/// Edit the contact matching `query`. With `interactive`, the user may choose if several match.
+fn edit_contact(config: &Configuration, query: &str, interactive: bool) -> MainResult<()> {
+    let fpath = if interactive {
+        try!(choose_contact(config, query))
+    } else {
+        try!(resolve_contact(config, query))
+    };
+    edit_file(config, &fpath)
+}
+
+fn edit_file(config: &Configuration, fpath: &path::Path) -> MainResult<()> {
+    editor::cli_main(fpath);
+
+    let fcontent = {
+        let mut fcontent = String::new();
+        let mut file = try!(fs::File::open(fpath));
+        try!(file.read_to_string(&mut fcontent));
+        fcontent
+    };
+
+    if (&fcontent[..]).trim().len() == 0 {
+        try!(utils::trash_file(config, fpath));
+        return Err(MainError::new("Contact emptied, file moved to trash. Use `mates undo` to restore it.").into());
+    };
+
+    Ok(())
+}
+
+/// Orders for the results of query commands.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SortKey {
+    /// By name, then email address
+    Name,
+    Email,
+    /// Most recently changed first
+    Recent,
+    /// Most recently written to first, according to the contact log
+    Contacted
+}
+
+/// Which results of a query command are printed in which order, set with `--sort`, `--offset` and
+/// `--limit`. Without `--sort`, results are in the order of the index, or ranked by the usage log
+/// if one is given.
+struct ResultOptions {
+    sort: Option<SortKey>,
+    offset: usize,
+    limit: usize,
+    usage: Option<usage::UsageLog>,
+    /// Read for `--sort contacted`.
+    contacted: Option<usage::ContactLog>
+}
+
+impl ResultOptions {
+    fn from_matches(config: &Configuration, submatches: &ArgMatches) -> MainResult<ResultOptions> {
+        fn number(submatches: &ArgMatches, arg: &str, default: usize) -> MainResult<usize> {
+            match submatches.value_of(arg) {
+                Some(x) => x.parse().map_err(|_| MainError::new(format!("Invalid number: {}", x)).with_kind(ErrorKind::InvalidArgument).into()),
+                None => Ok(default)
+            }
+        }
+        let sort = match submatches.value_of("sort") {
+            None => None,
+            Some("name") => Some(SortKey::Name),
+            Some("email") => Some(SortKey::Email),
+            Some("recent") => Some(SortKey::Recent),
+            Some("contacted") => Some(SortKey::Contacted),
+            Some(x) => return Err(MainError::new(format!("Invalid sort order: {}", x)).with_kind(ErrorKind::InvalidArgument).into())
+        };
+        Ok(ResultOptions {
+            sort: sort,
+            offset: try!(number(submatches, "offset", 0)),
+            limit: try!(number(submatches, "limit", usize::MAX)),
+            usage: None,
+            contacted: if sort == Some(SortKey::Contacted) {
+                Some(try!(usage::ContactLog::read(&config.contacted_path())))
+            } else {
+                None
+            }
+        })
+    }
 
-    try!(af.write::<(), io::Error, _>(|outf| {
-        for entry in try!(fs::read_dir(dir)) {
-            let entry = match entry {
-                Ok(x) => x,
-                Err(e) => {
-                    println!("Error while listing directory: {}", e);
-                    errors = true;
-                    continue;
-                }
-            };
+    /// Rank results by the usage log unless `--sort` is given. A log that can't be read is
+    /// ignored, since queries shouldn't fail because of it.
+    fn with_usage(mut self, config: &Configuration) -> ResultOptions {
+        if self.sort.is_none() {
+            self.usage = usage::UsageLog::read(&config.usage_path).ok().filter(|x| !x.is_empty());
+        }
+        self
+    }
 
-            let pathbuf = entry.path();
+    /// Sort the results if requested. Sorting is stable, so entries that compare equal keep their
+    /// order.
+    fn sort<'a, I: Iterator<Item = utils::IndexItem> + 'a>(&self, items: I) -> Box<Iterator<Item = utils::IndexItem> + 'a> {
+        let key = match (self.sort, &self.usage) {
+            (Some(x), _) => x,
+            (None, &Some(ref usage)) => {
+                let mut items: Vec<_> = items.collect();
+                items.sort_by_key(|x| cmp::Reverse(usage.score(&x.email)));
+                return Box::new(items.into_iter());
+            },
+            (None, &None) => return Box::new(items)
+        };
+        let mut items: Vec<_> = items.collect();
+        match key {
+            // Entries written by older versions have no sort key.
+            SortKey::Name => items.sort_by_key(|x| {
+                (if x.sort_name.is_empty() { x.folded_name.clone() } else { x.sort_name.clone() }, x.email.to_lowercase())
+            }),
+            SortKey::Email => items.sort_by_key(|x| x.email.to_lowercase()),
+            SortKey::Recent => items.sort_by(|a, b| b.changed_at().cmp(&a.changed_at())),
+            SortKey::Contacted => if let Some(ref log) = self.contacted {
+                items.sort_by(|a, b| log.last_contacted(&b.email).cmp(&log.last_contacted(&a.email)))
+            }
+        }
+        Box::new(items.into_iter())
+    }
 
-            if pathbuf.str_extension().unwrap_or("") != "vcf" || !pathbuf.is_file() {
-                continue;
-            };
+    /// Sort the results, then skip and limit them.
+    fn apply<'a, I: Iterator<Item = utils::IndexItem> + 'a>(&self, items: I) -> iter::Take<iter::Skip<Box<Iterator<Item = utils::IndexItem> + 'a>>> {
+        self.sort(items).skip(self.offset).take(self.limit)
+    }
+}
 
-            let contact = match utils::Contact::from_file(&pathbuf) {
-                Ok(x) => x,
-                Err(e) => {
-                    println!("Error while reading {}: {}", pathbuf.display(), e);
-                    errors = true;
-                    continue
-                }
-            };
+/// Skip entries with the same name and email address as an earlier one, e.g. from contacts sharing
+/// an address or a contact listing it twice, so only the first filepath is kept.
+fn unique_addresses<'a, I: Iterator<Item = utils::IndexItem> + 'a>(items: I) -> Box<Iterator<Item = utils::IndexItem> + 'a> {
+    let mut seen = HashSet::new();
+    Box::new(items.filter(move |x| seen.insert((x.name.clone(), x.email.to_lowercase()))))
+}
 
-            match utils::index_item_from_contact(&contact) {
-                Ok(index_string) => {
-                    try!(outf.write_all(index_string.as_bytes()));
-                },
-                Err(e) => {
-                    println!("Error while indexing {}: {}", pathbuf.display(), e);
-                    errors = true;
-                    continue
-                }
-            };
-        };
-        Ok(())
-    }));
+/// How query commands print their results.
+enum ResultFormat {
+    /// The command's own format.
+    Default,
+    /// `--format`, one record per contact.
+    Records(OutputFormat),
+    /// `--template`, one line per index entry.
+    Template(output::Template)
+}
 
-    if errors {
-        Err(MainError::new("Several errors happened while generating the index.").into())
-    } else {
-        Ok(())
+impl ResultFormat {
+    fn from_matches(config: &Configuration, submatches: &ArgMatches) -> MainResult<ResultFormat> {
+        if let Some(x) = submatches.value_of("template") {
+            let template = try!(output::Template::parse(x, &config.index_fields)
+                                 .map_err(|e| MainError::new(e).with_kind(ErrorKind::InvalidArgument)));
+            return Ok(ResultFormat::Template(template));
+        }
+        Ok(match try!(output_format(submatches)) {
+            Some(x) => ResultFormat::Records(x),
+            None => ResultFormat::Default
+        })
     }
 }
 
-pub fn cli_main() {
-    match cli_main_raw() {
-        Err(e) => {
-            writeln!(&mut io::stderr(), "{}", e).unwrap();
-            process::exit(1);
-        },
-        _ => ()
-    };
+/// What results end with: NUL with `--print0`, for `xargs -0`, or else a newline.
+fn line_end(submatches: &ArgMatches) -> char {
+    if submatches.is_present("print0") { '\0' } else { '\n' }
 }
 
-pub fn cli_main_raw() -> MainResult<()> {
-    let matches = app::app().get_matches();
-
-    let command = matches.subcommand_name().unwrap();
+/// The `--format` given to a command, if any.
+fn output_format(submatches: &ArgMatches) -> MainResult<Option<OutputFormat>> {
+    match submatches.value_of("format") {
+        Some(x) => match OutputFormat::parse(x, submatches.is_present("header")) {
+            Some(format) => Ok(Some(format)),
+            None => Err(MainError::new(format!("Invalid format: {}", x)).with_kind(ErrorKind::InvalidArgument).into())
+        },
+        None => Ok(None)
+    }
+}
 
-    let config = match Configuration::new() {
-        Ok(x) => x,
-        Err(e) => {
-            return Err(MainError::new(format!("Error while reading configuration: {}", e)).into());
+/// With `record`, the printed addresses are recorded in the usage log. Unless `format` is the
+/// default, the results are printed in that format instead of mutt's.
+fn mutt_query<'a>(config: &Configuration, query: &Query, category: Option<&str>, record: bool,
+                  format: &ResultFormat, results: &ResultOptions) -> MainResult<()> {
+    if let ResultFormat::Default = *format {
+        println!("");  // For some reason mutt requires an empty line
+    }
+    // We need to ignore errors here, otherwise mutt's UI will glitch
+    if let Ok(items) = utils::index_query(config, query) {
+        let items: Vec<_> = results.apply(unique_addresses(items
+            .filter(|item| category.map_or(true, |x| item.in_category(x)))
+            .filter(|item| item.email.len() > 0 && item.name.len() > 0)))
+            .collect();
+        match *format {
+            ResultFormat::Records(format) => {
+                let records = contact_records(config, &items)
+                    .unwrap_or_else(|_| output::records_from_items(config, items.iter()));
+                print!("{}", output::format_records(&records, format));
+            },
+            ResultFormat::Template(ref template) => for item in items.iter() {
+                println!("{}", template.render(config, item));
+            },
+            // mutt shows the third column as additional information
+            ResultFormat::Default => for item in items.iter() {
+                println!("{}\t{}\t{}", item.email, item.name, item.types);
+            }
+        }
+        if record {
+            let _ = usage::record(&config.usage_path, items.iter().map(|x| &x.email[..]));
         }
     };
+    Ok(())
+}
 
-    let submatches = matches.subcommand_matches(command).expect("Internal error.");
+/// The records of the contacts of the given index entries, in their order. Records hold all
+/// addresses and phone numbers of each contact, not only those of the given entries, which for
+/// entries filtered on their email address would leave out every phone number.
+fn contact_records(config: &Configuration, items: &[utils::IndexItem]) -> io::Result<Vec<output::Record>> {
+    let fpaths: HashSet<_> = items.iter().filter_map(|x| x.resolved_path(config)).collect();
+    let rest: Vec<_> = try!(utils::index_query(config, &Query::new("")))
+        .filter(|x| x.resolved_path(config).map_or(false, |p| fpaths.contains(&p)))
+        .collect();
+    Ok(output::records_from_items(config, items.iter().chain(rest.iter())))
+}
 
-    match command {
-        "index" => {
-            println!("Rebuilding index file \"{}\"...", config.index_path.display());
-            try!(build_index(&config.index_path, &config.vdir_path));
-        },
-        "mutt-query" => {
-            let query = submatches.value_of("query").unwrap_or("");
-            try!(mutt_query(&config, &query[..]));
-        },
-        "file-query" => {
-            let query = submatches.value_of("query").unwrap_or("");
-            try!(file_query(&config, &query[..]));
+/// Print `email<TAB>name` lines for aerc's `address-book-cmd`.
+fn aerc_query(config: &Configuration, query: &Query, category: Option<&str>, results: &ResultOptions) -> MainResult<()> {
+    let items = unique_addresses(try!(utils::index_query(config, query).map_err(|e| index_error(config, e)))
+        .filter(|item| category.map_or(true, |x| item.in_category(x)))
+        .filter(|item| item.email.len() > 0));
+    for item in results.apply(items) {
+        println!("{}\t{}", item.email, item.name);
+    }
+    Ok(())
+}
+
+/// Print the filepaths of matching contacts, or the contacts in `format`, return how many. Without
+/// `--sort`, they're sorted by filepath. Templates are rendered for the first matching entry of
+/// each contact.
+fn file_query<'a>(config: &Configuration, query: &Query, format: &ResultFormat,
+                  results: &ResultOptions, end: char) -> MainResult<usize> {
+    let items: Vec<_> = results.sort(try!(utils::index_query(config, query).map_err(|e| index_error(config, e))))
+        .filter(|x| x.filepath.is_some())
+        .collect();
+    // Records are in the order their contacts first appear.
+    let mut seen = HashSet::new();
+    let firsts = items.iter().filter(|x| seen.insert(x.resolved_path(config)));
+    let mut records: Vec<_> = output::records_from_items(config, items.iter()).into_iter()
+        .zip(firsts)
+        .collect();
+    if results.sort.is_none() {
+        records.sort_by(|a, b| a.0.filepath.cmp(&b.0.filepath));
+    }
+    let records: Vec<_> = records.into_iter().skip(results.offset).take(results.limit).collect();
+    match *format {
+        ResultFormat::Records(format) => {
+            let records: Vec<_> = records.into_iter().map(|x| x.0).collect();
+            print!("{}", output::format_records(&records, format));
+            return Ok(records.len());
         },
-        "email-query" => {
-            let query = submatches.value_of("query").unwrap_or("");
-            try!(email_query(&config, &query[..]));
+        ResultFormat::Template(ref template) => for &(_, first) in records.iter() {
+            print!("{}{}", template.render(config, first), end);
         },
-        "add" => {
-            let stdin = io::stdin();
-            let mut email = String::new();
-            try!(stdin.lock().read_to_string(&mut email));
-            let contact = try!(utils::add_contact_from_email(
-                &config.vdir_path,
-                &email[..]
-            ));
-            println!("{}", contact.path.display());
+        ResultFormat::Default => for &(ref record, _) in records.iter() {
+            print!("{}{}", record.filepath.display(), end);
+        }
+    }
+    Ok(records.len())
+}
+
+/// Print the unmodified vCard files of all contacts matching `query`, one after another. Return
+/// the number of contacts.
+fn vcf_query(config: &Configuration, query: &Query, results: &ResultOptions) -> MainResult<usize> {
+    let mut seen = HashSet::new();
+    let mut fpaths: Vec<_> = results.sort(try!(utils::index_query(config, query).map_err(|e| index_error(config, e))))
+        .filter_map(|x| x.resolved_path(config))
+        .filter(|x| seen.insert(x.clone()))
+        .collect();
+    if results.sort.is_none() {
+        fpaths.sort();
+    }
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut count = 0;
+    for fpath in fpaths.into_iter().skip(results.offset).take(results.limit) {
+        let mut contents = vec![];
+        try!(try!(fs::File::open(&fpath)).read_to_end(&mut contents));
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            contents.extend_from_slice(b"\r\n");
+        }
+        try!(stdout.write_all(&contents));
+        count += 1;
+    }
+    Ok(count)
+}
 
-            let mut index_fp = try!(fs::OpenOptions::new()
-                                    .append(true)
-                                    .write(true)
-                                    .open(&config.index_path));
+/// Options of `email-query` for how results are printed.
+struct EmailOutput {
+    /// Append the type of each address.
+    show_type: bool,
+    /// Print all results on one line as a list of recipients, e.g. for expanding a category in
+    /// mutt.
+    join: bool,
+    /// Record the printed addresses in the usage log.
+    record: bool,
+    format: ResultFormat
+}
 
-            let index_entry = try!(utils::index_item_from_contact(&contact));
-            try!(index_fp.write_all(index_entry.as_bytes()));
-        },
-        "edit" => {
-            let query = submatches.value_of("file-or-query").unwrap_or("");
-            try!(edit_contact(&config, &query[..]));
-        },
-        _ => {
-            return Err(MainError::new(format!("Invalid command: {}", command)).into());
+/// Print the names and email addresses of matching contacts, return how many. Each line starts
+/// with `label` and a tab if given.
+fn email_query<'a>(config: &Configuration, query: &Query, category: Option<&str>, output: &EmailOutput,
+                   label: Option<&str>, results: &ResultOptions) -> MainResult<usize> {
+    let items: Vec<_> = results.apply(unique_addresses(try!(utils::index_query(config, query).map_err(|e| index_error(config, e)))
+        .filter(|item| category.map_or(true, |x| item.in_category(x)))
+        .filter(|item| item.name.len() > 0 && item.email.len() > 0)))
+        .collect();
+    let prefix = label.map_or(String::new(), |x| format!("{}\t", x));
+    if let ResultFormat::Records(format) = output.format {
+        let records = try!(contact_records(config, &items).map_err(|e| index_error(config, e)));
+        print!("{}", output::format_records(&records, format));
+    } else if let ResultFormat::Template(ref template) = output.format {
+        for item in items.iter() {
+            println!("{}{}", prefix, template.render(config, item));
         }
-    };
+    } else if output.join {
+        let recipients: Vec<String> = items.iter()
+            .map(|item| utils::format_address(&item.name, &item.email))
+            .collect();
+        if !recipients.is_empty() {
+            println!("{}{}", prefix, recipients.join(", "));
+        }
+    } else {
+        for item in items.iter() {
+            if output.show_type && item.types.len() > 0 {
+                println!("{}{} <{}>\t{}", prefix, item.name, item.email, item.types);
+            } else {
+                println!("{}{} <{}>", prefix, item.name, item.email);
+            }
+        };
+    }
+    if output.record {
+        try!(usage::record(&config.usage_path, items.iter().map(|x| &x.email[..])));
+    }
+    Ok(items.len())
+}
+
+/// Record when the addresses in the sent mail in the given Maildirs were last written to, in the
+/// contact log. Messages without a valid Date header are taken to be written when they were last
+/// modified.
+fn scan_sent(config: &Configuration, maildirs: &[&str]) -> MainResult<()> {
+    let mut contacts = vec![];
+    let mut count = 0;
+    for maildir in maildirs {
+        for fpath in try!(utils::list_maildir_messages(path::Path::new(maildir))) {
+            let header = try!(utils::read_email_header(&fpath));
+            let time = match utils::read_date_from_email(&header) {
+                Some(x) => x.timestamp(),
+                None => DateTime::<Utc>::from(try!(try!(fs::metadata(&fpath)).modified())).timestamp()
+            };
+            contacts.extend(utils::read_recipients_from_email(&header).into_iter()
+                            .filter(|&(_, ref email)| utils::is_valid_email(email))
+                            .map(|(_, email)| (email, time)));
+            count += 1;
+        }
+    }
+    let total = try!(usage::record_contacts(&config.contacted_path(), contacts.into_iter()));
+    println!("Scanned {} messages, {} addresses are known to the contact log.", count, total);
     Ok(())
 }
 
-fn edit_contact(config: &Configuration, query: &str) -> MainResult<()> {
-    let results = if get_pwd().join(query).is_file() {
-        vec![path::PathBuf::from(query)]
+/// Record the given addresses, or those read from stdin, in the usage log.
+fn touch_addresses(config: &Configuration, addresses: Vec<String>) -> MainResult<()> {
+    let addresses = if addresses.is_empty() {
+        let mut s = String::new();
+        try!(io::stdin().read_to_string(&mut s));
+        s.lines().map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect()
     } else {
-        try!(utils::file_query(config, query)).into_iter().collect()
+        addresses
     };
+    let mut emails = vec![];
+    for address in addresses.iter() {
+        match utils::parse_from_header(address) {
+            (_, Some(email)) if utils::is_valid_email(email.trim()) => emails.push(email.trim()),
+            _ => return Err(MainError::new(format!("Invalid address: {}", address)).with_kind(ErrorKind::InvalidArgument).into())
+        }
+    }
+    try!(usage::record(&config.usage_path, emails.into_iter()));
+    Ok(())
+}
 
-    if results.len() < 1 {
-        return Err(MainError::new("No such contact.").into());
-    } else if results.len() > 1 {
-        return Err(MainError::new("Ambiguous query.").into());
+/// Look up a program the way the shell would, return its path if it exists.
+fn find_executable(name: &str) -> Option<path::PathBuf> {
+    if name.contains('/') {
+        let p = path::PathBuf::from(name);
+        return if p.is_file() { Some(p) } else { None };
     }
+    let paths = match env::var_os("PATH") {
+        Some(x) => x,
+        None => return None
+    };
+    env::split_paths(&paths).map(|dir| dir.join(name)).find(|p| p.is_file())
+}
 
-    let fpath = &results[0];
-    editor::cli_main(fpath);
+/// Check the environment for common configuration problems and print a report.
+fn doctor() -> MainResult<()> {
+    let mut failed = false;
+    {
+        let mut check = |ok: bool, critical: bool, msg: String, hint: &str| {
+            println!("[{}] {}", if ok { " OK " } else if critical { "FAIL" } else { "WARN" }, msg);
+            if !ok {
+                println!("       {}", hint);
+                failed = failed || critical;
+            }
+        };
 
-    let fcontent = {
-        let mut fcontent = String::new();
-        let mut file = try!(fs::File::open(fpath));
-        try!(file.read_to_string(&mut fcontent));
-        fcontent
-    };
+        let config = match Configuration::new() {
+            Ok(x) => {
+                check(true, true, "Configuration is valid".to_owned(), "");
+                x
+            },
+            Err(e) => {
+                check(false, true, format!("Configuration is invalid: {}", e),
+                      "Fix the environment variables mentioned above.");
+                return Err(MainError::new("Some critical checks failed.").into());
+            }
+        };
 
-    if (&fcontent[..]).trim().len() == 0 {
-        try!(fs::remove_file(fpath));
-        return Err(MainError::new("Contact emptied, file removed.").into());
-    };
+        let mut vars: Vec<(&str, &path::Path)> = config.collections.iter().map(|x| ("MATES_DIR", x.as_path())).collect();
+        vars.push(("MATES_INDEX", &config.index_path));
+        for &(var, p) in vars.iter() {
+            check(!p.to_string_lossy().contains('~'), false,
+                  format!("{} is {}", var, p.display()),
+                  "mates doesn't expand '~', use $HOME instead.");
+        }
+
+        for dir in config.collections.iter() {
+            check(dir.is_dir(), true,
+                  format!("MATES_DIR {} is a directory", dir.display()),
+                  "Create it, or point MATES_DIR to your directory of vcf-files.");
+            check(utils::is_writable(dir), true,
+                  format!("MATES_DIR {} is writable", dir.display()),
+                  "Fix the directory's permissions, otherwise contacts can't be added or edited.");
+        }
 
+        let index_parent = match config.index_path.parent() {
+            Some(x) if x.as_os_str().is_empty() => get_pwd(),
+            Some(x) => x.to_owned(),
+            None => get_pwd()
+        };
+        check(index_parent.is_dir() && utils::is_writable(&index_parent), true,
+              format!("Index directory {} is writable", index_parent.display()),
+              "Create the directory or set MATES_INDEX to a writable location.");
+        check(config.index_path.is_file(), false,
+              format!("Index {} exists", config.index_path.display()),
+              "Run `mates index` to create it.");
+
+        if let Some(ref grep_cmd) = config.grep_cmd {
+            let grep_program = &grep_cmd[0];
+            check(find_executable(grep_program).is_some(), true,
+                  format!("Search command {:?} is available", grep_program),
+                  "Install it, or unset MATES_GREP to use the builtin search.");
+        }
+
+        check(get_envvar("TERM").map(|x| x != "dumb").unwrap_or(false), false,
+              "Terminal is usable for `mates edit`".to_owned(),
+              "Set TERM to your terminal type, the contact editor needs it.");
+    }
+
+    if failed {
+        Err(MainError::new("Some critical checks failed.").into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Print all contacts in `MATES_DIR` as 'name <email>', one line per email.
+fn list_contacts(config: &Configuration, sort: bool, format: Option<OutputFormat>, end: char,
+                 colors: Colors) -> MainResult<()> {
+    let contacts = read_contacts(try!(config.contact_files()));
+    match format {
+        Some(format) => print_records(config, &contacts, sort, format),
+        None => print_contacts(config, &contacts, sort, end, colors, &[])
+    }
     Ok(())
 }
 
-fn mutt_query<'a>(config: &Configuration, query: &str) -> MainResult<()> {
-    println!("");  // For some reason mutt requires an empty line
-    // We need to ignore errors here, otherwise mutt's UI will glitch
-    if let Ok(items) = utils::index_query(config, query) {
-        for item in items {
-            if item.email.len() > 0 && item.name.len() > 0 {
-                println!("{}\t{}", item.email, item.name);
-            };
-        };
+/// Print contacts in the given format, sorted by name if `sort`.
+fn print_records(config: &Configuration, contacts: &[utils::Contact], sort: bool, format: OutputFormat) {
+    let mut contacts: Vec<_> = contacts.iter().collect();
+    if sort {
+        contacts.sort_by_key(|x| utils::sort_name(&x.component, config.name_order));
+    }
+    let records: Vec<_> = contacts.iter().map(|x| output::Record::from_contact(config, x)).collect();
+    print!("{}", output::format_records(&records, format));
+}
+
+/// Print contacts as 'name <email>', one line per email, or just the name for contacts without
+/// email. Lines end with `end`. With colors, the parts of names and emails matching the `FN` and
+/// `EMAIL` filters of `search` are highlighted.
+fn print_contacts(config: &Configuration, contacts: &[utils::Contact], sort: bool, end: char, colors: Colors,
+                  filters: &[(&str, &str)]) {
+    let needles = |prop: &str| -> Vec<String> {
+        filters.iter().filter(|x| x.0 == prop).map(|x| x.1.to_owned()).collect()
     };
+    let (name_needles, email_needles) = (needles("FN"), needles("EMAIL"));
+    let mut lines = vec![];
+    for contact in contacts {
+        let name = utils::display_name(&contact.component, config.name_order).unwrap_or_else(String::new);
+        let sort_key = utils::sort_name(&contact.component, config.name_order);
+        let colored_name = colors.name_matching(&name, &name_needles);
+        let emails = utils::by_preference(contact.component.get_all("EMAIL"));
+        if emails.is_empty() {
+            lines.push((sort_key.clone(), colored_name.clone()));
+        }
+        for email in emails {
+            let colored_email = colors.email_matching(&email.value_as_string(), &email_needles);
+            lines.push((sort_key.clone(), format!("{} <{}>", colored_name, colored_email)));
+        }
+    }
+
+    if sort {
+        lines.sort_by_key(|&(ref key, _)| key.clone());
+    }
+    for (_, line) in lines {
+        print!("{}{}", line, end);
+    }
+}
+
+/// Print contacts in `MATES_DIR` that match all the given `(property, substring)` filters.
+fn search_contacts(config: &Configuration, filters: &[(&str, &str)], sort: bool, format: Option<OutputFormat>,
+                   colors: Colors) -> MainResult<()> {
+    let contacts: Vec<_> = read_contacts(try!(config.contact_files()))
+        .into_iter()
+        .filter(|c| filters.iter().all(|&(prop_name, needle)| {
+            let needle = needle.to_lowercase();
+            c.component.get_all(prop_name).iter().any(|p| p.value_as_string().to_lowercase().contains(&needle[..]))
+        }))
+        .collect();
+    match format {
+        Some(format) => print_records(config, &contacts, sort, format),
+        None => print_contacts(config, &contacts, sort, '\n', colors, filters)
+    }
     Ok(())
 }
 
-fn file_query<'a>(config: &Configuration, query: &str) -> MainResult<()> {
-    for path in try!(utils::file_query(config, query)).iter() {
-        println!("{}", path.display());
-    };
+fn group_query(config: &Configuration, category: &str) -> MainResult<()> {
+    for item in try!(utils::read_index(config).map_err(|e| index_error(config, e))) {
+        if item.email.len() == 0 || !item.in_category(category) {
+            continue;
+        }
+        let filepath = item.resolved_path(config).unwrap_or_else(path::PathBuf::new);
+        println!("{}\t{}\t{}", item.name, item.email, filepath.display());
+    }
     Ok(())
 }
 
-fn email_query<'a>(config: &Configuration, query: &str) -> MainResult<()> {
-    for item in try!(utils::index_query(config, query)) {
-        if item.name.len() > 0 && item.email.len() > 0 {
-            println!("{} <{}>", item.name, item.email);
-        };
+/// Print all categories with the number of contacts in each.
+fn list_groups(config: &Configuration) -> MainResult<()> {
+    let mut groups: BTreeMap<String, HashSet<path::PathBuf>> = BTreeMap::new();
+    for item in try!(utils::read_index(config).map_err(|e| index_error(config, e))) {
+        let filepath = item.filepath.clone().unwrap_or_else(path::PathBuf::new);
+        for category in item.categories {
+            groups.entry(category).or_insert_with(HashSet::new).insert(filepath.clone());
+        }
+    }
+
+    for (category, contacts) in groups.iter() {
+        println!("{}\t{}", category, contacts.len());
+    }
+    Ok(())
+}
+
+/// Print email addresses for shell completion, those whose name or email start with `partial`
+/// first. A name matches as a prefix if any of its words does, with or without diacritics. With
+/// `with_name`, `name <email>` is printed instead of only the address.
+fn complete(config: &Configuration, partial: &str, with_name: bool) -> MainResult<()> {
+    let partial = utils::fold_diacritics(partial);
+    let mut seen = HashSet::new();
+    let mut prefix_matches = vec![];
+    let mut other_matches = vec![];
+
+    for item in try!(utils::read_index(config).map_err(|e| index_error(config, e))) {
+        if item.email.len() == 0 || seen.contains(&item.email) {
+            continue;
+        }
+        let email = item.email.to_lowercase();
+        let name = &item.folded_name;
+        let candidate = if with_name { utils::format_address(&item.name, &item.email) } else { item.email.clone() };
+        if email.starts_with(&partial[..]) || name.split_whitespace().any(|x| x.starts_with(&partial[..])) {
+            prefix_matches.push(candidate);
+        } else if email.contains(&partial[..]) || name.contains(&partial[..]) {
+            other_matches.push(candidate);
+        } else {
+            continue;
+        }
+        seen.insert(item.email);
+    }
+
+    for candidate in prefix_matches.iter().chain(other_matches.iter()) {
+        println!("{}", candidate);
+    }
+    Ok(())
+}
+
+/// TYPE values for EMAIL properties defined by RFC 2426 and RFC 6350.
+const EMAIL_TYPES: &'static [&'static str] = &["INTERNET", "X400", "PREF", "HOME", "WORK"];
+
+/// Let the user choose one of the given index entries, through `MATES_PICKER` if set or a numbered
+/// menu otherwise.
+fn pick_item(config: &Configuration, items: Vec<utils::IndexItem>) -> MainResult<Option<utils::IndexItem>> {
+    if let Some(ref picker_cmd) = config.picker_cmd {
+        let mut process = try!(
+            utils::command_from_config(picker_cmd)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::inherit())
+            .spawn());
+        {
+            let stdin = process.stdin.as_mut().expect("Failed to get stdin of picker.");
+            for item in items.iter() {
+                let line = writeln!(stdin, "{} <{}>\t{}", item.name, item.email,
+                                    item.filepath.as_ref().map(|x| x.display().to_string()).unwrap_or_else(String::new));
+                // The picker may exit before reading everything
+                if line.is_err() {
+                    break;
+                }
+            }
+        }
+        let output = try!(process.wait_with_output());
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let selected = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        return Ok(items.into_iter().find(|item| {
+            selected.starts_with(&format!("{} <{}>", item.name, item.email)[..])
+        }));
+    }
+
+    let stderr = &mut io::stderr();
+    for (i, item) in items.iter().enumerate() {
+        try!(writeln!(stderr, "{:3}) {} <{}>", i + 1, item.name, item.email));
+    }
+    try!(write!(stderr, "Select a contact: "));
+    let mut answer = String::new();
+    try!(io::stdin().read_line(&mut answer));
+    Ok(match answer.trim().parse::<usize>() {
+        Ok(i) if i >= 1 && i <= items.len() => Some(items.into_iter().nth(i - 1).unwrap()),
+        _ => None
+    })
+}
+
+/// Let the user pick a contact and print its email, or filepath if `print_file` is set.
+fn pick_contact(config: &Configuration, print_file: bool) -> MainResult<()> {
+    let items: Vec<_> = try!(utils::read_index(config).map_err(|e| index_error(config, e))).into_iter()
+        .filter(|x| x.email.len() > 0)
+        .collect();
+    match try!(pick_item(config, items)) {
+        Some(item) => {
+            if print_file {
+                println!("{}", item.resolved_path(config).unwrap_or_else(path::PathBuf::new).display());
+            } else {
+                println!("{}", item.email);
+            }
+            Ok(())
+        },
+        None => Err(MainError::new("No contact selected.").into())
+    }
+}
+
+fn phone_query(config: &Configuration, query: &Query, format: &ResultFormat, results: &ResultOptions) -> MainResult<()> {
+    let items = try!(utils::index_query(config, query).map_err(|e| index_error(config, e)))
+        .filter(|item| item.name.len() > 0 && item.tel.len() > 0);
+    for item in results.apply(items) {
+        match *format {
+            ResultFormat::Template(ref template) => println!("{}", template.render(config, &item)),
+            _ => println!("{} <{}>", item.name, item.tel)
+        }
     };
     Ok(())
 }
 
+/// Storage format of the index.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IndexBackend {
+    /// Tab-separated text file, searchable with grep
+    Text,
+    /// The text index in a binary encoding that loads faster
+    Binary,
+    /// SQLite database, requires the `sqlite` cargo feature
+    Sqlite
+}
+
 pub struct Configuration {
+    pub index_backend: IndexBackend,
     pub index_path: path::PathBuf,
+    /// The first collection in `MATES_DIR`, where new contacts are created.
     pub vdir_path: path::PathBuf,
-    pub grep_cmd: String
+    /// All directories in `MATES_DIR`.
+    pub collections: Vec<path::PathBuf>,
+    /// `MATES_GREP` split into the program and its arguments, followed by `MATES_GREP_ARGS`.
+    pub grep_cmd: Option<Vec<String>>,
+    /// `MATES_PICKER` split into the program and its arguments.
+    pub picker_cmd: Option<Vec<String>>,
+    pub default_email_type: Option<String>,
+    pub auto_index: bool,
+    pub index_fields: Vec<String>,
+    /// Where `mates touch` and `--record` record used addresses.
+    pub usage_path: path::PathBuf,
+    /// How query commands interpret their search string, unless overridden.
+    pub match_mode: MatchMode,
+    /// In which order names are displayed and sorted.
+    pub name_order: NameOrder,
+    /// The vCard version new and imported contacts are written in.
+    pub vcard_version: VcardVersion
 }
 
 impl Configuration {
+    /// The contact log filled by `mates scan-sent`, next to the index.
+    pub fn contacted_path(&self) -> path::PathBuf {
+        let mut x = self.index_path.clone().into_os_string();
+        x.push(".contacted");
+        path::PathBuf::from(x)
+    }
+
+    /// Directory where the deleted contact `fpath` is moved to, in its own collection so it stays
+    /// on the same filesystem.
+    pub fn trash_path(&self, fpath: &path::Path) -> path::PathBuf {
+        self.collection_of(fpath).join(".trash")
+    }
+
+    /// The log of deleted contacts of all collections, kept in the trash of the first one.
+    pub fn trash_log_path(&self) -> path::PathBuf {
+        self.vdir_path.join(".trash").join("log")
+    }
+
+    /// Paths of the contact files in all collections.
+    pub fn contact_files(&self) -> io::Result<Vec<path::PathBuf>> {
+        let mut rv = vec![];
+        for dir in self.collections.iter() {
+            rv.extend(try!(utils::list_contact_files(dir)));
+        }
+        Ok(rv)
+    }
+
+    /// The collection containing `fpath`, or the first one if there's none.
+    pub fn collection_of(&self, fpath: &path::Path) -> &path::Path {
+        for dir in self.collections.iter() {
+            if utils::relative_to_dir(fpath, dir) != fpath {
+                return dir;
+            }
+        }
+        &self.vdir_path
+    }
+
+    /// Name of the collection containing `fpath`, the name of its directory. If there's only one
+    /// collection, it has no name.
+    pub fn collection_name(&self, fpath: &path::Path) -> String {
+        if self.collections.len() < 2 {
+            return String::new();
+        }
+        match self.collection_of(fpath).file_name() {
+            Some(x) => x.to_string_lossy().into_owned(),
+            None => String::new()
+        }
+    }
+
+    /// The filepath of a contact as stored in the index. It's relative to the contact's collection,
+    /// prefixed with the collection's name if there are several.
+    pub fn indexed_path(&self, fpath: &path::Path) -> path::PathBuf {
+        let rv = utils::relative_to_dir(fpath, self.collection_of(fpath));
+        if self.collections.len() < 2 || rv.is_absolute() {
+            rv
+        } else {
+            path::PathBuf::from(self.collection_name(fpath)).join(rv)
+        }
+    }
+
+    /// The reverse of `indexed_path`.
+    pub fn resolve_indexed_path(&self, filepath: &path::Path) -> path::PathBuf {
+        if self.collections.len() > 1 {
+            let mut components = filepath.components();
+            if let Some(path::Component::Normal(name)) = components.next() {
+                for dir in self.collections.iter() {
+                    if dir.file_name() == Some(name) {
+                        return dir.join(components.as_path());
+                    }
+                }
+            }
+        }
+        self.vdir_path.join(filepath)
+    }
+
     pub fn new() -> Result<Configuration, String> {
+        let collections: Vec<path::PathBuf> = match get_envvar("MATES_DIR") {
+            Some(x) => x.split(':').filter(|x| !x.is_empty()).map(path::PathBuf::from).collect(),
+            None => vec![]
+        };
+        if collections.is_empty() {
+            return Err("MATES_DIR must be set to your vdir path (directory of vcf-files).".to_owned());
+        }
+        if collections.len() > 1 {
+            let mut names = HashSet::new();
+            for dir in collections.iter() {
+                match dir.file_name() {
+                    Some(name) if names.insert(name.to_owned()) => (),
+                    _ => return Err(format!("MATES_DIR: Collection directories need distinct names, {} is ambiguous.",
+                                            dir.display()))
+                }
+            }
+        }
+
+        let index_path = match get_envvar("MATES_INDEX") {
+            Some(x) => path::PathBuf::from(&x),
+            None => match get_envvar("HOME") {
+                Some(home) => get_pwd().join(&home).join(".mates_index"),
+                None => return Err("Unable to determine user's home directory.".to_owned())
+            }
+        };
+
         Ok(Configuration {
-            index_path: match get_envvar("MATES_INDEX") {
+            index_backend: match get_envvar("MATES_INDEX_BACKEND") {
+                None => IndexBackend::Text,
+                Some(ref x) if x == "text" => IndexBackend::Text,
+                Some(ref x) if x == "binary" => IndexBackend::Binary,
+                Some(ref x) if x == "sqlite" && cfg!(feature = "sqlite") => IndexBackend::Sqlite,
+                Some(ref x) if x == "sqlite" => return Err(
+                    "MATES_INDEX_BACKEND is sqlite, but mates was built without the sqlite feature.".to_owned()),
+                Some(x) => return Err(format!("MATES_INDEX_BACKEND: Unknown backend {:?}, must be text, binary or sqlite.", x))
+            },
+            index_path: index_path.clone(),
+            usage_path: match get_envvar("MATES_USAGE") {
                 Some(x) => path::PathBuf::from(&x),
-                None => match get_envvar("HOME") {
-                    Some(home) => get_pwd().join(&home).join(".mates_index"),
-                    None => return Err("Unable to determine user's home directory.".to_owned())
+                None => {
+                    let mut x = index_path.into_os_string();
+                    x.push(".usage");
+                    path::PathBuf::from(x)
                 }
             },
-            vdir_path: match get_envvar("MATES_DIR") {
-                Some(x) => path::PathBuf::from(&x),
-                None => return Err("MATES_DIR must be set to your vdir path (directory of vcf-files).".to_owned())
+            vdir_path: collections[0].clone(),
+            collections: collections,
+            grep_cmd: match try!(command_from_envvar("MATES_GREP")) {
+                Some(mut cmd) => {
+                    if let Some(x) = get_envvar("MATES_GREP_ARGS") {
+                        cmd.extend(try!(utils::split_shell_words(&x).map_err(|e| format!("MATES_GREP_ARGS: {}", e))));
+                    }
+                    Some(cmd)
+                },
+                None => None
             },
-            grep_cmd: match get_envvar("MATES_GREP") {
-                Some(x) => x,
-                None => "grep -i".to_owned()
+            picker_cmd: try!(command_from_envvar("MATES_PICKER")),
+            default_email_type: match get_envvar("MATES_DEFAULT_EMAIL_TYPE") {
+                Some(x) => {
+                    let x = x.to_uppercase();
+                    for token in x.split(',') {
+                        if !EMAIL_TYPES.contains(&token) && !token.starts_with("X-") {
+                            return Err(format!("MATES_DEFAULT_EMAIL_TYPE: Unknown type {:?}, must be one of {}, or start with X-.",
+                                               token, EMAIL_TYPES.join(", ")));
+                        }
+                    }
+                    Some(x)
+                },
+                None => None
+            },
+            name_order: match get_envvar("MATES_NAME_ORDER") {
+                None => NameOrder::Given,
+                Some(ref x) if x == "given" => NameOrder::Given,
+                Some(ref x) if x == "family" => NameOrder::Family,
+                Some(x) => return Err(format!("MATES_NAME_ORDER: Unknown order {:?}, must be given or family.", x))
+            },
+            vcard_version: match get_envvar("MATES_VCARD_VERSION") {
+                None => VcardVersion::V4,
+                Some(ref x) if x == "4.0" => VcardVersion::V4,
+                Some(ref x) if x == "3.0" => VcardVersion::V3,
+                Some(x) => return Err(format!("MATES_VCARD_VERSION: Unknown version {:?}, must be 4.0 or 3.0.", x))
+            },
+            auto_index: match get_envvar("MATES_AUTO_INDEX") {
+                None => true,
+                Some(ref x) if x == "1" || x == "yes" || x == "true" => true,
+                Some(ref x) if x == "0" || x == "no" || x == "false" => false,
+                Some(x) => return Err(format!("MATES_AUTO_INDEX: Invalid value {:?}, must be 1 or 0.", x))
+            },
+            index_fields: {
+                let fields = get_envvar("MATES_INDEX_FIELDS").unwrap_or_else(|| "NICKNAME,ORG".to_owned());
+                let mut rv = vec![];
+                for field in fields.split(',').map(|x| x.trim().to_uppercase()) {
+                    if field.is_empty() || utils::FIXED_INDEX_FIELDS.contains(&&field[..]) {
+                        continue;
+                    }
+                    if !field.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                        return Err(format!("MATES_INDEX_FIELDS: Invalid property name {:?}.", field));
+                    }
+                    if !rv.contains(&field) {
+                        rv.push(field);
+                    }
+                }
+                rv
+            },
+            match_mode: match get_envvar("MATES_MATCH") {
+                None => MatchMode::Substring,
+                Some(ref x) if x == "substring" => MatchMode::Substring,
+                Some(ref x) if x == "regex" => MatchMode::Regex,
+                Some(ref x) if x == "fuzzy" => MatchMode::Fuzzy,
+                Some(ref x) if x == "phonetic" => MatchMode::Phonetic,
+                Some(x) => return Err(format!("MATES_MATCH: Unknown mode {:?}, must be substring, regex, fuzzy or phonetic.", x))
             }
         })
     }
 }
 
 
+/// The class of a failure, which scripts can tell apart by the exit status or the `kind` of the
+/// JSON error printed with `--format json`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorKind {
+    /// No contact matched a query that had to find one.
+    NotFound,
+    /// A query that had to find a single contact matched several.
+    Ambiguous,
+    /// An invalid query, option value or command line.
+    InvalidArgument,
+    /// The index couldn't be read, or is out of date.
+    Index,
+    /// The configuration in the environment is invalid.
+    Config,
+    /// Anything else, like errors reading or writing contacts.
+    Other
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ErrorKind::NotFound => "not-found",
+            ErrorKind::Ambiguous => "ambiguous",
+            ErrorKind::InvalidArgument => "invalid-argument",
+            ErrorKind::Index => "index",
+            ErrorKind::Config => "config",
+            ErrorKind::Other => "other"
+        }
+    }
+
+    pub fn exit_status(&self) -> i32 {
+        match *self {
+            ErrorKind::Other => 1,
+            ErrorKind::NotFound => 2,
+            ErrorKind::Ambiguous => 3,
+            ErrorKind::InvalidArgument => 4,
+            ErrorKind::Index => 5,
+            ErrorKind::Config => 6
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub struct MainError {
     desc: String,
+    kind: ErrorKind,
+    path: Option<path::PathBuf>,
 }
 
 pub type MainResult<T> = Result<T, Box<Error>>;
@@ -270,6 +2555,27 @@ impl MainError {
     pub fn new<T: Into<String>>(desc: T) -> Self {
         MainError {
             desc: desc.into(),
+            kind: ErrorKind::Other,
+            path: None,
         }
     }
+
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// The file the error is about.
+    pub fn with_path<P: Into<path::PathBuf>>(mut self, path: P) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> Option<&path::Path> {
+        self.path.as_ref().map(|x| x.as_path())
+    }
 }
@@ -0,0 +1,95 @@
+//! ANSI colors for output meant for humans, as printed by `list`, `show` and `search`.
+
+use std::env;
+
+use utils;
+
+const NAME: &'static str = "1";
+const EMAIL: &'static str = "36";
+const LABEL: &'static str = "2";
+const MATCH: &'static str = "1;33";
+
+/// Whether and how to color output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Colors {
+    enabled: bool
+}
+
+impl Colors {
+    /// Colors for the `--color` option: `always`, `never` or `auto`, the default, which colors
+    /// output to terminals unless `NO_COLOR` is set.
+    pub fn from_setting(setting: Option<&str>) -> Colors {
+        let enabled = match setting {
+            Some("always") => true,
+            Some("never") => false,
+            _ => env::var_os("NO_COLOR").map_or(true, |x| x.is_empty()) && utils::stdout_is_terminal()
+        };
+        Colors { enabled: enabled }
+    }
+
+    pub fn name(&self, s: &str) -> String {
+        self.paint(s, NAME)
+    }
+
+    pub fn email(&self, s: &str) -> String {
+        self.paint(s, EMAIL)
+    }
+
+    pub fn label(&self, s: &str) -> String {
+        self.paint(s, LABEL)
+    }
+
+    /// A name with the parts matching any of `needles` highlighted, ignoring case.
+    pub fn name_matching(&self, s: &str, needles: &[String]) -> String {
+        self.paint_matching(s, NAME, needles)
+    }
+
+    /// An email address with the parts matching any of `needles` highlighted, ignoring case.
+    pub fn email_matching(&self, s: &str, needles: &[String]) -> String {
+        self.paint_matching(s, EMAIL, needles)
+    }
+
+    fn paint(&self, s: &str, style: &str) -> String {
+        if self.enabled && !s.is_empty() {
+            format!("\x1b[{}m{}\x1b[0m", style, s)
+        } else {
+            s.to_owned()
+        }
+    }
+
+    fn paint_matching(&self, s: &str, style: &str, needles: &[String]) -> String {
+        if !self.enabled {
+            return s.to_owned();
+        }
+        let chars: Vec<char> = s.chars().collect();
+        let fold = |c: char| c.to_lowercase().next().unwrap_or(c);
+        let folded: Vec<char> = chars.iter().map(|&c| fold(c)).collect();
+        let mut highlighted = vec![false; chars.len()];
+        for needle in needles {
+            let needle: Vec<char> = needle.chars().map(&fold).collect();
+            if needle.is_empty() || needle.len() > folded.len() {
+                continue;
+            }
+            for start in 0..folded.len() - needle.len() + 1 {
+                if folded[start..start + needle.len()] == needle[..] {
+                    for x in highlighted[start..start + needle.len()].iter_mut() {
+                        *x = true;
+                    }
+                }
+            }
+        }
+
+        let mut rv = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let mut j = i;
+            while j < chars.len() && highlighted[j] == highlighted[i] {
+                j += 1;
+            }
+            let part: String = chars[i..j].iter().collect();
+            rv.push_str(&self.paint(&part, if highlighted[i] { MATCH } else { style }));
+            i = j;
+        }
+        rv
+    }
+}
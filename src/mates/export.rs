@@ -0,0 +1,565 @@
+use std::collections::HashSet;
+
+use base64;
+use chrono::Utc;
+use vobject::{Component,Property,unescape_chars};
+
+use utils::{Contact,VcardVersion,by_preference,fold_diacritics,format_address,format_timestamp,parse_date_value,
+            property_types,set_property_types,split_list_value,split_value,write_component_sorted};
+
+/// Formats of `mates export`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat {
+    /// One multi-VCARD file, converted to vCard 3.0 with `vcard3`.
+    Vcf { vcard3: bool },
+    /// LDIF with an `inetOrgPerson` entry per contact, as Thunderbird and LDAP directories import.
+    Ldif,
+    /// A heading per contact with the properties org-contacts reads, for Emacs.
+    Org,
+    /// A self-contained HTML page listing names, organizations, addresses, phone numbers and photos.
+    Html,
+    /// An iCalendar file with yearly events for birthdays and anniversaries.
+    Ics
+}
+
+impl ExportFormat {
+    pub fn parse(s: &str, vcard3: bool) -> Option<ExportFormat> {
+        match s {
+            "vcf" => Some(ExportFormat::Vcf { vcard3: vcard3 }),
+            "ldif" => Some(ExportFormat::Ldif),
+            "org" => Some(ExportFormat::Org),
+            "html" => Some(ExportFormat::Html),
+            "ics" => Some(ExportFormat::Ics),
+            _ => None
+        }
+    }
+}
+
+/// Render the given contacts in the given format.
+pub fn export(contacts: &[Contact], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Vcf { vcard3 } => to_vcf(contacts, vcard3),
+        ExportFormat::Ldif => to_ldif(contacts),
+        ExportFormat::Org => to_org_contacts(contacts),
+        ExportFormat::Html => to_html(contacts),
+        ExportFormat::Ics => to_ics(contacts)
+    }
+}
+
+/// Properties that only exist in vCard 4.0 and have no 3.0 equivalent.
+const VCARD4_ONLY_PROPS: &'static [&'static str] = &[
+    "KIND", "GENDER", "ANNIVERSARY", "LANG", "MEMBER", "RELATED", "CLIENTPIDMAP", "XML"
+];
+
+/// Convert a vCard 4.0 component into something vCard 3.0 clients understand. 4.0-only properties
+/// are kept as X- properties.
+pub fn downconvert_to_3(comp: &mut Component) {
+    comp.set(Property::new("VERSION", "3.0"));
+
+    for prop_name in VCARD4_ONLY_PROPS {
+        if let Some(props) = comp.remove(prop_name) {
+            for mut prop in props {
+                prop.name = format!("X-{}", prop_name);
+                comp.push(prop);
+            }
+        }
+    }
+
+    for props in comp.props.values_mut() {
+        for prop in props.iter_mut() {
+            // PREF=1 becomes TYPE=PREF
+            if prop.params.remove("PREF").is_some() {
+                let types = match prop.params.get("TYPE") {
+                    Some(t) => format!("{},PREF", t),
+                    None => "PREF".to_owned()
+                };
+                prop.params.insert("TYPE".to_owned(), types);
+            }
+            if let Some(t) = prop.params.remove("TYPE") {
+                prop.params.insert("TYPE".to_owned(), t.trim_matches('"').to_uppercase());
+            }
+
+            // TEL;VALUE=uri:tel:+1234 becomes TEL:+1234
+            if prop.name == "TEL" && prop.params.get("VALUE").map(|x| x.to_lowercase()) == Some("uri".to_owned()) {
+                prop.params.remove("VALUE");
+                if prop.raw_value.starts_with("tel:") {
+                    prop.raw_value = prop.raw_value[4..].to_owned();
+                }
+            }
+
+            // PHOTO:data:image/jpeg;base64,... becomes PHOTO;ENCODING=b;TYPE=JPEG:...
+            if (prop.name == "PHOTO" || prop.name == "LOGO") && prop.raw_value.starts_with("data:image/") {
+                if let Some(uri) = photo_data_uri(prop) {
+                    let data_start = uri.find(";base64,").unwrap();
+                    prop.params.insert("ENCODING".to_owned(), "b".to_owned());
+                    prop.params.insert("TYPE".to_owned(), uri["data:image/".len()..data_start].to_uppercase());
+                    prop.raw_value = uri[data_start + 8..].to_owned();
+                }
+            }
+        }
+    }
+}
+
+/// Convert a vCard 3.0 (or older) component into vCard 4.0, the reverse of `downconvert_to_3`.
+/// The `PREF` type becomes `PREF=1`, and embedded photos become `data:` URIs.
+pub fn upconvert_to_4(comp: &mut Component) {
+    comp.set(Property::new("VERSION", "4.0"));
+
+    for prop_name in VCARD4_ONLY_PROPS {
+        let x_name = format!("X-{}", prop_name);
+        if comp.get_all(prop_name).is_empty() {
+            if let Some(props) = comp.remove(&x_name) {
+                for mut prop in props {
+                    prop.name = prop_name.to_string();
+                    comp.push(prop);
+                }
+            }
+        }
+    }
+
+    for props in comp.props.values_mut() {
+        for prop in props.iter_mut() {
+            // vCard 4.0 has no INTERNET type, every EMAIL is one.
+            if let Some(t) = prop.params.remove("TYPE") {
+                let types: Vec<&str> = t.trim_matches('"').split(',')
+                    .filter(|x| prop.name != "EMAIL" || x.trim().to_uppercase() != "INTERNET")
+                    .collect();
+                set_property_types(prop, &types.join(","), VcardVersion::V4);
+            }
+
+            if (prop.name == "PHOTO" || prop.name == "LOGO") && prop.params.contains_key("ENCODING") {
+                if let Some(uri) = photo_data_uri(prop) {
+                    prop.params.remove("ENCODING");
+                    prop.params.remove("TYPE");
+                    prop.raw_value = uri;
+                }
+            }
+        }
+    }
+}
+
+/// Convert a component into the given vCard version, see `downconvert_to_3` and `upconvert_to_4`.
+pub fn convert_to_version(comp: &mut Component, version: VcardVersion) {
+    match version {
+        VcardVersion::V3 => downconvert_to_3(comp),
+        VcardVersion::V4 => upconvert_to_4(comp)
+    }
+}
+
+/// A contact as vCard 3.0 without photos and other embedded media, small enough for a QR code and
+/// understood by phone cameras.
+pub fn to_compact_vcard(comp: &Component) -> String {
+    let mut comp = comp.clone();
+    for prop_name in &["PHOTO", "LOGO", "SOUND", "KEY", "PRODID"] {
+        comp.remove(prop_name);
+    }
+    downconvert_to_3(&mut comp);
+    write_component_sorted(&comp)
+}
+
+/// Concatenate the given contacts into one multi-VCARD string.
+pub fn to_vcf(contacts: &[Contact], vcard3: bool) -> String {
+    let mut rv = String::new();
+    for contact in contacts {
+        if vcard3 {
+            let mut comp = contact.component.clone();
+            downconvert_to_3(&mut comp);
+            rv.push_str(&write_component_sorted(&comp));
+        } else {
+            rv.push_str(&write_component_sorted(&contact.component));
+        }
+    }
+    rv
+}
+
+/// Write the given contacts as LDIF entries of the `inetOrgPerson` object class. `FN` becomes
+/// `cn`, `N` `sn` and `givenName`, `EMAIL` `mail`, `ORG` `o` and `ou`, and `TEL`, depending on its
+/// type, `mobile`, `homePhone`, `facsimileTelephoneNumber`, `pager` or `telephoneNumber`.
+pub fn to_ldif(contacts: &[Contact]) -> String {
+    let mut rv = String::new();
+    for contact in contacts {
+        let comp = &contact.component;
+        let name = comp.get_only("FN").map(|x| x.value_as_string()).unwrap_or_default();
+        let emails: Vec<String> = by_preference(comp.get_all("EMAIL")).iter().map(|x| x.value_as_string()).collect();
+        let raw_n = comp.get_only("N").map(|x| x.raw_value.clone()).unwrap_or_default();
+        let mut n_fields = raw_n.split(';').map(|x| unescape_chars(x.trim()));
+        let family = n_fields.next().unwrap_or_default();
+        let given = n_fields.next().unwrap_or_default();
+
+        // Thunderbird names entries by name and first (here the preferred) address.
+        let mut dn = format!("cn={}", ldap_dn_value(&name));
+        if let Some(email) = emails.first() {
+            dn.push_str(&format!(",mail={}", ldap_dn_value(email)));
+        }
+        rv.push_str(&ldif_line("dn", &dn));
+        for class in &["top", "person", "organizationalPerson", "inetOrgPerson"] {
+            rv.push_str(&ldif_line("objectClass", class));
+        }
+        rv.push_str(&ldif_line("cn", &name));
+        // `sn` is required by the person class.
+        rv.push_str(&ldif_line("sn", if family.is_empty() { &name } else { &family }));
+        if !given.is_empty() {
+            rv.push_str(&ldif_line("givenName", &given));
+        }
+        for email in emails.iter() {
+            rv.push_str(&ldif_line("mail", email));
+        }
+        for tel in comp.get_all("TEL") {
+            let types = property_types(tel);
+            let has_type = |t: &str| types.split(',').any(|x| x.trim() == t);
+            let attr = if has_type("CELL") {
+                "mobile"
+            } else if has_type("FAX") {
+                "facsimileTelephoneNumber"
+            } else if has_type("PAGER") {
+                "pager"
+            } else if has_type("HOME") {
+                "homePhone"
+            } else {
+                "telephoneNumber"
+            };
+            let number = tel.value_as_string();
+            rv.push_str(&ldif_line(attr, number.trim_left_matches("tel:")));
+        }
+        if let Some(org) = comp.get_only("ORG") {
+            let mut units = split_value(&org.raw_value, ';').into_iter();
+            if let Some(o) = units.next() {
+                rv.push_str(&ldif_line("o", &o));
+            }
+            for ou in units {
+                rv.push_str(&ldif_line("ou", &ou));
+            }
+        }
+        rv.push('\n');
+    }
+    rv
+}
+
+/// Write the given contacts as org-contacts entries: a heading with the name and a property drawer
+/// with `EMAIL`, `PHONE`, `NICKNAME`, `BIRTHDAY`, `ADDRESS` and `NOTE`. Contacts are sorted by name
+/// and then filepath, so the output only changes where contacts did.
+pub fn to_org_contacts(contacts: &[Contact]) -> String {
+    let mut sorted: Vec<(String, &Contact)> = contacts.iter().map(|contact| {
+        let name = contact.component.get_only("FN").map(|x| x.value_as_string()).unwrap_or_default();
+        (org_value(&name), contact)
+    }).collect();
+    sorted.sort_by(|a, b| (fold_diacritics(&a.0), &a.1.path).cmp(&(fold_diacritics(&b.0), &b.1.path)));
+
+    let mut rv = String::new();
+    for (name, contact) in sorted {
+        let comp = &contact.component;
+        let values = |prop: &str| -> Vec<String> {
+            comp.get_all(prop).iter().map(|x| org_value(&x.value_as_string())).filter(|x| !x.is_empty()).collect()
+        };
+        let mut props = vec![];
+        props.push(("EMAIL", values("EMAIL").join(" ")));
+        // Numbers are separated by spaces, so they can't contain any.
+        let phones: Vec<String> = values("TEL").iter()
+            .map(|x| x.trim_left_matches("tel:").split_whitespace().collect())
+            .collect();
+        props.push(("PHONE", phones.join(" ")));
+        let nicknames: Vec<String> = comp.get_all("NICKNAME").iter()
+            .flat_map(|x| split_list_value(&x.raw_value))
+            .map(|x| org_value(&x))
+            .collect();
+        props.push(("NICKNAME", nicknames.join(", ")));
+        let birthday = comp.get_only("BDAY").and_then(|x| parse_date_value(&x.value_as_string()));
+        if let Some((Some(year), month, day)) = birthday {
+            props.push(("BIRTHDAY", format!("{:04}-{:02}-{:02}", year, month, day)));
+        }
+        if let Some(adr) = comp.get_all("ADR").first() {
+            props.push(("ADDRESS", org_value(&split_value(&adr.raw_value, ';').join(", "))));
+        }
+        props.push(("NOTE", values("NOTE").join(" ")));
+
+        rv.push_str(&format!("* {}\n:PROPERTIES:\n", name));
+        for (key, value) in props {
+            if !value.is_empty() {
+                rv.push_str(&format!(":{}: {}\n", key, value));
+            }
+        }
+        rv.push_str(":END:\n");
+    }
+    rv
+}
+
+/// A value on a single line, as headings and properties of org files need.
+fn org_value(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Write a calendar with a yearly all-day event for each `BDAY` and `ANNIVERSARY` of the given
+/// contacts. Dates without a year start in 2000. Events on February 29 fall on February 28 in other
+/// years, as in `mates birthdays`.
+pub fn to_ics(contacts: &[Contact]) -> String {
+    let mut calendar = Component::new("VCALENDAR");
+    calendar.push(Property::new("VERSION", "2.0"));
+    calendar.push(Property::new("PRODID", "-//mates//mates//EN"));
+    let now = format_timestamp(&Utc::now());
+
+    for contact in contacts {
+        let comp = &contact.component;
+        let name = comp.get_only("FN").map(|x| x.value_as_string()).unwrap_or_default();
+        let uid = comp.get_only("UID").map(|x| x.value_as_string())
+            .unwrap_or_else(|| contact.path.file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default());
+        for &(prop_name, kind) in &[("BDAY", "birthday"), ("ANNIVERSARY", "anniversary")] {
+            let date = comp.get_only(prop_name).and_then(|x| parse_date_value(&x.value_as_string()));
+            let (year, month, day) = match date {
+                Some(x) => x,
+                None => continue
+            };
+            let mut event = Component::new("VEVENT");
+            event.push(Property::new("UID", format!("{}-{}", uid, kind)));
+            event.push(Property::new("DTSTAMP", &now[..]));
+            let mut start = Property::new("DTSTART", format!("{:04}{:02}{:02}", year.unwrap_or(2000), month, day));
+            start.params.insert("VALUE".to_owned(), "DATE".to_owned());
+            event.push(start);
+            let mut rrule = Property::new("RRULE", "");
+            rrule.raw_value = if month == 2 && day == 29 {
+                "FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=-1".to_owned()
+            } else {
+                "FREQ=YEARLY".to_owned()
+            };
+            event.push(rrule);
+            event.push(Property::new("SUMMARY", format!("{}'s {}", name, kind)));
+            event.push(Property::new("TRANSP", "TRANSPARENT"));
+            calendar.subcomponents.push(event);
+        }
+    }
+    write_component_sorted(&calendar)
+}
+
+const HTML_STYLE: &'static str = "
+body { font-family: sans-serif; margin: 2em; }
+.contact { display: inline-block; vertical-align: top; width: 20em; margin: 0 1em 1.5em 0; break-inside: avoid; }
+.contact img { float: right; width: 4em; height: 4em; object-fit: cover; border-radius: 50%; }
+.contact h2 { font-size: 1.1em; margin: 0 0 .2em; }
+.contact p { margin: 0 0 .3em; color: #555; }
+.contact ul { list-style: none; margin: 0; padding: 0; }
+.type { color: #888; font-size: .85em; }
+a { color: inherit; }
+";
+
+/// Write the given contacts, sorted by name, as a single HTML page for printing or sharing. Photos
+/// embedded in the vCards are kept, photos linked from elsewhere are left out so the page is
+/// self-contained.
+pub fn to_html(contacts: &[Contact]) -> String {
+    let mut sorted: Vec<&Contact> = contacts.iter().collect();
+    sorted.sort_by_key(|x| {
+        let name = x.component.get_only("FN").map(|x| x.value_as_string()).unwrap_or_default();
+        (fold_diacritics(&name), x.path.clone())
+    });
+
+    let mut rv = String::new();
+    rv.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Contacts</title>\n");
+    rv.push_str(&format!("<style>{}</style>\n</head>\n<body>\n", HTML_STYLE));
+    for contact in sorted {
+        let comp = &contact.component;
+        rv.push_str("<section class=\"contact\">\n");
+        if let Some(src) = comp.get_all("PHOTO").iter().filter_map(photo_data_uri).next() {
+            rv.push_str(&format!("<img src=\"{}\" alt=\"\">\n", src));
+        }
+        let name = comp.get_only("FN").map(|x| x.value_as_string()).unwrap_or_default();
+        rv.push_str(&format!("<h2>{}</h2>\n", html_escape(&name)));
+        let mut details: Vec<String> = comp.get_all("TITLE").iter().map(|x| x.value_as_string()).collect();
+        if let Some(org) = comp.get_only("ORG") {
+            details.push(split_value(&org.raw_value, ';').join(", "));
+        }
+        if !details.is_empty() {
+            rv.push_str(&format!("<p>{}</p>\n", html_escape(&details.join(", "))));
+        }
+        rv.push_str("<ul>\n");
+        for email in comp.get_all("EMAIL") {
+            let email_value = email.value_as_string();
+            rv.push_str(&format!("<li><a href=\"mailto:{0}\">{0}</a>{1}</li>\n", html_escape(&email_value),
+                                 html_types(email)));
+        }
+        for tel in comp.get_all("TEL") {
+            let number = tel.value_as_string();
+            let number = number.trim_left_matches("tel:");
+            let uri: String = number.chars().filter(|c| !c.is_whitespace()).collect();
+            rv.push_str(&format!("<li><a href=\"tel:{}\">{}</a>{}</li>\n", html_escape(&uri),
+                                 html_escape(number), html_types(tel)));
+        }
+        for adr in comp.get_all("ADR") {
+            rv.push_str(&format!("<li>{}{}</li>\n", html_escape(&split_value(&adr.raw_value, ';').join(", ")),
+                                 html_types(adr)));
+        }
+        rv.push_str("</ul>\n</section>\n");
+    }
+    rv.push_str("</body>\n</html>\n");
+    rv
+}
+
+/// The types of a property as a small label, e.g. ` <span class="type">work</span>`.
+fn html_types(prop: &Property) -> String {
+    let types: Vec<String> = property_types(prop).split(',')
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty() && x != "internet" && x != "pref" && x != "voice")
+        .collect();
+    if types.is_empty() {
+        String::new()
+    } else {
+        format!(" <span class=\"type\">{}</span>", html_escape(&types.join(", ")))
+    }
+}
+
+/// A `data:` URI for an embedded photo, either given as one (vCard 4.0) or as base64 with
+/// `ENCODING=b` (vCard 3.0). None for photos linked from elsewhere.
+fn photo_data_uri(prop: &Property) -> Option<String> {
+    let value: String = prop.raw_value.chars().filter(|c| !c.is_whitespace()).collect();
+    let is_base64 = |s: &str| s.chars().all(|c| c.is_ascii_alphanumeric() || "+/=".contains(c));
+    if value.starts_with("data:image/") {
+        let data_start = match value.find(";base64,") {
+            Some(i) => i + 8,
+            None => return None
+        };
+        if is_base64(&value[data_start..]) && !value[..data_start].contains(&['"', '<', '>', '&'][..]) {
+            return Some(value);
+        }
+        return None;
+    }
+    let encoding = prop.params.get("ENCODING").map(|x| x.to_lowercase());
+    if encoding != Some("b".to_owned()) && encoding != Some("base64".to_owned()) || !is_base64(&value) {
+        return None;
+    }
+    let subtype = prop.params.get("TYPE")
+        .map(|x| x.trim_matches('"').to_lowercase())
+        .map(|x| x.trim_left_matches("image/").to_owned())
+        .filter(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-'))
+        .unwrap_or_else(|| "jpeg".to_owned());
+    Some(format!("data:image/{};base64,{}", subtype, value))
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Escape a value for use in a distinguished name, as in RFC 4514.
+fn ldap_dn_value(s: &str) -> String {
+    let mut rv = String::with_capacity(s.len());
+    for (i, c) in s.chars().enumerate() {
+        if ",+\"\\<>;=".contains(c) || (i == 0 && (c == '#' || c == ' ')) {
+            rv.push('\\');
+        }
+        rv.push(c);
+    }
+    if rv.ends_with(' ') {
+        rv.pop();
+        rv.push_str("\\ ");
+    }
+    rv
+}
+
+/// An LDIF attribute line, folded at 76 characters. Values that aren't safe strings as defined
+/// by RFC 2849, e.g. non-ASCII names, are base64-encoded.
+fn ldif_line(attr: &str, value: &str) -> String {
+    let safe = value.bytes().all(|b| b > 0 && b < 0x80 && b != b'\n' && b != b'\r')
+        && !value.starts_with(&[' ', ':', '<'][..])
+        && !value.ends_with(' ');
+    let line = if safe {
+        format!("{}: {}", attr, value)
+    } else {
+        format!("{}:: {}", attr, base64::encode(value.as_bytes()))
+    };
+    // Only base64 values and ASCII safe strings are folded, so byte offsets are char boundaries.
+    let mut rv = String::with_capacity(line.len() + 2);
+    let mut rest = &line[..];
+    let mut width = 76;
+    while rest.len() > width {
+        rv.push_str(&rest[..width]);
+        rv.push_str("\n ");
+        rest = &rest[width..];
+        width = 75;
+    }
+    rv.push_str(rest);
+    rv.push('\n');
+    rv
+}
+
+/// Turn a nickname or name into an alias key: lowercase, without diacritics, words joined with
+/// `-` and only letters, digits, `-`, `_` and `.` kept.
+fn alias_key(s: &str) -> String {
+    let folded = fold_diacritics(s);
+    let words: Vec<String> = folded
+        .split_whitespace()
+        .map(|w| w.chars().filter(|c| c.is_ascii_alphanumeric() || "-_.".contains(*c)).collect::<String>())
+        .filter(|w| !w.is_empty())
+        .collect();
+    words.join("-")
+}
+
+/// The alias key of a contact, from its first `NICKNAME` or its name.
+fn contact_alias_key(comp: &Component) -> String {
+    let nickname = comp.get_all("NICKNAME").iter()
+        .flat_map(|x| split_list_value(&x.raw_value))
+        .next();
+    let name = comp.get_only("FN").map(|x| x.value_as_string()).unwrap_or_default();
+    match alias_key(&nickname.unwrap_or(name)) {
+        ref x if x.is_empty() => "contact".to_owned(),
+        x => x
+    }
+}
+
+/// `base`, or if that's taken, `base` with the lowest free number appended, e.g. `alice-2`.
+fn unique_key(used: &mut HashSet<String>, base: &str) -> String {
+    let mut key = base.to_owned();
+    let mut i = 1;
+    while used.contains(&key) {
+        i += 1;
+        key = format!("{}-{}", base, i);
+    }
+    used.insert(key.clone());
+    key
+}
+
+/// Write an `alias` line for every email address of the given contacts, as in mutt's alias file.
+/// Keys come from the first `NICKNAME`, or the name. Further addresses of a contact, and contacts
+/// whose key is taken, get a number appended, e.g. `alice-2`.
+pub fn to_mutt_aliases(contacts: &[Contact]) -> String {
+    let mut rv = String::new();
+    let mut used = HashSet::new();
+    for contact in contacts {
+        let comp = &contact.component;
+        let name = comp.get_only("FN").map(|x| x.value_as_string()).unwrap_or_default();
+        let base = contact_alias_key(comp);
+        for email in comp.get_all("EMAIL") {
+            let key = unique_key(&mut used, &base);
+            rv.push_str(&format!("alias {} {}\n", key, format_address(&name, &email.value_as_string())));
+        }
+    }
+    rv
+}
+
+/// Write the given contacts in the tab-separated `.addressbook` format of (al)pine: a nickname,
+/// the full name as `Last, First` and the addresses, as a parenthesized list if there are several.
+/// Nicknames are made as for mutt aliases. Contacts without email addresses are left out.
+pub fn to_pine_addressbook(contacts: &[Contact]) -> String {
+    let mut rv = String::new();
+    let mut used = HashSet::new();
+    for contact in contacts {
+        let comp = &contact.component;
+        let emails: Vec<String> = comp.get_all("EMAIL").iter().map(|x| x.value_as_string()).collect();
+        if emails.is_empty() {
+            continue;
+        }
+        let fn_name = comp.get_only("FN").map(|x| x.value_as_string()).unwrap_or_default();
+        let raw_n = comp.get_only("N").map(|x| x.raw_value.clone()).unwrap_or_default();
+        let mut n_fields = raw_n.split(';').map(|x| unescape_chars(x.trim()));
+        let name = match (n_fields.next(), n_fields.next()) {
+            (Some(ref family), Some(ref given)) if !family.is_empty() && !given.is_empty() =>
+                format!("{}, {}", family, given),
+            _ => fn_name
+        };
+        let address = if emails.len() == 1 {
+            emails[0].clone()
+        } else {
+            format!("({})", emails.join(","))
+        };
+        let key = unique_key(&mut used, &contact_alias_key(comp));
+        let fields: Vec<String> = [key, name, address].iter().map(|x| x.replace(&['\t', '\r', '\n'][..], " ")).collect();
+        rv.push_str(&fields.join("\t"));
+        rv.push('\n');
+    }
+    rv
+}
@@ -0,0 +1,191 @@
+//! Harvesting contacts from mail: counting the people in the From, To and Cc headers of many
+//! messages, to create contacts for those who are written to or by often.
+
+use std::collections::{HashMap,HashSet};
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::path;
+use std::process;
+
+use utils::{is_valid_email,read_addresses_from_email};
+
+/// An address seen in the harvested messages.
+pub struct Correspondent {
+    /// The name most often given with the address, if any.
+    pub name: Option<String>,
+    pub email: String,
+    /// In how many messages the address was seen.
+    pub count: usize
+}
+
+struct Seen {
+    email: String,
+    names: HashMap<String, usize>,
+    count: usize
+}
+
+/// The addresses of the messages read so far.
+pub struct Harvest {
+    seen: HashMap<String, Seen>,
+    pub messages: usize
+}
+
+impl Harvest {
+    pub fn new() -> Harvest {
+        Harvest { seen: HashMap::new(), messages: 0 }
+    }
+
+    /// Count the addresses in the From, To and Cc headers of a message, given its header. An
+    /// address given several times in one message is counted once.
+    pub fn add_message(&mut self, header: &str) {
+        self.messages += 1;
+        let mut in_message = HashSet::new();
+        for (name, email) in read_addresses_from_email(header, &["from", "to", "cc"]) {
+            let email = email.trim().to_owned();
+            if !is_valid_email(&email) || !in_message.insert(email.to_lowercase()) {
+                continue;
+            }
+            let seen = self.seen.entry(email.to_lowercase())
+                .or_insert_with(|| Seen { email: email.clone(), names: HashMap::new(), count: 0 });
+            seen.count += 1;
+            let name = name.map(|x| x.trim().trim_matches(&['"', '\''][..]).trim().to_owned());
+            if let Some(name) = name {
+                if !name.is_empty() && !name.contains('@') {
+                    *seen.names.entry(name).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    /// The addresses seen in more than `threshold` messages, the most frequent first.
+    pub fn correspondents(self, threshold: usize) -> Vec<Correspondent> {
+        let mut rv: Vec<Correspondent> = self.seen.into_iter()
+            .map(|(_, seen)| seen)
+            .filter(|seen| seen.count > threshold)
+            .map(|seen| {
+                let mut names: Vec<(String, usize)> = seen.names.into_iter().collect();
+                names.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                Correspondent {
+                    name: names.into_iter().next().map(|x| x.0),
+                    email: seen.email,
+                    count: seen.count
+                }
+            })
+            .collect();
+        rv.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.email.to_lowercase().cmp(&b.email.to_lowercase())));
+        rv
+    }
+}
+
+/// The Maildirs to harvest in `root`: the given folders, which may be Maildir++ folders like
+/// `.Sent`, or if there are none, `root` and every folder below it.
+pub fn maildir_folders(root: &path::Path, folders: &[&str]) -> io::Result<Vec<path::PathBuf>> {
+    if !folders.is_empty() {
+        let mut rv = vec![];
+        for folder in folders {
+            match [root.join(folder), root.join(format!(".{}", folder))].iter().find(|x| x.is_dir()) {
+                Some(x) => rv.push(x.clone()),
+                None => return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No folder {} in {}", folder, root.display())
+                ))
+            }
+        }
+        return Ok(rv);
+    }
+
+    let mut rv = vec![];
+    try!(find_maildirs(root, &mut rv));
+    if rv.is_empty() {
+        rv.push(root.to_owned());
+    }
+    rv.sort();
+    Ok(rv)
+}
+
+/// The files of the messages matching a notmuch query, one per message, as found by the notmuch
+/// program.
+pub fn notmuch_files(query: &str) -> io::Result<Vec<path::PathBuf>> {
+    let output = match process::Command::new("notmuch")
+        .args(&["search", "--output=files", "--duplicate=1", "--"])
+        .arg(query)
+        .stderr(process::Stdio::inherit())
+        .output() {
+        Ok(x) => x,
+        Err(e) => return Err(io::Error::new(e.kind(), format!("Couldn't run notmuch: {}", e)))
+    };
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("notmuch search failed: {}", output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines()
+       .filter(|x| !x.is_empty())
+       .map(path::PathBuf::from)
+       .collect())
+}
+
+/// Call `f` with the header of each message in the mbox file at `path`, without reading the whole
+/// file into memory.
+pub fn read_mbox_headers<F: FnMut(&str)>(path: &path::Path, mut f: F) -> io::Result<()> {
+    let mut reader = io::BufReader::new(try!(fs::File::open(path)));
+    let mut line = vec![];
+    let mut header = vec![];
+    let (mut in_header, mut after_blank) = (false, true);
+    loop {
+        line.clear();
+        if try!(reader.read_until(b'\n', &mut line)) == 0 {
+            break;
+        }
+        let blank = line == b"\n" || line == b"\r\n";
+        if after_blank && is_mbox_separator(&line) {
+            if in_header {
+                f(&String::from_utf8_lossy(&header));
+            }
+            header.clear();
+            in_header = true;
+        } else if in_header && blank {
+            f(&String::from_utf8_lossy(&header));
+            in_header = false;
+        } else if in_header {
+            header.extend_from_slice(&line);
+        }
+        after_blank = blank;
+    }
+    if in_header {
+        f(&String::from_utf8_lossy(&header));
+    }
+    Ok(())
+}
+
+/// Whether a line starts a message in an mbox file, like `From alice@example.com Fri Jul  8
+/// 12:08:34 2011`. Lines in message bodies starting with "From " are escaped as ">From " by most
+/// programs, but not all, so the line must also hold a time and a year.
+fn is_mbox_separator(line: &[u8]) -> bool {
+    if !line.starts_with(b"From ") {
+        return false;
+    }
+    let line = String::from_utf8_lossy(line);
+    let words: Vec<&str> = line.split_whitespace().collect();
+    words.len() >= 4 &&
+        words.iter().any(|x| x.len() == 8 && x.matches(':').count() == 2) &&
+        words.iter().any(|x| x.len() == 4 && x.chars().all(|c| c.is_digit(10)))
+}
+
+fn is_maildir(dir: &path::Path) -> bool {
+    dir.join("cur").is_dir() || dir.join("new").is_dir()
+}
+
+fn find_maildirs(dir: &path::Path, rv: &mut Vec<path::PathBuf>) -> io::Result<()> {
+    if is_maildir(dir) {
+        rv.push(dir.to_owned());
+    }
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Symlinks aren't followed, they could lead back up.
+        if try!(entry.file_type()).is_dir() && !["cur", "new", "tmp"].contains(&&name[..]) {
+            try!(find_maildirs(&entry.path(), rv));
+        }
+    }
+    Ok(())
+}
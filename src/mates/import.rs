@@ -0,0 +1,1200 @@
+use std::collections::{HashMap,HashSet};
+use std::io;
+use std::mem;
+use std::path;
+
+use base64;
+use chrono::Utc;
+use uuid::Uuid;
+use vobject::{Component,Property,escape_chars,parse_component};
+
+use export::convert_to_version;
+use vcard21;
+use utils::{Contact,VcardVersion,format_timestamp,join_type_params,list_contact_files,merge_components,parse_address_list,split_list_value,
+            structured_name,write_component_sorted};
+
+/// Split a file containing several VCARD components into one string per component. Lines may end
+/// in CRLF, LF or, as in old macOS exports, CR. Blank lines within a component are dropped, and
+/// lines without a colon, like the base64 data of photos whose exporter didn't indent it, are
+/// taken as continuations of the previous line.
+pub fn split_vcards(input: &str) -> Vec<String> {
+    let mut rv = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+
+    for line in input.split(|c| c == '\n' || c == '\r') {
+        let upper = line.to_uppercase();
+        if upper.starts_with("BEGIN:") {
+            depth += 1;
+        }
+        if depth > 0 && !line.trim().is_empty() {
+            if !line.contains(':') && !current.is_empty() && !line.starts_with(' ') && !line.starts_with('\t') {
+                current.push(' ');
+            }
+            current.push_str(line);
+            current.push_str("\r\n");
+        }
+        if upper.starts_with("END:") && depth > 0 {
+            depth -= 1;
+            if depth == 0 {
+                rv.push(current.split_off(0));
+            }
+        }
+    }
+    rv
+}
+
+/// Whether a UID can be used as filename as-is.
+fn is_safe_filename(uid: &str) -> bool {
+    !uid.is_empty() && !uid.starts_with('.') &&
+        uid.chars().all(|c| c.is_alphanumeric() || "-_.@".contains(c))
+}
+
+/// Result of an import.
+pub struct ImportStats {
+    pub imported: Vec<path::PathBuf>,
+    /// Existing contacts that imported ones were merged into.
+    pub merged: Vec<path::PathBuf>,
+    pub duplicates: usize
+}
+
+/// Write each of the given components into its own file in `dir`, named after its UID and
+/// converted into `version`. Components without UID get a new one, components identical to an
+/// existing contact are skipped. With `merge_by_email`, components sharing an email address with a
+/// contact in `dir` are merged into it instead.
+pub fn import_components(components: Vec<Component>, dir: &path::Path, merge_by_email: bool,
+                         version: VcardVersion) -> io::Result<ImportStats> {
+    let mut existing = HashSet::new();
+    let mut by_email = HashMap::new();
+    for fpath in try!(list_contact_files(dir)) {
+        if let Ok(contact) = Contact::from_file(&fpath) {
+            existing.insert(duplicate_key(&contact.component));
+            for email in contact.component.get_all("EMAIL") {
+                by_email.entry(email.value_as_string().to_lowercase()).or_insert_with(|| fpath.clone());
+            }
+        }
+    }
+
+    let mut stats = ImportStats { imported: vec![], merged: vec![], duplicates: 0 };
+    for mut comp in components {
+        let target = if merge_by_email {
+            comp.get_all("EMAIL").iter()
+                .filter_map(|x| by_email.get(&x.value_as_string().to_lowercase()))
+                .next()
+                .cloned()
+        } else {
+            None
+        };
+        if let Some(fpath) = target {
+            let mut contact = try!(Contact::from_file(&fpath));
+            convert_to_version(&mut comp, VcardVersion::of(&contact.component));
+            let before = write_component_sorted(&contact.component);
+            merge_new_properties(&mut contact.component, &comp);
+            if write_component_sorted(&contact.component) == before {
+                stats.duplicates += 1;
+            } else {
+                contact.component.set(Property::new("REV", &format_timestamp(&Utc::now())[..]));
+                try!(contact.write_replace());
+                for email in contact.component.get_all("EMAIL") {
+                    by_email.entry(email.value_as_string().to_lowercase()).or_insert_with(|| fpath.clone());
+                }
+                if !stats.merged.contains(&fpath) {
+                    stats.merged.push(fpath);
+                }
+            }
+            continue;
+        }
+
+        if comp.get_only("UID").is_none() {
+            comp.set(Property::new("UID", &Uuid::new_v4().hyphenated().to_string()[..]));
+        }
+        convert_to_version(&mut comp, version);
+        let serialized = duplicate_key(&comp);
+        if existing.contains(&serialized) {
+            stats.duplicates += 1;
+            continue;
+        }
+
+        let uid = comp.get_only("UID").map(|x| x.value_as_string()).unwrap_or_else(String::new);
+        let mut fpath = dir.join(&format!("{}.vcf", uid));
+        if !is_safe_filename(&uid) || fpath.exists() {
+            fpath = Contact::new_uid_path(dir).1;
+        }
+
+        let contact = Contact { component: comp, path: fpath };
+        try!(contact.write_create());
+        existing.insert(serialized);
+        for email in contact.component.get_all("EMAIL") {
+            by_email.entry(email.value_as_string().to_lowercase()).or_insert_with(|| contact.path.clone());
+        }
+        stats.imported.push(contact.path);
+    }
+    Ok(stats)
+}
+
+/// What a component is compared by to find duplicates: its serialization as vCard 4.0, so that
+/// contacts written in another version before are still recognized. UID and REV are left out,
+/// since imported components without UID get a new one each time.
+fn duplicate_key(comp: &Component) -> String {
+    let mut comp = comp.clone();
+    comp.props.remove("UID");
+    comp.props.remove("REV");
+    convert_to_version(&mut comp, VcardVersion::V4);
+    write_component_sorted(&comp)
+}
+
+/// Merge `other` into `comp` like `merge_components`, but leave out email addresses and phone
+/// numbers `comp` already has, even if they have other types.
+fn merge_new_properties(comp: &mut Component, other: &Component) {
+    let key = |name: &str, value: &str| -> String {
+        match name {
+            "EMAIL" => value.trim().to_lowercase(),
+            _ => value.chars().filter(|c| c.is_digit(10) || *c == '+').collect()
+        }
+    };
+    let mut other = other.clone();
+    for name in &["EMAIL", "TEL"] {
+        let known: HashSet<String> = comp.get_all(name).iter().map(|x| key(name, &x.value_as_string())).collect();
+        if let Some(props) = other.props.get_mut(*name) {
+            props.retain(|x| !known.contains(&key(name, &x.value_as_string())));
+        }
+    }
+    merge_components(comp, &other);
+}
+
+/// Parse a multi-VCARD file as exported by phones and other address books.
+pub fn parse_vcf(input: &str) -> (Vec<Component>, Vec<String>) {
+    let mut components = vec![];
+    let mut errors = vec![];
+    for (i, chunk) in split_vcards(input).into_iter().enumerate() {
+        match parse_component(&join_type_params(&chunk)) {
+            Ok(mut x) => {
+                strip_base64_whitespace(&mut x);
+                components.push(x)
+            },
+            Err(e) => errors.push(format!("Error while parsing contact #{}: {}", i + 1, e))
+        };
+    }
+    (components, errors)
+}
+
+/// Remove the whitespace left in base64 values, such as photos, by exporters indenting their
+/// continuation lines with more than one space.
+fn strip_base64_whitespace(comp: &mut Component) {
+    for props in comp.props.values_mut() {
+        for prop in props.iter_mut() {
+            let encoding = prop.params.get("ENCODING").map(|x| x.to_lowercase());
+            if encoding == Some("b".to_owned()) || encoding == Some("base64".to_owned()) {
+                prop.raw_value = prop.raw_value.chars().filter(|c| !c.is_whitespace()).collect();
+            }
+        }
+    }
+}
+
+/// A format `mates import` reads.
+pub enum ImportFormat {
+    /// Files with one or several vCards.
+    Vcf,
+    /// CSV with a header line, whose columns are mapped to vCard properties.
+    Csv(CsvMapping),
+    /// LDIF, as exported by Thunderbird and LDAP directories.
+    Ldif,
+    /// The CSV or vCard export of Google Contacts, e.g. from Google Takeout.
+    Google,
+    /// abook's addressbook file.
+    Abook,
+    /// A file of mutt `alias` commands.
+    MuttAlias
+}
+
+impl ImportFormat {
+    /// The format named by `--format`, with the column mapping given with `--map` for CSV.
+    pub fn parse(name: &str, map: Option<&str>) -> Result<ImportFormat, String> {
+        match (name, map) {
+            ("vcf", None) => Ok(ImportFormat::Vcf),
+            ("csv", Some(x)) => CsvMapping::parse(x).map(ImportFormat::Csv),
+            ("csv", None) => Ok(ImportFormat::Csv(CsvMapping { columns: vec![], outlook: false })),
+            ("outlook-csv", None) => Ok(ImportFormat::Csv(CsvMapping::outlook())),
+            ("ldif", None) => Ok(ImportFormat::Ldif),
+            ("google", None) => Ok(ImportFormat::Google),
+            ("abook", None) => Ok(ImportFormat::Abook),
+            ("mutt-alias", None) => Ok(ImportFormat::MuttAlias),
+            (_, Some(_)) if ["vcf", "ldif", "google", "abook", "mutt-alias", "outlook-csv"].contains(&name) =>
+                Err("--map only applies to --format csv.".to_owned()),
+            _ => Err(format!("Unknown import format: {}", name))
+        }
+    }
+
+    /// Whether contacts sharing an email address with existing ones are merged into them instead
+    /// of being imported as new contacts.
+    pub fn merges_by_email(&self) -> bool {
+        match *self {
+            ImportFormat::Google => true,
+            _ => false
+        }
+    }
+}
+
+/// Parse the input of `mates import` into contacts, and messages about the entries that were skipped.
+pub fn parse_input(input: &str, format: &ImportFormat) -> Result<(Vec<Component>, Vec<String>), String> {
+    match *format {
+        ImportFormat::Vcf => Ok(parse_vcf(input)),
+        ImportFormat::Csv(ref mapping) => parse_csv_contacts(input, mapping),
+        ImportFormat::Ldif => Ok(parse_ldif(input)),
+        ImportFormat::Google if input.trim_left_matches('\u{feff}').trim_left().to_uppercase().starts_with("BEGIN:VCARD") =>
+            Ok(parse_google_vcf(input)),
+        ImportFormat::Google => Ok(parse_google_csv(input)),
+        ImportFormat::Abook => Ok(parse_abook(input)),
+        ImportFormat::MuttAlias => Ok(parse_mutt_aliases(input))
+    }
+}
+
+/// Decode the contents of a file in `format` as text. vCard files may be vCard 2.1 in another
+/// charset than UTF-8, and are converted into vCard 3.0.
+pub fn decode_input(bytes: Vec<u8>, format: &ImportFormat) -> Result<String, String> {
+    match *format {
+        ImportFormat::Vcf | ImportFormat::Google => Ok(vcard21::decode_vcards(&bytes)),
+        _ => String::from_utf8(bytes).map_err(|_| "The file isn't valid UTF-8.".to_owned())
+    }
+}
+
+/// What a CSV column is imported as.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CsvField {
+    Name,
+    Given,
+    Family,
+    Email,
+    Phone,
+    Org,
+    Title,
+    Nickname,
+    Note,
+    Birthday,
+    Url,
+    Categories,
+    Uid,
+    Street,
+    City,
+    Region,
+    Postcode,
+    Country
+}
+
+impl CsvField {
+    fn parse(s: &str) -> Option<CsvField> {
+        Some(match &s.to_lowercase()[..] {
+            "name" => CsvField::Name,
+            "given" => CsvField::Given,
+            "family" => CsvField::Family,
+            "email" => CsvField::Email,
+            "phone" => CsvField::Phone,
+            "org" => CsvField::Org,
+            "title" => CsvField::Title,
+            "nickname" => CsvField::Nickname,
+            "note" => CsvField::Note,
+            "birthday" => CsvField::Birthday,
+            "url" => CsvField::Url,
+            "categories" => CsvField::Categories,
+            "uid" => CsvField::Uid,
+            "street" => CsvField::Street,
+            "city" => CsvField::City,
+            "region" => CsvField::Region,
+            "postcode" => CsvField::Postcode,
+            "country" => CsvField::Country,
+            _ => return None
+        })
+    }
+
+    /// The part of `ADR` the field is, if it's part of an address.
+    fn address_part(&self) -> Option<usize> {
+        match *self {
+            CsvField::Street => Some(0),
+            CsvField::City => Some(1),
+            CsvField::Region => Some(2),
+            CsvField::Postcode => Some(3),
+            CsvField::Country => Some(4),
+            _ => None
+        }
+    }
+
+    /// Whether the field can be given a type, like `phone.cell`.
+    fn takes_type(&self) -> bool {
+        *self == CsvField::Email || *self == CsvField::Phone || self.address_part().is_some()
+    }
+
+    /// The vCard property the field is written to. Names and addresses are handled separately.
+    fn property(&self) -> &'static str {
+        match *self {
+            CsvField::Name => "FN",
+            CsvField::Given | CsvField::Family => "N",
+            CsvField::Email => "EMAIL",
+            CsvField::Phone => "TEL",
+            CsvField::Org => "ORG",
+            CsvField::Title => "TITLE",
+            CsvField::Nickname => "NICKNAME",
+            CsvField::Note => "NOTE",
+            CsvField::Birthday => "BDAY",
+            CsvField::Url => "URL",
+            CsvField::Categories => "CATEGORIES",
+            CsvField::Uid => "UID",
+            CsvField::Street | CsvField::City | CsvField::Region | CsvField::Postcode | CsvField::Country => "ADR"
+        }
+    }
+}
+
+/// Which CSV columns become which vCard properties, as given to `--map`.
+pub struct CsvMapping {
+    /// The field, the `TYPE` of addresses and phone numbers, and the column's header.
+    columns: Vec<(CsvField, Option<String>, String)>,
+    /// Whether this is the mapping for Outlook's export. Its columns may be missing, and its dates
+    /// are written like 5/12/1985.
+    outlook: bool
+}
+
+impl CsvMapping {
+    /// Parse a mapping like `name=Full Name,email=E-mail,phone.cell=Mobile`. A field may be given
+    /// several times, e.g. for work and home addresses in different columns.
+    pub fn parse(s: &str) -> Result<CsvMapping, String> {
+        let mut columns = vec![];
+        for entry in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let (key, column) = match entry.find('=') {
+                Some(i) => (entry[..i].trim(), entry[i + 1..].trim()),
+                None => return Err(format!("Invalid mapping {:?}, must be FIELD=COLUMN.", entry))
+            };
+            let (name, types) = match key.find('.') {
+                Some(i) => (&key[..i], Some(key[i + 1..].to_uppercase())),
+                None => (key, None)
+            };
+            let field = match CsvField::parse(name) {
+                Some(x) => x,
+                None => return Err(format!(
+                    "Invalid field: {}, must be name, given, family, email, phone, org, title, nickname, \
+                     note, birthday, url, categories, uid, street, city, region, postcode or country.", name))
+            };
+            if types.is_some() && !field.takes_type() {
+                return Err(format!("Only email, phone and address fields take a type, not {}.", name));
+            }
+            columns.push((field, types, column.to_owned()));
+        }
+        if columns.is_empty() {
+            return Err("The mapping is empty.".to_owned());
+        }
+        Ok(CsvMapping { columns: columns, outlook: false })
+    }
+
+    /// The mapping for the columns of Outlook's CSV export.
+    pub fn outlook() -> CsvMapping {
+        let mut columns = vec![];
+        {
+            let mut map = |field: CsvField, types: Option<&str>, column: &str| {
+                columns.push((field, types.map(|x| x.to_owned()), column.to_owned()));
+            };
+            map(CsvField::Given, None, "First Name");
+            map(CsvField::Family, None, "Last Name");
+            map(CsvField::Nickname, None, "Nickname");
+            map(CsvField::Org, None, "Company");
+            map(CsvField::Title, None, "Job Title");
+            for column in &["E-mail Address", "E-mail 2 Address", "E-mail 3 Address"] {
+                map(CsvField::Email, None, column);
+            }
+            for &(types, column) in &[("WORK", "Business Phone"), ("WORK", "Business Phone 2"),
+                                      ("HOME", "Home Phone"), ("HOME", "Home Phone 2"), ("CELL", "Mobile Phone"),
+                                      ("FAX,WORK", "Business Fax"), ("FAX,HOME", "Home Fax"), ("PAGER", "Pager")] {
+                map(CsvField::Phone, Some(types), column);
+            }
+            map(CsvField::Phone, None, "Other Phone");
+            for &(types, prefix) in &[("WORK", "Business"), ("HOME", "Home"), ("", "Other")] {
+                let types = if types.is_empty() { None } else { Some(types) };
+                map(CsvField::Street, types, &format!("{} Street", prefix));
+                map(CsvField::City, types, &format!("{} City", prefix));
+                map(CsvField::Region, types, &format!("{} State", prefix));
+                map(CsvField::Postcode, types, &format!("{} Postal Code", prefix));
+                map(CsvField::Country, types, &format!("{} Country/Region", prefix));
+            }
+            map(CsvField::Birthday, None, "Birthday");
+            map(CsvField::Note, None, "Notes");
+            map(CsvField::Url, None, "Web Page");
+            map(CsvField::Categories, None, "Categories");
+        }
+        CsvMapping { columns: columns, outlook: true }
+    }
+}
+
+/// Convert a date written like 5/12/1985 into `1985-05-12`. Outlook writes `0/0/00` or nothing for
+/// no date.
+fn outlook_date(s: &str) -> Option<String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    let parts: Vec<u32> = s.split('/').filter_map(|x| x.trim().parse().ok()).collect();
+    match parts[..] {
+        [month, day, year] if month > 0 && day > 0 && year >= 1000 => Some(format!("{:04}-{:02}-{:02}", year, month, day)),
+        [_, _, _] => None,
+        _ => Some(s.to_owned())
+    }
+}
+
+/// Split CSV text into rows of fields. Fields may be quoted to contain separators, line breaks and
+/// doubled quotes. Blank lines are kept as rows with a single empty field. The separator is a
+/// semicolon if the first line holds more of them than commas, as in spreadsheets exported with
+/// some locales, and a comma otherwise.
+pub fn parse_csv(input: &str) -> Vec<Vec<String>> {
+    let input = input.trim_left_matches('\u{feff}');
+    let first_line = input.lines().next().unwrap_or("");
+    let sep = if first_line.matches(';').count() > first_line.matches(',').count() { ';' } else { ',' };
+
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            },
+            '"' if field.is_empty() => quoted = true,
+            '\r' if !quoted => (),
+            '\n' if !quoted => {
+                row.push(field.split_off(0));
+                rows.push(mem::replace(&mut row, vec![]));
+            },
+            c if c == sep && !quoted => row.push(field.split_off(0)),
+            c => field.push(c)
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+fn is_blank_row(row: &[String]) -> bool {
+    row.iter().all(|x| x.trim().is_empty())
+}
+
+/// Convert the rows of a CSV file into contacts, using `mapping` or, if it's empty, the columns
+/// whose headers are names of fields. Rows without a name are skipped.
+fn parse_csv_contacts(input: &str, mapping: &CsvMapping) -> Result<(Vec<Component>, Vec<String>), String> {
+    let mut rows = parse_csv(input).into_iter();
+    let header: Vec<String> = match rows.next() {
+        Some(x) => x.into_iter().map(|x| x.trim().to_owned()).collect(),
+        None => return Ok((vec![], vec![]))
+    };
+
+    let mut columns = vec![];
+    if mapping.columns.is_empty() {
+        for (i, name) in header.iter().enumerate() {
+            if let Some(field) = CsvField::parse(name) {
+                columns.push((field, None, i));
+            }
+        }
+        if columns.is_empty() {
+            return Err("No column is named after a field, use --map to choose the columns.".to_owned());
+        }
+    } else {
+        for &(field, ref types, ref column) in mapping.columns.iter() {
+            match header.iter().position(|x| x.to_lowercase() == column.to_lowercase()) {
+                Some(i) => columns.push((field, types.clone(), i)),
+                None if mapping.outlook => (),
+                None => return Err(format!("No such column: {}", column))
+            }
+        }
+        if columns.is_empty() {
+            return Err("None of the columns of Outlook's export were found.".to_owned());
+        }
+    }
+
+    let mut components = vec![];
+    let mut skipped = vec![];
+    for (i, row) in rows.enumerate().filter(|x| !is_blank_row(&x.1)) {
+        let values: Vec<(CsvField, String, &Option<String>)> = columns.iter()
+            .filter_map(|&(field, ref types, column)| match row.get(column).map(|x| x.trim()) {
+                // Outlook writes the internal addresses of Exchange users like
+                // /o=ExchangeLabs/ou=..., which can't be mailed to from elsewhere.
+                Some(x) if mapping.outlook && field == CsvField::Email && !x.contains('@') => None,
+                Some(x) if mapping.outlook && field == CsvField::Birthday => outlook_date(x).map(|x| (field, x, types)),
+                Some(x) if !x.is_empty() => Some((field, x.to_owned(), types)),
+                _ => None
+            })
+            .collect();
+        let get = |field: CsvField| values.iter().find(|x| x.0 == field).map(|x| x.1.clone());
+
+        let given = get(CsvField::Given).unwrap_or_default();
+        let family = get(CsvField::Family).unwrap_or_default();
+        let name = match get(CsvField::Name) {
+            Some(x) => x,
+            None => format!("{} {}", given, family).trim().to_owned()
+        };
+        if name.is_empty() {
+            // Line numbers count the header.
+            skipped.push(format!("Skipped row {}: no name.", i + 2));
+            continue;
+        }
+
+        let mut comp = Component::new("VCARD");
+        comp.push(Property::new("VERSION", "3.0"));
+        comp.push(Property::new("FN", &name[..]));
+        let mut n = Property::new("N", "");
+        n.raw_value = if given.is_empty() && family.is_empty() {
+            structured_name(&name)
+        } else {
+            format!("{};{};;;", escape_chars(&family), escape_chars(&given))
+        };
+        comp.push(n);
+        let mut addresses: Vec<(&Option<String>, Vec<String>)> = vec![];
+        for &(field, ref value, types) in values.iter() {
+            if let Some(part) = field.address_part() {
+                let i = match addresses.iter().position(|x| x.0 == types) {
+                    Some(i) => i,
+                    None => {
+                        addresses.push((types, vec![String::new(); 5]));
+                        addresses.len() - 1
+                    }
+                };
+                addresses[i].1[part] = escape_chars(value);
+                continue;
+            }
+            match field {
+                CsvField::Name | CsvField::Given | CsvField::Family => continue,
+                CsvField::Uid | CsvField::Birthday | CsvField::Org | CsvField::Title
+                    if comp.get_only(field.property()).is_some() => continue,
+                _ => ()
+            }
+            let mut prop = Property::new(field.property(), &value[..]);
+            if field == CsvField::Categories {
+                let categories: Vec<String> = value.split(|c| c == ',' || c == ';')
+                    .map(|x| escape_chars(x.trim()))
+                    .filter(|x| !x.is_empty())
+                    .collect();
+                prop.raw_value = categories.join(",");
+            }
+            if let Some(ref x) = *types {
+                prop.params.insert("TYPE".to_owned(), x.clone());
+            }
+            comp.push(prop);
+        }
+        for (types, parts) in addresses {
+            let mut prop = Property::new("ADR", "");
+            prop.raw_value = format!(";;{}", parts.join(";"));
+            if let Some(ref x) = *types {
+                prop.params.insert("TYPE".to_owned(), x.clone());
+            }
+            comp.push(prop);
+        }
+        components.push(comp);
+    }
+    Ok((components, skipped))
+}
+
+/// Split LDIF text into entries, each with the number of its first line and its attributes, whose
+/// names are lowercased and stripped of options like `;lang-de`. Folded lines are joined and
+/// base64 values decoded. Values referring to URLs, like `jpegPhoto:< file:///...`, are left out.
+fn parse_ldif_entries(input: &str) -> Vec<(usize, Result<Vec<(String, String)>, String>)> {
+    // Join folded lines first, keeping the number of the line each one starts on.
+    let mut lines: Vec<(usize, String)> = vec![];
+    for (i, line) in input.trim_left_matches('\u{feff}').lines().enumerate() {
+        let line = line.trim_right_matches('\r');
+        match lines.last_mut() {
+            Some(&mut (_, ref mut last)) if line.starts_with(' ') && !last.is_empty() => {
+                last.push_str(&line[1..]);
+                continue;
+            },
+            _ => ()
+        }
+        lines.push((i + 1, line.to_owned()));
+    }
+
+    let mut entries = vec![];
+    let mut current: Option<(usize, Result<Vec<(String, String)>, String>)> = None;
+    for (lineno, line) in lines {
+        if line.trim().is_empty() {
+            entries.extend(current.take());
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let entry = current.get_or_insert_with(|| (lineno, Ok(vec![])));
+        let attrs = match entry.1 {
+            Ok(ref mut x) => x,
+            Err(_) => continue
+        };
+        let i = match line.find(':') {
+            Some(i) => i,
+            None => {
+                entry.1 = Err(format!("invalid line {}.", lineno));
+                continue;
+            }
+        };
+        let name = line[..i].split(';').next().unwrap_or("").trim().to_lowercase();
+        let rest = &line[i + 1..];
+        let value = if rest.starts_with(':') {
+            match base64::decode(rest[1..].trim()).ok().and_then(|x| String::from_utf8(x).ok()) {
+                Some(x) => x,
+                None => {
+                    entry.1 = Err(format!("invalid base64 value on line {}.", lineno));
+                    continue;
+                }
+            }
+        } else if rest.starts_with('<') {
+            continue;
+        } else {
+            rest.trim_left_matches(' ').to_owned()
+        };
+        attrs.push((name, value));
+    }
+    entries.extend(current);
+    entries
+}
+
+/// Convert LDIF entries into contacts: `cn` becomes `FN`, `sn` and `givenName` `N`, `mail` `EMAIL`,
+/// `telephoneNumber`, `mobile`, `homePhone`, `facsimileTelephoneNumber` and `pager` `TEL`, `o` and
+/// `ou` `ORG`, `title` `TITLE` and `description` `NOTE`, along with some attributes specific to
+/// Thunderbird. Groups and entries without a name are skipped.
+pub fn parse_ldif(input: &str) -> (Vec<Component>, Vec<String>) {
+    let mut components = vec![];
+    let mut skipped = vec![];
+    for (lineno, entry) in parse_ldif_entries(input) {
+        let attrs = match entry {
+            Ok(x) => x,
+            Err(e) => {
+                skipped.push(format!("Skipped entry at line {}: {}", lineno, e));
+                continue;
+            }
+        };
+        if !attrs.iter().any(|x| x.0 == "dn") {
+            // E.g. just the `version: 1` line.
+            continue;
+        }
+        let get = |name: &str| attrs.iter().find(|x| x.0 == name).map(|x| x.1.trim().to_owned())
+            .unwrap_or_default();
+        if attrs.iter().any(|x| x.0 == "objectclass" && x.1.to_lowercase() == "groupofnames") {
+            skipped.push(format!("Skipped entry at line {}: {} is a group.", lineno, get("cn")));
+            continue;
+        }
+
+        let (given, family) = (get("givenname"), get("sn"));
+        let name = match get("cn") {
+            ref x if x.is_empty() => format!("{} {}", given, family).trim().to_owned(),
+            x => x
+        };
+        if name.is_empty() {
+            skipped.push(format!("Skipped entry at line {}: no name.", lineno));
+            continue;
+        }
+
+        let mut comp = Component::new("VCARD");
+        comp.push(Property::new("VERSION", "3.0"));
+        comp.push(Property::new("FN", &name[..]));
+        let mut n = Property::new("N", "");
+        n.raw_value = if given.is_empty() && family.is_empty() {
+            structured_name(&name)
+        } else {
+            format!("{};{};;;", escape_chars(&family), escape_chars(&given))
+        };
+        comp.push(n);
+
+        let mut org = vec![];
+        for &(ref attr, ref value) in attrs.iter() {
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            let (prop_name, types) = match &attr[..] {
+                "mail" | "mozillasecondemail" => ("EMAIL", None),
+                "telephonenumber" => ("TEL", None),
+                "homephone" => ("TEL", Some("HOME")),
+                "mobile" => ("TEL", Some("CELL")),
+                "facsimiletelephonenumber" => ("TEL", Some("FAX")),
+                "pager" => ("TEL", Some("PAGER")),
+                "title" => ("TITLE", None),
+                "description" => ("NOTE", None),
+                "mozillanickname" => ("NICKNAME", None),
+                "labeleduri" | "mozillaworkurl" | "mozillahomeurl" => ("URL", None),
+                "o" => {
+                    org.insert(0, escape_chars(value));
+                    continue;
+                },
+                "ou" => {
+                    org.push(escape_chars(value));
+                    continue;
+                },
+                _ => continue
+            };
+            let mut prop = Property::new(prop_name, value);
+            if let Some(x) = types {
+                prop.params.insert("TYPE".to_owned(), x.to_owned());
+            }
+            comp.push(prop);
+        }
+        if !org.is_empty() {
+            let mut prop = Property::new("ORG", "");
+            prop.raw_value = org.join(";");
+            comp.push(prop);
+        }
+        components.push(comp);
+    }
+    (components, skipped)
+}
+
+/// The vCard `TYPE` of a Google Contacts label like `Work`, `* Mobile` or Apple's
+/// `_$!<HomeFax>!$_`. A leading `*` marks the preferred value. Custom labels have no type.
+fn google_label_type(label: &str) -> Option<String> {
+    let label = label.trim();
+    let (pref, label) = if label.starts_with('*') { (true, label[1..].trim()) } else { (false, label) };
+    let label = label.trim_left_matches("_$!<").trim_right_matches(">!$_");
+    let mut types: Vec<&str> = match &label.to_lowercase().replace(' ', "")[..] {
+        "home" => vec!["HOME"],
+        "work" => vec!["WORK"],
+        "mobile" | "cell" => vec!["CELL"],
+        "homefax" => vec!["FAX", "HOME"],
+        "workfax" => vec!["FAX", "WORK"],
+        "fax" | "otherfax" => vec!["FAX"],
+        "pager" => vec!["PAGER"],
+        "main" | "other" | "" => vec![],
+        _ => return if pref { Some("PREF".to_owned()) } else { None }
+    };
+    if pref {
+        types.push("PREF");
+    }
+    if types.is_empty() { None } else { Some(types.join(",")) }
+}
+
+/// Parse the vCards exported by Google Contacts. Labels given in `X-ABLABEL` properties of the same
+/// group become types of the labelled addresses and phone numbers where possible, and the
+/// `myContacts` category every contact is in is left out.
+pub fn parse_google_vcf(input: &str) -> (Vec<Component>, Vec<String>) {
+    let (mut components, errors) = parse_vcf(input);
+    for comp in components.iter_mut() {
+        let labels: Vec<(String, String)> = comp.get_all("X-ABLABEL").iter()
+            .filter_map(|x| x.prop_group.clone().map(|group| (group, x.value_as_string())))
+            .collect();
+        let mut used_groups = HashSet::new();
+        for props in comp.props.values_mut() {
+            for prop in props.iter_mut() {
+                let label = match prop.prop_group {
+                    Some(ref group) if prop.name != "X-ABLABEL" => labels.iter().find(|x| x.0 == *group).map(|x| x.1.clone()),
+                    _ => None
+                };
+                if let Some(types) = label.and_then(|x| google_label_type(&x)) {
+                    let types = match prop.params.get("TYPE") {
+                        Some(x) => format!("{},{}", x, types),
+                        None => types
+                    };
+                    prop.params.insert("TYPE".to_owned(), types);
+                    used_groups.extend(prop.prop_group.take());
+                }
+            }
+        }
+        if let Some(props) = comp.props.get_mut("X-ABLABEL") {
+            props.retain(|x| x.prop_group.as_ref().map_or(true, |g| !used_groups.contains(g)));
+        }
+        if comp.get_all("X-ABLABEL").is_empty() {
+            comp.props.remove("X-ABLABEL");
+        }
+
+        let categories: Vec<String> = comp.get_all("CATEGORIES").iter()
+            .flat_map(|x| split_list_value(&x.raw_value))
+            .filter(|x| x != "myContacts")
+            .map(|x| escape_chars(&x))
+            .collect();
+        comp.props.remove("CATEGORIES");
+        if !categories.is_empty() {
+            let mut prop = Property::new("CATEGORIES", "");
+            prop.raw_value = categories.join(",");
+            comp.push(prop);
+        }
+    }
+    (components, errors)
+}
+
+/// Parse the CSV exported by Google Contacts, in its current format with columns like `First Name`
+/// and `E-mail 1 - Label` or the older one with `Given Name` and `E-mail 1 - Type`. Labels become
+/// types, and labels starting with `*`, like `* myContacts`, are left out of the categories.
+pub fn parse_google_csv(input: &str) -> (Vec<Component>, Vec<String>) {
+    let mut rows = parse_csv(input).into_iter();
+    let header: Vec<String> = match rows.next() {
+        Some(x) => x.into_iter().map(|x| x.trim().to_owned()).collect(),
+        None => return (vec![], vec![])
+    };
+
+    let mut components = vec![];
+    let mut skipped = vec![];
+    for (i, row) in rows.enumerate().filter(|x| !is_blank_row(&x.1)) {
+        let get = |names: &[&str]| -> String {
+            for name in names {
+                if let Some(x) = header.iter().position(|x| x == name).and_then(|x| row.get(x)) {
+                    if !x.trim().is_empty() {
+                        return x.trim().to_owned();
+                    }
+                }
+            }
+            String::new()
+        };
+        // Values of several addresses or labels sharing a column are separated by " ::: ".
+        let split = |s: String| -> Vec<String> {
+            s.split(" ::: ").map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect()
+        };
+
+        let given = get(&["First Name", "Given Name"]);
+        let additional = get(&["Middle Name", "Additional Name"]);
+        let family = get(&["Last Name", "Family Name"]);
+        let mut name = get(&["Name"]);
+        if name.is_empty() {
+            let parts: Vec<&str> = [&given[..], &additional[..], &family[..]].iter().cloned()
+                .filter(|x| !x.is_empty()).collect();
+            name = parts.join(" ");
+        }
+        let org_name = get(&["Organization Name", "Organization 1 - Name"]);
+
+        let mut comp = Component::new("VCARD");
+        comp.push(Property::new("VERSION", "3.0"));
+        for &(prop_name, column) in &[("NICKNAME", "Nickname"), ("BDAY", "Birthday"), ("NOTE", "Notes")] {
+            let value = get(&[column]);
+            if !value.is_empty() {
+                comp.push(Property::new(prop_name, &value[..]));
+            }
+        }
+        let title = get(&["Organization Title", "Organization 1 - Title"]);
+        if !title.is_empty() {
+            comp.push(Property::new("TITLE", &title[..]));
+        }
+        let department = get(&["Organization Department", "Organization 1 - Department"]);
+        if !org_name.is_empty() || !department.is_empty() {
+            let mut prop = Property::new("ORG", "");
+            prop.raw_value = format!("{};{}", escape_chars(&org_name), escape_chars(&department));
+            prop.raw_value = prop.raw_value.trim_right_matches(';').to_owned();
+            comp.push(prop);
+        }
+
+        // Numbered columns, e.g. "E-mail 1 - Label" and "E-mail 1 - Value".
+        for &(prefix, prop_name) in &[("E-mail", "EMAIL"), ("Phone", "TEL"), ("Website", "URL")] {
+            for n in 1.. {
+                let value_column = format!("{} {} - Value", prefix, n);
+                if !header.contains(&value_column) {
+                    break;
+                }
+                let label = get(&[&format!("{} {} - Label", prefix, n)[..], &format!("{} {} - Type", prefix, n)[..]]);
+                for value in split(get(&[&value_column[..]])) {
+                    let mut prop = Property::new(prop_name, &value[..]);
+                    if let Some(types) = google_label_type(&label) {
+                        prop.params.insert("TYPE".to_owned(), types);
+                    }
+                    comp.push(prop);
+                }
+            }
+        }
+        for n in 1.. {
+            let column = |part: &str| format!("Address {} - {}", n, part);
+            if !header.contains(&column("Street")) {
+                break;
+            }
+            let parts: Vec<String> = ["PO Box", "Extended Address", "Street", "City", "Region", "Postal Code", "Country"]
+                .iter().map(|x| escape_chars(&get(&[&column(x)[..]]))).collect();
+            if parts.iter().all(|x| x.is_empty()) {
+                continue;
+            }
+            let mut prop = Property::new("ADR", "");
+            prop.raw_value = parts.join(";");
+            if let Some(types) = google_label_type(&get(&[&column("Label")[..], &column("Type")[..]])) {
+                prop.params.insert("TYPE".to_owned(), types);
+            }
+            comp.push(prop);
+        }
+
+        let categories: Vec<String> = split(get(&["Labels", "Group Membership"])).into_iter()
+            .filter(|x| !x.starts_with('*'))
+            .map(|x| escape_chars(&x))
+            .collect();
+        if !categories.is_empty() {
+            let mut prop = Property::new("CATEGORIES", "");
+            prop.raw_value = categories.join(",");
+            comp.push(prop);
+        }
+
+        // Entries for companies or bare addresses are named after them, with an empty N.
+        let person = !name.is_empty();
+        if name.is_empty() {
+            name = org_name;
+        }
+        if name.is_empty() {
+            name = comp.get_all("EMAIL").first().map(|x| x.value_as_string()).unwrap_or_default();
+        }
+        if name.is_empty() {
+            skipped.push(format!("Skipped row {}: no name or email.", i + 2));
+            continue;
+        }
+        comp.push(Property::new("FN", &name[..]));
+        let mut n = Property::new("N", "");
+        n.raw_value = if !person {
+            ";;;;".to_owned()
+        } else if given.is_empty() && family.is_empty() {
+            structured_name(&name)
+        } else {
+            format!("{};{};{};;", escape_chars(&family), escape_chars(&given), escape_chars(&additional))
+        };
+        comp.push(n);
+        components.push(comp);
+    }
+    (components, skipped)
+}
+
+/// Parse abook's addressbook file, with one INI-style section per entry. `name`, `email`, `nick`,
+/// `phone`, `workphone`, `mobile`, `fax`, the address fields, `url`, `notes`, `anniversary` and
+/// `groups` are converted, entries without a name are skipped.
+pub fn parse_abook(input: &str) -> (Vec<Component>, Vec<String>) {
+    // Sections are numbered entries, except for `[format]`.
+    let mut entries: Vec<(String, Vec<(String, String)>)> = vec![];
+    for line in input.lines().map(|x| x.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            entries.push((line[1..line.len() - 1].trim().to_owned(), vec![]));
+        } else if let (Some(entry), Some(i)) = (entries.last_mut(), line.find('=')) {
+            entry.1.push((line[..i].trim().to_lowercase(), line[i + 1..].trim().to_owned()));
+        }
+    }
+
+    let mut components = vec![];
+    let mut skipped = vec![];
+    for (section, fields) in entries {
+        if section == "format" {
+            continue;
+        }
+        let get = |key: &str| fields.iter().find(|x| x.0 == key).map(|x| x.1.clone()).unwrap_or_default();
+        let name = get("name");
+        if name.is_empty() {
+            skipped.push(format!("Skipped entry [{}]: no name.", section));
+            continue;
+        }
+
+        let mut comp = Component::new("VCARD");
+        comp.push(Property::new("VERSION", "3.0"));
+        comp.push(Property::new("FN", &name[..]));
+        let mut n = Property::new("N", "");
+        n.raw_value = structured_name(&name);
+        comp.push(n);
+        for email in get("email").split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            comp.push(Property::new("EMAIL", email));
+        }
+        for &(key, types) in &[("phone", Some("HOME")), ("workphone", Some("WORK")), ("mobile", Some("CELL")),
+                               ("fax", Some("FAX")), ("nick", None), ("url", None), ("notes", None),
+                               ("anniversary", None)] {
+            let value = get(key);
+            if value.is_empty() {
+                continue;
+            }
+            let prop_name = match key {
+                "nick" => "NICKNAME",
+                "url" => "URL",
+                "notes" => "NOTE",
+                "anniversary" => "ANNIVERSARY",
+                _ => "TEL"
+            };
+            let mut prop = Property::new(prop_name, &value[..]);
+            if let Some(x) = types {
+                prop.params.insert("TYPE".to_owned(), x.to_owned());
+            }
+            comp.push(prop);
+        }
+
+        let street: Vec<String> = vec![get("address"), get("address2")].into_iter().filter(|x| !x.is_empty()).collect();
+        let adr = [street.join(", "), get("city"), get("state"), get("zip"), get("country")];
+        if adr.iter().any(|x| !x.is_empty()) {
+            let parts: Vec<String> = adr.iter().map(|x| escape_chars(x)).collect();
+            let mut prop = Property::new("ADR", "");
+            prop.raw_value = format!(";;{}", parts.join(";"));
+            comp.push(prop);
+        }
+
+        let groups: Vec<String> = split_list_value(&get("groups")).iter().map(|x| escape_chars(x)).collect();
+        if !groups.is_empty() {
+            let mut prop = Property::new("CATEGORIES", "");
+            prop.raw_value = groups.join(",");
+            comp.push(prop);
+        }
+        components.push(comp);
+    }
+    (components, skipped)
+}
+
+/// A contact collected from mutt aliases.
+struct AliasEntry {
+    name: Option<String>,
+    email: String,
+    nicknames: Vec<String>,
+    categories: Vec<String>
+}
+
+/// Remove a trailing `# comment` from an alias line, unless the `#` is quoted.
+fn strip_alias_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted && prev.is_whitespace() => return line[..i].trim_right(),
+            _ => ()
+        }
+        prev = c;
+    }
+    line
+}
+
+/// Parse a mutt alias file. Each `alias KEY ADDRESS` line becomes a contact with KEY as its
+/// nickname. The addresses of aliases with several ones, which mutt uses as groups, get KEY as a
+/// category instead, like the groups given with `-group NAME`. Addresses listed several times are
+/// merged into one contact. Lines that aren't aliases are skipped.
+pub fn parse_mutt_aliases(input: &str) -> (Vec<Component>, Vec<String>) {
+    // Lines ending with a backslash are continued on the next one.
+    let mut lines: Vec<(usize, String)> = vec![];
+    let mut continued = false;
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim_right_matches('\r');
+        let (text, continues) = if line.ends_with('\\') { (&line[..line.len() - 1], true) } else { (line, false) };
+        match lines.last_mut() {
+            Some(&mut (_, ref mut last)) if continued => last.push_str(text),
+            _ => lines.push((i + 1, text.to_owned()))
+        }
+        continued = continues;
+    }
+
+    let mut entries: Vec<AliasEntry> = vec![];
+    let mut skipped = vec![];
+    for (lineno, line) in lines {
+        let line = strip_alias_comment(line.trim());
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        if words.next() != Some("alias") {
+            skipped.push(format!("Skipped line {}: not an alias.", lineno));
+            continue;
+        }
+        let mut rest = line["alias".len()..].trim_left();
+        let mut groups = vec![];
+        let mut key = None;
+        while let Some(word) = rest.split_whitespace().next() {
+            rest = rest[word.len()..].trim_left();
+            if word == "-group" {
+                if let Some(group) = rest.split_whitespace().next() {
+                    groups.push(group.to_owned());
+                    rest = rest[group.len()..].trim_left();
+                }
+            } else {
+                key = Some(word.to_owned());
+                break;
+            }
+        }
+        let key = match key {
+            Some(x) => x,
+            None => {
+                skipped.push(format!("Skipped line {}: no alias name.", lineno));
+                continue;
+            }
+        };
+        let addresses = parse_address_list(rest);
+        if addresses.is_empty() {
+            skipped.push(format!("Skipped line {}: no address for {}.", lineno, key));
+            continue;
+        }
+
+        let is_group = addresses.len() > 1;
+        for (name, email) in addresses {
+            let i = match entries.iter().position(|x| x.email.to_lowercase() == email.to_lowercase()) {
+                Some(i) => i,
+                None => {
+                    entries.push(AliasEntry { name: None, email: email, nicknames: vec![], categories: vec![] });
+                    entries.len() - 1
+                }
+            };
+            let entry = &mut entries[i];
+            if entry.name.is_none() {
+                entry.name = name.map(|x| x.trim().to_owned()).and_then(|x| if x.is_empty() { None } else { Some(x) });
+            }
+            let new_categories = if is_group { vec![key.clone()] } else { vec![] };
+            for category in new_categories.into_iter().chain(groups.iter().cloned()) {
+                if !entry.categories.contains(&category) {
+                    entry.categories.push(category);
+                }
+            }
+            if !is_group && !entry.nicknames.contains(&key) {
+                entry.nicknames.push(key.clone());
+            }
+        }
+    }
+
+    let components = entries.into_iter().map(|entry| {
+        let mut comp = Component::new("VCARD");
+        comp.push(Property::new("VERSION", "3.0"));
+        let mut n = Property::new("N", "");
+        n.raw_value = match entry.name {
+            Some(ref x) => structured_name(x),
+            None => ";;;;".to_owned()
+        };
+        comp.push(n);
+        comp.push(Property::new("FN", entry.name.as_ref().unwrap_or(&entry.email)));
+        comp.push(Property::new("EMAIL", &entry.email[..]));
+        for nickname in entry.nicknames {
+            comp.push(Property::new("NICKNAME", &nickname[..]));
+        }
+        if !entry.categories.is_empty() {
+            let categories: Vec<String> = entry.categories.iter().map(|x| escape_chars(x)).collect();
+            let mut prop = Property::new("CATEGORIES", "");
+            prop.raw_value = categories.join(",");
+            comp.push(prop);
+        }
+        comp
+    }).collect();
+    (components, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_csv;
+
+    fn row(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|x| (*x).to_owned()).collect()
+    }
+
+    #[test]
+    fn quoted_fields() {
+        assert_eq!(parse_csv("Name,Notes\r\n\"Doe, Jane\",\"first\r\nsecond\"\r\n"),
+                   vec![row(&["Name", "Notes"]), row(&["Doe, Jane", "first\r\nsecond"])]);
+        assert_eq!(parse_csv("\"say \"\"hi\"\"\",\"\"\"\",\"\"\n"), vec![row(&["say \"hi\"", "\"", ""])]);
+        // Quotes inside an unquoted field are kept as they are.
+        assert_eq!(parse_csv("a\"b,c\n"), vec![row(&["a\"b", "c"])]);
+    }
+
+    #[test]
+    fn separator_detection() {
+        assert_eq!(parse_csv("Name;E-mail\nDoe, Jane;jane@example.com\n"),
+                   vec![row(&["Name", "E-mail"]), row(&["Doe, Jane", "jane@example.com"])]);
+        assert_eq!(parse_csv("Name,E-mail;Home\nJane,a;b\n"),
+                   vec![row(&["Name", "E-mail;Home"]), row(&["Jane", "a;b"])]);
+        // Ties go to the comma.
+        assert_eq!(parse_csv("a;b,c\n"), vec![row(&["a;b", "c"])]);
+    }
+
+    #[test]
+    fn blank_parse_csv() {
+        assert_eq!(parse_csv("a,b\n\n1,2\r\n\r\n"), vec![row(&["a", "b"]), row(&[""]), row(&["1", "2"]), row(&[""])]);
+        assert_eq!(parse_csv("a,b\n1,"), vec![row(&["a", "b"]), row(&["1", ""])]);
+        assert_eq!(parse_csv(""), Vec::<Vec<String>>::new());
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        assert_eq!(parse_csv("\u{feff}Name;Phone\nJane;123"), vec![row(&["Name", "Phone"]), row(&["Jane", "123"])]);
+    }
+}
@@ -4,8 +4,29 @@ extern crate uuid;
 extern crate atomicwrites;
 extern crate clap;
 extern crate cursive;
+extern crate chrono;
+extern crate unicode_normalization;
+extern crate libc;
+extern crate regex;
+extern crate base64;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(feature = "watch")]
+extern crate notify;
 
 pub mod app;
 pub mod cli;
 mod utils;
 mod editor;
+mod export;
+mod import;
+mod harvest;
+mod binary_index;
+mod query;
+mod usage;
+mod output;
+mod color;
+mod qr;
+mod vcard21;
+#[cfg(feature = "sqlite")]
+mod sqlite_index;
@@ -0,0 +1,488 @@
+//! Machine-readable output formats of the query commands and `list`.
+//!
+//! Results are grouped into one record per contact, so a contact with several addresses appears
+//! once, listing all of them. Templates instead render each index entry into a line of its own.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::iter;
+use std::mem;
+use std::path;
+
+use cli::Configuration;
+use utils::{Contact,IndexItem,by_preference,display_name,format_address,org_value,property_types,terminal_width};
+
+/// Formats selectable with `--format`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    /// An array of objects with the keys `name`, `emails`, `filepath` and `uid`.
+    Json,
+    /// Comma-separated values quoted as in RFC 4180, with the same columns as `COLUMNS`.
+    Csv { header: bool },
+    /// Tab-separated values. Tabs and line breaks within values are replaced by spaces.
+    Tsv { header: bool },
+    /// `name <email>` lines, quoted as in RFC 5322, as alot's shellcommand address books expect.
+    Alot,
+    /// The JSON output of `notmuch address`, with an object per address.
+    Notmuch,
+    /// The table printed by `khard list`, with an index, the name, the first phone number and the
+    /// first email address of each contact.
+    Khard,
+    /// An aligned table of names, first email addresses, phone numbers and organizations, fit to
+    /// the width of the terminal.
+    Table
+}
+
+/// Columns of the CSV and TSV formats. Several emails are separated by commas.
+const COLUMNS: &'static [&'static str] = &["name", "emails", "filepath", "uid"];
+
+impl OutputFormat {
+    /// Parse a format name. With `header`, CSV and TSV start with a line naming the columns.
+    pub fn parse(s: &str, header: bool) -> Option<OutputFormat> {
+        match s {
+            "json" => Some(OutputFormat::Json),
+            "csv" => Some(OutputFormat::Csv { header: header }),
+            "tsv" => Some(OutputFormat::Tsv { header: header }),
+            "alot" => Some(OutputFormat::Alot),
+            "notmuch" => Some(OutputFormat::Notmuch),
+            "khard" => Some(OutputFormat::Khard),
+            "table" => Some(OutputFormat::Table),
+            _ => None
+        }
+    }
+}
+
+/// A line format given with `--template`, such as `{name} <{email}>\t{org}`.
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<TemplatePart>
+}
+
+#[derive(Clone, Debug)]
+enum TemplatePart {
+    Text(String),
+    Field(TemplateField)
+}
+
+/// Placeholders of templates, see `TEMPLATE_FIELDS`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TemplateField {
+    Name,
+    Email,
+    File,
+    Uid,
+    Tel,
+    Types,
+    Categories,
+    Collection,
+    /// One of the properties in `MATES_INDEX_FIELDS`, by position.
+    Property(usize)
+}
+
+/// Placeholders available besides the lowercase names of `MATES_INDEX_FIELDS`.
+const TEMPLATE_FIELDS: &'static [&'static str] = &[
+    "name", "email", "file", "uid", "tel", "types", "categories", "collection"
+];
+
+impl Template {
+    /// Parse a template. Placeholders are written in braces, literal braces doubled, and `\t`,
+    /// `\n` and `\\` stand for a tab, a newline and a backslash.
+    pub fn parse(s: &str, index_fields: &[String]) -> Result<Template, String> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => { chars.next(); text.push('{'); },
+                '}' if chars.peek() == Some(&'}') => { chars.next(); text.push('}'); },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(x) => name.push(x),
+                            None => return Err("Template: Missing }.".to_owned())
+                        }
+                    }
+                    let field = match TemplateField::parse(&name, index_fields) {
+                        Some(x) => x,
+                        None => return Err(format!(
+                            "Template: Unknown field {{{}}}, must be one of {} or MATES_INDEX_FIELDS.",
+                            name, TEMPLATE_FIELDS.join(", ")))
+                    };
+                    if !text.is_empty() {
+                        parts.push(TemplatePart::Text(mem::replace(&mut text, String::new())));
+                    }
+                    parts.push(TemplatePart::Field(field));
+                },
+                '}' => return Err("Template: Unmatched }, write }} for a literal one.".to_owned()),
+                '\\' => match chars.next() {
+                    Some('t') => text.push('\t'),
+                    Some('n') => text.push('\n'),
+                    Some('\\') => text.push('\\'),
+                    Some(x) => { text.push('\\'); text.push(x); },
+                    None => text.push('\\')
+                },
+                c => text.push(c)
+            }
+        }
+        if !text.is_empty() {
+            parts.push(TemplatePart::Text(text));
+        }
+        Ok(Template { parts: parts })
+    }
+
+    /// Render the template for an index entry.
+    pub fn render(&self, config: &Configuration, item: &IndexItem) -> String {
+        let mut rv = String::new();
+        for part in self.parts.iter() {
+            match *part {
+                TemplatePart::Text(ref x) => rv.push_str(x),
+                TemplatePart::Field(field) => rv.push_str(&match field {
+                    TemplateField::Name => item.name.clone(),
+                    TemplateField::Email => item.email.clone(),
+                    TemplateField::File => item.resolved_path(config).map(|x| x.display().to_string()).unwrap_or_default(),
+                    TemplateField::Uid => item.uid.clone(),
+                    TemplateField::Tel => item.tel.clone(),
+                    TemplateField::Types => item.types.clone(),
+                    TemplateField::Categories => item.categories.join(","),
+                    TemplateField::Collection => item.collection.clone(),
+                    TemplateField::Property(i) => item.fields.get(i).cloned().unwrap_or_default()
+                })
+            }
+        }
+        rv
+    }
+}
+
+impl TemplateField {
+    fn parse(s: &str, index_fields: &[String]) -> Option<TemplateField> {
+        match s {
+            "name" => Some(TemplateField::Name),
+            "email" => Some(TemplateField::Email),
+            "file" => Some(TemplateField::File),
+            "uid" => Some(TemplateField::Uid),
+            "tel" => Some(TemplateField::Tel),
+            "types" => Some(TemplateField::Types),
+            "categories" => Some(TemplateField::Categories),
+            "collection" => Some(TemplateField::Collection),
+            x => index_fields.iter().position(|f| f.to_lowercase() == x).map(TemplateField::Property)
+        }
+    }
+}
+
+/// A contact as printed in machine-readable formats.
+pub struct Record {
+    pub name: String,
+    pub emails: Vec<String>,
+    /// The `TYPE` of each of `emails`.
+    pub email_types: Vec<String>,
+    /// Phone numbers and their `TYPE`.
+    pub phones: Vec<(String, String)>,
+    pub filepath: path::PathBuf,
+    pub uid: String,
+    /// Name of the directory of the contact's collection.
+    pub collection: String,
+    /// The organization, with its units separated by commas.
+    pub org: String
+}
+
+impl Record {
+    fn columns(&self) -> Vec<String> {
+        vec![self.name.clone(), self.emails.join(","), self.filepath.display().to_string(), self.uid.clone()]
+    }
+
+    /// The record of a contact, with its preferred email addresses and phone numbers first.
+    pub fn from_contact(config: &Configuration, contact: &Contact) -> Record {
+        let comp = &contact.component;
+        let emails = by_preference(comp.get_all("EMAIL"));
+        Record {
+            name: display_name(comp, config.name_order).unwrap_or_default(),
+            emails: emails.iter().map(|x| x.value_as_string()).collect(),
+            email_types: emails.iter().map(|x| property_types(x)).collect(),
+            phones: by_preference(comp.get_all("TEL")).iter().map(|x| (x.value_as_string(), property_types(x))).collect(),
+            filepath: contact.path.clone(),
+            uid: comp.get_only("UID").map(|x| x.value_as_string()).unwrap_or_default(),
+            collection: collection_dir_name(config, &contact.path),
+            org: comp.get_all("ORG").iter().map(org_value).collect::<Vec<_>>().join(", ")
+        }
+    }
+}
+
+fn collection_dir_name(config: &Configuration, fpath: &path::Path) -> String {
+    config.collection_of(fpath).file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Group index entries into records by contact file, in the order each contact first appears.
+pub fn records_from_items<'a, I: Iterator<Item = &'a IndexItem>>(config: &Configuration, items: I) -> Vec<Record> {
+    let mut rv: Vec<Record> = vec![];
+    let mut positions = HashMap::new();
+    for item in items {
+        let filepath = item.resolved_path(config).unwrap_or_else(path::PathBuf::new);
+        let i = match positions.get(&filepath).cloned() {
+            Some(i) => i,
+            None => {
+                positions.insert(filepath.clone(), rv.len());
+                // The organization is only known if it's one of `MATES_INDEX_FIELDS`.
+                let org = config.index_fields.iter().position(|x| x == "ORG")
+                    .and_then(|i| item.fields.get(i).cloned())
+                    .unwrap_or_default();
+                rv.push(Record {
+                    name: item.name.clone(),
+                    emails: vec![],
+                    email_types: vec![],
+                    phones: vec![],
+                    collection: collection_dir_name(config, &filepath),
+                    filepath: filepath,
+                    uid: item.uid.clone(),
+                    org: org
+                });
+                rv.len() - 1
+            }
+        };
+        if !item.email.is_empty() && !rv[i].emails.contains(&item.email) {
+            rv[i].emails.push(item.email.clone());
+            rv[i].email_types.push(item.types.clone());
+        }
+        if !item.tel.is_empty() && !rv[i].phones.iter().any(|x| x.0 == item.tel) {
+            rv[i].phones.push((item.tel.clone(), item.types.clone()));
+        }
+    }
+    rv
+}
+
+/// Render records in the given format, ending with a newline.
+pub fn format_records(records: &[Record], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => to_json(records),
+        OutputFormat::Csv { header } => to_separated(records, header, |x| csv_field(x), ",", "\r\n"),
+        OutputFormat::Tsv { header } => to_separated(records, header, |x| x.replace(&['\t', '\r', '\n'][..], " "), "\t", "\n"),
+        OutputFormat::Alot => {
+            let mut rv = String::new();
+            for (name, email) in addresses(records) {
+                rv.push_str(&format_address(name, email));
+                rv.push('\n');
+            }
+            rv
+        },
+        OutputFormat::Notmuch => {
+            let objects: Vec<String> = addresses(records).map(|(name, email)| {
+                format!("{{\"name\": {}, \"address\": {}, \"name-addr\": {}}}",
+                        json_string(name), json_string(email), json_string(&format_address(name, email)))
+            }).collect();
+            format!("[{}]\n", objects.join(",\n"))
+        },
+        OutputFormat::Khard => to_khard(records),
+        OutputFormat::Table => to_table(records, terminal_width())
+    }
+}
+
+/// Render records like `khard list`: a table of left-aligned columns, preceded by the name of the
+/// address book, or with a column for it if the records are from several.
+fn to_khard(records: &[Record]) -> String {
+    if records.is_empty() {
+        return String::new();
+    }
+    let several = records.iter().any(|x| x.collection != records[0].collection);
+    let mut header = vec!["Index".to_owned(), "Name".to_owned(), "Phone".to_owned(), "E-Mail".to_owned()];
+    if several {
+        header.push("Address book".to_owned());
+    }
+    let mut rows = vec![header];
+    for (i, record) in records.iter().enumerate() {
+        let phone = record.phones.first().map(|&(ref tel, ref types)| khard_labeled(tel, types));
+        let email = record.emails.first().map(|x| khard_labeled(x, &record.email_types[0]));
+        let mut row = vec![(i + 1).to_string(), record.name.clone(), phone.unwrap_or_default(),
+                           email.unwrap_or_default()];
+        if several {
+            row.push(record.collection.clone());
+        }
+        rows.push(row);
+    }
+
+    // Like khard, pad every column to its widest value plus three spaces.
+    let widths = column_widths(&rows);
+    let mut rv = String::new();
+    if !several {
+        rv.push_str(&format!("Address book: {}\n", records[0].collection));
+    }
+    rv.push_str(&align_columns(&rows, &widths, 3));
+    rv
+}
+
+/// Render records as a table of their name, first email address, first phone number and
+/// organization. If it's wider than `width`, the widest columns are shortened, ending in `…`.
+fn to_table(records: &[Record], width: Option<usize>) -> String {
+    let mut rows = vec![vec!["Name".to_owned(), "Email".to_owned(), "Phone".to_owned(), "Organization".to_owned()]];
+    for record in records {
+        rows.push(vec![
+            record.name.clone(),
+            record.emails.first().cloned().unwrap_or_default(),
+            record.phones.first().map(|x| x.0.trim_left_matches("tel:").to_owned()).unwrap_or_default(),
+            record.org.clone()
+        ]);
+    }
+    for row in rows.iter_mut() {
+        for col in row.iter_mut() {
+            *col = col.replace(&['\t', '\r', '\n'][..], " ");
+        }
+    }
+
+    let gap = 2;
+    let mut widths = column_widths(&rows);
+    if let Some(width) = width {
+        let available = width.saturating_sub(gap * (widths.len() - 1));
+        while widths.iter().sum::<usize>() > available {
+            let (i, &widest) = widths.iter().enumerate().max_by_key(|&(_, w)| *w).unwrap();
+            // Keep at least a few characters of each column.
+            if widest <= 5 {
+                break;
+            }
+            widths[i] -= 1;
+        }
+        for row in rows.iter_mut() {
+            for (i, col) in row.iter_mut().enumerate() {
+                if col.chars().count() > widths[i] {
+                    *col = col.chars().take(widths[i] - 1).chain(iter::once('…')).collect();
+                }
+            }
+        }
+    }
+    align_columns(&rows, &widths, gap)
+}
+
+/// The width of each column of `rows`, in characters.
+fn column_widths(rows: &[Vec<String>]) -> Vec<usize> {
+    let mut widths = vec![0; rows.first().map_or(0, |x| x.len())];
+    for row in rows.iter() {
+        for (i, col) in row.iter().enumerate() {
+            widths[i] = cmp::max(widths[i], col.chars().count());
+        }
+    }
+    widths
+}
+
+/// Pad each column of `rows` to its width plus `gap` spaces, without trailing spaces.
+fn align_columns(rows: &[Vec<String>], widths: &[usize], gap: usize) -> String {
+    let mut rv = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (i, col) in row.iter().enumerate() {
+            line.push_str(col);
+            line.extend(iter::repeat(' ').take((widths[i] + gap).saturating_sub(col.chars().count())));
+        }
+        rv.push_str(line.trim_right());
+        rv.push('\n');
+    }
+    rv
+}
+
+/// A value prefixed with its lowercase types, as in `work: alice@example.com`. The `INTERNET` and
+/// `PREF` types of email addresses are left out.
+fn khard_labeled(value: &str, types: &str) -> String {
+    let labels: Vec<String> = types.split(',')
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty() && x != "internet" && x != "pref")
+        .collect();
+    if labels.is_empty() {
+        value.to_owned()
+    } else {
+        format!("{}: {}", labels.join(", "), value)
+    }
+}
+
+/// The (name, email) pairs of the given records.
+fn addresses<'a>(records: &'a [Record]) -> Box<Iterator<Item = (&'a str, &'a str)> + 'a> {
+    Box::new(records.iter().flat_map(|x| x.emails.iter().map(move |e| (&x.name[..], &e[..]))))
+}
+
+fn to_separated<F: Fn(&str) -> String>(records: &[Record], header: bool, field: F, separator: &str,
+                                       newline: &str) -> String {
+    let mut rv = String::new();
+    if header {
+        rv.push_str(&COLUMNS.join(separator));
+        rv.push_str(newline);
+    }
+    for record in records {
+        let fields: Vec<String> = record.columns().iter().map(|x| field(x)).collect();
+        rv.push_str(&fields.join(separator));
+        rv.push_str(newline);
+    }
+    rv
+}
+
+/// Quote `s` as a CSV field if it contains commas, quotes or line breaks.
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn to_json(records: &[Record]) -> String {
+    if records.is_empty() {
+        return "[]\n".to_owned();
+    }
+    let objects: Vec<String> = records.iter().map(|x| {
+        let emails: Vec<String> = x.emails.iter().map(|e| json_string(e)).collect();
+        format!("  {{\"name\": {}, \"emails\": [{}], \"filepath\": {}, \"uid\": {}}}",
+                json_string(&x.name), emails.join(", "),
+                json_string(&x.filepath.display().to_string()), json_string(&x.uid))
+    }).collect();
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+/// Quote `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut rv = String::with_capacity(s.len() + 2);
+    rv.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => rv.push_str("\\\""),
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\r' => rv.push_str("\\r"),
+            '\t' => rv.push_str("\\t"),
+            c if (c as u32) < 0x20 => rv.push_str(&format!("\\u{:04x}", c as u32)),
+            c => rv.push(c)
+        }
+    }
+    rv.push('"');
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use super::{OutputFormat,Record,format_records,to_table};
+
+    /// A contact with an address and a phone number, which are separate lines of the index.
+    fn record() -> Record {
+        Record {
+            name: "Jane Doe".to_owned(),
+            emails: vec!["jane@example.com".to_owned()],
+            email_types: vec!["work".to_owned()],
+            phones: vec![("tel:+1 555 1001".to_owned(), "cell".to_owned())],
+            filepath: path::PathBuf::from("jane.vcf"),
+            uid: "jane".to_owned(),
+            collection: "contacts".to_owned(),
+            org: "Acme".to_owned()
+        }
+    }
+
+    #[test]
+    fn table_shows_phone_numbers() {
+        assert_eq!(to_table(&[record()], None),
+                   "Name      Email             Phone        Organization\n\
+                    Jane Doe  jane@example.com  +1 555 1001  Acme\n");
+    }
+
+    #[test]
+    fn khard_shows_phone_numbers() {
+        let output = format_records(&[record()], OutputFormat::Khard);
+        assert!(output.starts_with("Address book: contacts\n"));
+        assert!(output.lines().nth(2).unwrap().contains("+1 555 1001"), "{}", output);
+    }
+}
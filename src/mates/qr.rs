@@ -0,0 +1,627 @@
+//! QR code generation for `mates qr`, following ISO/IEC 18004 (byte mode only).
+//!
+//! Codes are rendered as block characters for the terminal or as PNG images.
+
+use std::cmp;
+use std::iter;
+
+/// Error correction levels used, in order of preference.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EcLevel {
+    Medium,
+    Low
+}
+
+impl EcLevel {
+    fn index(self) -> usize {
+        match self {
+            EcLevel::Low => 0,
+            EcLevel::Medium => 1
+        }
+    }
+
+    /// The two bits identifying the level in the format information.
+    fn format_bits(self) -> u32 {
+        match self {
+            EcLevel::Low => 1,
+            EcLevel::Medium => 0
+        }
+    }
+}
+
+/// Error correction codewords per block, by level (low, medium) and version.
+const ECC_CODEWORDS_PER_BLOCK: [[u8; 41]; 2] = [
+    [0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28, 30, 30,
+     26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28, 28,
+     28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28]
+];
+
+/// Error correction blocks, by level (low, medium) and version.
+const NUM_ERROR_CORRECTION_BLOCKS: [[u8; 41]; 2] = [
+    [0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13, 14, 15,
+     16, 17, 18, 19, 19, 20, 21, 22, 24, 25],
+    [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23, 25,
+     26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49]
+];
+
+/// A QR code, as a square of dark (`true`) and light modules.
+pub struct QrCode {
+    size: usize,
+    modules: Vec<Vec<bool>>,
+    is_function: Vec<Vec<bool>>
+}
+
+impl QrCode {
+    /// Encode `data` in the smallest code possible, with medium error correction if it fits and low
+    /// otherwise. None if it's too long for any QR code.
+    pub fn encode(data: &[u8]) -> Option<QrCode> {
+        for &ecl in &[EcLevel::Medium, EcLevel::Low] {
+            for version in 1..41 {
+                let count_bits = if version < 10 { 8 } else { 16 };
+                if data.len() >= 1 << count_bits {
+                    continue;
+                }
+                if 4 + count_bits + data.len() * 8 <= num_data_codewords(version, ecl) * 8 {
+                    return Some(QrCode::with_version(data, version, ecl, count_bits));
+                }
+            }
+        }
+        None
+    }
+
+    fn with_version(data: &[u8], version: usize, ecl: EcLevel, count_bits: usize) -> QrCode {
+        // Byte mode indicator, character count, data, terminator and padding.
+        let capacity = num_data_codewords(version, ecl);
+        let mut bits = BitBuffer { bits: vec![] };
+        bits.append(0b0100, 4);
+        bits.append(data.len() as u32, count_bits);
+        for &b in data {
+            bits.append(b as u32, 8);
+        }
+        let terminator = cmp::min(4, capacity * 8 - bits.bits.len());
+        bits.append(0, terminator);
+        let padding = (8 - bits.bits.len() % 8) % 8;
+        bits.append(0, padding);
+        let mut codewords: Vec<u8> = bits.bits.chunks(8)
+            .map(|x| x.iter().fold(0, |acc, &b| (acc << 1) | b as u8))
+            .collect();
+        for &pad in [0xEC, 0x11].iter().cycle() {
+            if codewords.len() >= capacity {
+                break;
+            }
+            codewords.push(pad);
+        }
+
+        let size = version * 4 + 17;
+        let mut qr = QrCode {
+            size: size,
+            modules: vec![vec![false; size]; size],
+            is_function: vec![vec![false; size]; size]
+        };
+        qr.draw_function_patterns(version);
+        qr.draw_format_bits(ecl, 0);
+        qr.draw_codewords(&add_ecc_and_interleave(&codewords, version, ecl));
+
+        let mut best = (0, usize::max_value());
+        for mask in 0..8 {
+            qr.apply_mask(mask);
+            qr.draw_format_bits(ecl, mask);
+            let penalty = qr.penalty();
+            if penalty < best.1 {
+                best = (mask, penalty);
+            }
+            qr.apply_mask(mask);
+        }
+        qr.apply_mask(best.0);
+        qr.draw_format_bits(ecl, best.0);
+        qr
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y][x] = dark;
+        self.is_function[y][x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        for &(x, y) in &[(3, 3), (size - 4, 3), (3, size - 4)] {
+            // Finder pattern with its separator.
+            for dy in -4i32..5 {
+                for dx in -4i32..5 {
+                    let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+                    if xx >= 0 && yy >= 0 && (xx as usize) < size && (yy as usize) < size {
+                        let dist = cmp::max(dx.abs(), dy.abs());
+                        self.set_function(xx as usize, yy as usize, dist != 2 && dist != 4);
+                    }
+                }
+            }
+        }
+
+        let positions = alignment_pattern_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Skip the corners taken by finder patterns.
+                if (i == 0 && j == 0) || (i == 0 && j == last) || (i == last && j == 0) {
+                    continue;
+                }
+                for dy in -2i32..3 {
+                    for dx in -2i32..3 {
+                        let dark = cmp::max(dx.abs(), dy.abs()) != 1;
+                        self.set_function((x as i32 + dx) as usize, (y as i32 + dy) as usize, dark);
+                    }
+                }
+            }
+        }
+
+        if version >= 7 {
+            let mut rem = version as u32;
+            for _ in 0..12 {
+                rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+            }
+            let bits = (version as u32) << 12 | rem;
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 != 0;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    /// Draw both copies of the format information, and the dark module next to them.
+    fn draw_format_bits(&mut self, ecl: EcLevel, mask: u32) {
+        let data = ecl.format_bits() << 3 | mask;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = (data << 10 | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Fill the modules that aren't function patterns with the given codewords, in the zigzag
+    /// order of two-module wide columns starting at the bottom right.
+    fn draw_codewords(&mut self, data: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        loop {
+            if right == 6 {
+                right = 5;
+            }
+            for vert in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    if !self.is_function[y][x] && i < data.len() * 8 {
+                        self.modules[y][x] = (data[i >> 3] >> (7 - (i & 7))) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Invert the data modules selected by the given mask pattern. Applying it twice undoes it.
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0
+                };
+                if invert && !self.is_function[y][x] {
+                    self.modules[y][x] = !self.modules[y][x];
+                }
+            }
+        }
+    }
+
+    /// The penalty score of the current modules, lower scores being easier to scan.
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut rv = 0;
+        let columns: Vec<Vec<bool>> = (0..size).map(|x| (0..size).map(|y| self.modules[y][x]).collect()).collect();
+
+        for line in self.modules.iter().chain(columns.iter()) {
+            // Runs of five or more modules of the same color.
+            let mut run = 1;
+            for i in 1..size {
+                if line[i] == line[i - 1] {
+                    run += 1;
+                } else {
+                    if run >= 5 {
+                        rv += run - 2;
+                    }
+                    run = 1;
+                }
+            }
+            if run >= 5 {
+                rv += run - 2;
+            }
+
+            // Patterns looking like finders, with four light modules on one side. Modules outside
+            // the code count as light.
+            let padded: Vec<bool> = iter::repeat(false).take(4)
+                .chain(line.iter().cloned())
+                .chain(iter::repeat(false).take(4))
+                .collect();
+            for window in padded.windows(11) {
+                let core = [true, false, true, true, true, false, true];
+                if (window[..7] == core && window[7..].iter().all(|x| !x)) ||
+                    (window[..4].iter().all(|x| !x) && window[4..] == core) {
+                    rv += 40;
+                }
+            }
+        }
+
+        // 2x2 blocks of the same color.
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let c = self.modules[y][x];
+                if c == self.modules[y][x + 1] && c == self.modules[y + 1][x] && c == self.modules[y + 1][x + 1] {
+                    rv += 3;
+                }
+            }
+        }
+
+        // How far the share of dark modules is from half.
+        let total = size * size;
+        let dark = self.modules.iter().flat_map(|x| x.iter()).filter(|x| **x).count();
+        let deviation = if dark * 20 > total * 10 { dark * 20 - total * 10 } else { total * 10 - dark * 20 };
+        let k = (deviation + total - 1) / total - 1;
+        rv + k * 10
+    }
+
+    /// Whether the module at the given position is dark. Positions outside the code, in the quiet
+    /// zone, are light.
+    fn dark(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.size && (y as usize) < self.size &&
+            self.modules[y as usize][x as usize]
+    }
+
+    /// Render the code with block characters, two modules per character, and a quiet zone of four
+    /// modules around it. Light modules are drawn, as terminals usually show light text on a dark
+    /// background.
+    pub fn to_terminal(&self) -> String {
+        let quiet = 4;
+        let size = self.size as i32;
+        let mut rv = String::new();
+        let mut y = -quiet;
+        while y < size + quiet {
+            for x in -quiet..size + quiet {
+                rv.push(match (self.dark(x, y), self.dark(x, y + 1) || y + 1 >= size + quiet) {
+                    (false, false) => '█',
+                    (false, true) => '▀',
+                    (true, false) => '▄',
+                    (true, true) => ' '
+                });
+            }
+            rv.push('\n');
+            y += 2;
+        }
+        rv
+    }
+
+    /// Render the code as a black and white PNG image, with `scale` pixels per module and a quiet
+    /// zone of four modules.
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let quiet = 4;
+        let width = (self.size + 2 * quiet) * scale;
+
+        // Rows of one bit per pixel, each preceded by the filter type 0. White pixels are ones.
+        let row_bytes = (width + 7) / 8;
+        let mut raw = Vec::with_capacity((row_bytes + 1) * width);
+        for py in 0..width {
+            raw.push(0);
+            let y = (py / scale) as i32 - quiet as i32;
+            let mut row = vec![0u8; row_bytes];
+            for px in 0..width {
+                let x = (px / scale) as i32 - quiet as i32;
+                if !self.dark(x, y) {
+                    row[px / 8] |= 0x80 >> (px % 8);
+                }
+            }
+            raw.extend(row);
+        }
+
+        let mut header = vec![];
+        header.extend(&be_u32(width as u32));
+        header.extend(&be_u32(width as u32));
+        // Bit depth 1, grayscale, default compression, filtering and no interlacing.
+        header.extend(&[1, 0, 0, 0, 0]);
+
+        let mut rv = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+        png_chunk(&mut rv, b"IHDR", &header);
+        png_chunk(&mut rv, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut rv, b"IEND", &[]);
+        rv
+    }
+}
+
+struct BitBuffer {
+    bits: Vec<bool>
+}
+
+impl BitBuffer {
+    /// Append the lowest `len` bits of `value`, most significant first.
+    fn append(&mut self, value: u32, len: usize) {
+        for i in (0..len).rev() {
+            self.bits.push((value >> i) & 1 != 0);
+        }
+    }
+}
+
+/// Modules available for data and error correction, after function patterns.
+fn num_raw_data_modules(version: usize) -> usize {
+    let mut rv = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let num_align = version / 7 + 2;
+        rv -= (25 * num_align - 10) * num_align - 55;
+        if version >= 7 {
+            rv -= 36;
+        }
+    }
+    rv
+}
+
+fn num_data_codewords(version: usize, ecl: EcLevel) -> usize {
+    num_raw_data_modules(version) / 8 -
+        ECC_CODEWORDS_PER_BLOCK[ecl.index()][version] as usize *
+        NUM_ERROR_CORRECTION_BLOCKS[ecl.index()][version] as usize
+}
+
+/// Center coordinates of the alignment patterns, the same for rows and columns.
+fn alignment_pattern_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return vec![];
+    }
+    let num_align = version / 7 + 2;
+    let size = version * 4 + 17;
+    let step = if version == 32 { 26 } else { (version * 4 + num_align * 2 + 1) / (num_align * 2 - 2) * 2 };
+    let mut rv: Vec<usize> = (0..num_align - 1).map(|i| size - 7 - i * step).collect();
+    rv.push(6);
+    rv.reverse();
+    rv
+}
+
+/// Split the data codewords into blocks, append Reed-Solomon codewords to each and interleave them.
+fn add_ecc_and_interleave(data: &[u8], version: usize, ecl: EcLevel) -> Vec<u8> {
+    let num_blocks = NUM_ERROR_CORRECTION_BLOCKS[ecl.index()][version] as usize;
+    let block_ecc_len = ECC_CODEWORDS_PER_BLOCK[ecl.index()][version] as usize;
+    let raw_codewords = num_raw_data_modules(version) / 8;
+    let num_short_blocks = num_blocks - raw_codewords % num_blocks;
+    let short_block_len = raw_codewords / num_blocks;
+
+    let divisor = reed_solomon_divisor(block_ecc_len);
+    let mut blocks = vec![];
+    let mut k = 0;
+    for i in 0..num_blocks {
+        let len = short_block_len - block_ecc_len + if i < num_short_blocks { 0 } else { 1 };
+        let mut block = data[k..k + len].to_vec();
+        k += len;
+        let ecc = reed_solomon_remainder(&block, &divisor);
+        if i < num_short_blocks {
+            // Placeholder so all blocks have the same length, skipped when interleaving.
+            block.push(0);
+        }
+        block.extend(ecc);
+        blocks.push(block);
+    }
+
+    let mut rv = Vec::with_capacity(raw_codewords);
+    for i in 0..blocks[0].len() {
+        for (j, block) in blocks.iter().enumerate() {
+            if i != short_block_len - block_ecc_len || j >= num_short_blocks {
+                rv.push(block[i]);
+            }
+        }
+    }
+    rv
+}
+
+/// The generator polynomial of the given degree, without its leading term, highest power first.
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut rv = vec![0; degree];
+    rv[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            rv[j] = gf_multiply(rv[j], root);
+            if j + 1 < degree {
+                rv[j] ^= rv[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    rv
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut rv = vec![0; divisor.len()];
+    for &b in data {
+        let factor = b ^ rv.remove(0);
+        rv.push(0);
+        for (x, &y) in rv.iter_mut().zip(divisor.iter()) {
+            *x ^= gf_multiply(y, factor);
+        }
+    }
+    rv
+}
+
+/// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+fn be_u32(x: u32) -> [u8; 4] {
+    [(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8]
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    out.extend(&be_u32(data.len() as u32));
+    let start = out.len();
+    out.extend(kind);
+    out.extend(data);
+    let crc = crc32(&out[start..]);
+    out.extend(&be_u32(crc));
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Wrap `data` in a zlib stream of uncompressed blocks. QR codes are small enough not to bother
+/// with compression.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut rv = vec![0x78, 0x01];
+    let mut chunks = data.chunks(0xFFFF).peekable();
+    if chunks.peek().is_none() {
+        rv.extend(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let len = chunk.len() as u16;
+        rv.push(if chunks.peek().is_none() { 1 } else { 0 });
+        rv.extend(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        rv.extend(chunk);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &x in data {
+        a = (a + x as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    rv.extend(&be_u32(b << 16 | a));
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EcLevel,QrCode,reed_solomon_divisor,reed_solomon_remainder};
+
+    /// The 15 format bits of a code, read from the copy next to the top right and bottom left
+    /// finder patterns.
+    fn format_bits(qr: &QrCode) -> u32 {
+        let size = qr.size;
+        let mut rv = 0;
+        for i in 0..15 {
+            let dark = if i < 8 { qr.modules[8][size - 1 - i] } else { qr.modules[size - 15 + i][8] };
+            rv |= (dark as u32) << i;
+        }
+        rv
+    }
+
+    #[test]
+    fn reed_solomon_codewords() {
+        // The "01234567" example of ISO/IEC 18004 in version 1-M.
+        let data = [0x10, 0x20, 0x0C, 0x56, 0x61, 0x80, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11,
+                    0xEC, 0x11];
+        assert_eq!(reed_solomon_remainder(&data, &reed_solomon_divisor(10)),
+                   vec![0xA5, 0x24, 0xD4, 0xC1, 0xED, 0x36, 0xC7, 0x87, 0x2C, 0x55]);
+    }
+
+    #[test]
+    fn version_selection() {
+        // Version 1-M holds 14 bytes.
+        assert_eq!(QrCode::encode(&[b'a'; 14]).unwrap().size, 21);
+        assert_eq!(QrCode::encode(&[b'a'; 15]).unwrap().size, 25);
+        // Version 40-M holds 2331 bytes, longer data falls back to low error correction, which
+        // fits 2420 bytes in version 36.
+        assert_eq!(QrCode::encode(&[b'a'; 2331]).unwrap().size, 177);
+        assert_eq!(QrCode::encode(&[b'a'; 2332]).unwrap().size, 161);
+        // Version 40-L holds 2953 bytes, more don't fit in any code.
+        assert_eq!(QrCode::encode(&[b'a'; 2953]).unwrap().size, 177);
+        assert!(QrCode::encode(&[b'a'; 2954]).is_none());
+    }
+
+    #[test]
+    fn format_information() {
+        let mut qr = QrCode::encode(b"mailto:alice@example.com").unwrap();
+        qr.draw_format_bits(EcLevel::Medium, 0);
+        assert_eq!(format_bits(&qr), 0b101010000010010);
+        qr.draw_format_bits(EcLevel::Low, 0);
+        assert_eq!(format_bits(&qr), 0b111011111000100);
+        // The dark module is always set.
+        assert!(qr.modules[qr.size - 8][8]);
+    }
+
+    #[test]
+    fn version_information() {
+        // Version 7-M holds 122 bytes, version 6-M only 106, so this is the first code with version
+        // information, 000111110010010100 for version 7.
+        let qr = QrCode::encode(&[b'a'; 107]).unwrap();
+        assert_eq!(qr.size, 45);
+        let mut bits = 0;
+        for i in 0..18 {
+            bits |= (qr.modules[i / 3][qr.size - 11 + i % 3] as u32) << i;
+            assert_eq!(qr.modules[i / 3][qr.size - 11 + i % 3], qr.modules[qr.size - 11 + i % 3][i / 3]);
+        }
+        assert_eq!(bits, 0b000111110010010100);
+    }
+
+    #[test]
+    fn finder_patterns() {
+        let qr = QrCode::encode(b"BEGIN:VCARD").unwrap();
+        let size = qr.size;
+        for &(x, y) in &[(0, 0), (size - 7, 0), (0, size - 7)] {
+            for i in 0..7 {
+                // Dark outer ring, light ring and dark center.
+                assert!(qr.modules[y][x + i] && qr.modules[y + 6][x + i]);
+                assert!(qr.modules[y + i][x] && qr.modules[y + i][x + 6]);
+            }
+            assert!(!qr.modules[y + 1][x + 1] && qr.modules[y + 3][x + 3]);
+        }
+        // Timing patterns alternate between the finder patterns.
+        for i in 8..size - 8 {
+            assert_eq!(qr.modules[6][i], i % 2 == 0);
+            assert_eq!(qr.modules[i][6], i % 2 == 0);
+        }
+    }
+}
@@ -0,0 +1,498 @@
+//! Queries against the index.
+//!
+//! Lines of the index are first searched as a whole, which is what `MATES_GREP` does too. The
+//! matching lines are then checked column by column: terms that aren't restricted to some columns
+//! search the email address, name, categories, phone number and `MATES_INDEX_FIELDS`, but not
+//! bookkeeping columns like the file path, modification time or UID.
+//!
+//! Both plain search strings and regular expressions are compiled into a regex, so the results
+//! don't depend on whether `MATES_GREP` is used: its output is checked again. Matching is
+//! case-insensitive unless requested otherwise, so plain search strings are passed to grep with
+//! each letter as a bracket expression like `[aA]`.
+//!
+//! Fuzzy queries instead score each column with a Smith-Waterman alignment, which tolerates typos
+//! such as swapped or missing letters. They can't be passed to `MATES_GREP`.
+//!
+//! Exact queries match entries where a column, by default the email address, equals the whole
+//! search string.
+//!
+//! Phonetic queries compare the Soundex codes of words, by default those of the name, so `meier`
+//! finds Meyer, Maier and Mayr. They can't be passed to `MATES_GREP` either.
+//!
+//! A parsed query consists of terms separated by whitespace, which all have to match, e.g.
+//! `alice berlin`. `OR` separates alternatives: `alice OR bob`. Double quotes keep a term with
+//! spaces together. Terms starting with `-`, like `-noreply`, must not match. An empty query
+//! matches every entry, without involving `MATES_GREP`.
+
+use std::cmp;
+use std::io;
+use std::mem;
+
+use regex::{self,Regex,RegexBuilder};
+
+use utils::{IndexItem,fold_diacritics};
+
+/// Columns of the index a query can be restricted to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QueryField {
+    Name,
+    Email,
+    File,
+    Uid,
+    /// One of the properties in `MATES_INDEX_FIELDS`, by position.
+    Property(usize)
+}
+
+impl QueryField {
+    /// Parse `name`, `email`, `file`, `uid` or the lowercase name of one of `index_fields`, e.g. `org`.
+    pub fn parse(s: &str, index_fields: &[String]) -> Option<QueryField> {
+        match &s.to_lowercase()[..] {
+            "name" => Some(QueryField::Name),
+            "email" => Some(QueryField::Email),
+            "file" => Some(QueryField::File),
+            "uid" => Some(QueryField::Uid),
+            x => index_fields.iter().position(|f| f.to_lowercase() == x).map(QueryField::Property)
+        }
+    }
+}
+
+/// How the search string is interpreted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MatchMode {
+    /// Match lines containing the search string.
+    Substring,
+    /// Match lines matching the search string as a regular expression.
+    Regex,
+    /// Match lines similar to the search string, best matches first.
+    Fuzzy,
+    /// Match entries with a column equal to the search string. Lines are searched for it as a
+    /// substring first.
+    Exact,
+    /// Match entries whose name contains words sounding like those of the search string.
+    Phonetic
+}
+
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    pub mode: MatchMode,
+    /// Distinguish upper and lower case. Fuzzy and phonetic queries ignore this.
+    pub case_sensitive: bool
+}
+
+impl Default for QueryOptions {
+    fn default() -> QueryOptions {
+        QueryOptions {
+            mode: MatchMode::Substring,
+            case_sensitive: false
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Query {
+    pub mode: MatchMode,
+    pub case_sensitive: bool,
+    /// An entry matches if all terms of any of these match.
+    alternatives: Vec<Vec<Term>>
+}
+
+/// A single search string, possibly restricted to some columns.
+#[derive(Clone)]
+struct Term {
+    text: String,
+    /// Columns that are searched. If empty, those shown to users are, see `columns`.
+    fields: Vec<QueryField>,
+    /// Entries matching this term are excluded.
+    negated: bool,
+    matcher: Matcher
+}
+
+#[derive(Clone)]
+enum Matcher {
+    Pattern(Regex),
+    /// The lowercased, folded search string.
+    Fuzzy(Vec<char>),
+    /// `lines` finds lines containing `text`, which is lowercased unless `case_sensitive`.
+    Exact { lines: Regex, text: String, case_sensitive: bool },
+    /// The Soundex codes of the words of the search string.
+    Phonetic(Vec<String>)
+}
+
+impl Query {
+    /// A query matching lines that contain `text` anywhere, case-insensitively.
+    pub fn new(text: &str) -> Query {
+        Query::with_options(text, &QueryOptions::default()).expect("Escaped patterns are valid.")
+    }
+
+    /// A query for `text` as a single term, interpreted according to `options`. Fails if it's an
+    /// invalid regex. Unless it's exact, an empty query has no terms and matches every entry.
+    pub fn with_options(text: &str, options: &QueryOptions) -> io::Result<Query> {
+        if text.is_empty() && options.mode != MatchMode::Exact {
+            return Ok(Query::from_alternatives(options, vec![vec![]]));
+        }
+        let term = try!(Term::new(text, vec![], options));
+        Ok(Query::from_alternatives(options, vec![vec![term]]))
+    }
+
+    /// Split `text` into terms and alternatives. A term like `email:gmail.com` or `org:acme` only
+    /// searches the named column. Names are those accepted by `QueryField::parse`, text before a
+    /// colon that isn't one is searched for. A leading `-`, as in `-noreply` or `-email:noreply`,
+    /// negates a term. A query without terms matches every entry.
+    pub fn parse(text: &str, options: &QueryOptions, index_fields: &[String]) -> io::Result<Query> {
+        // An exact query is compared as a whole.
+        if options.mode == MatchMode::Exact {
+            return Query::with_options(text, options);
+        }
+        let mut alternatives = vec![vec![]];
+        for token in split_terms(text) {
+            if token == "OR" {
+                alternatives.push(vec![]);
+                continue;
+            }
+            let term = if token.len() > 1 && token.starts_with('-') {
+                try!(Term::parse(&token[1..], true, options, index_fields))
+            } else {
+                try!(Term::parse(&token, false, options, index_fields))
+            };
+            alternatives.last_mut().unwrap().push(term);
+        }
+        alternatives.retain(|x| !x.is_empty());
+        if alternatives.is_empty() {
+            return Query::with_options("", options);
+        }
+        Ok(Query::from_alternatives(options, alternatives))
+    }
+
+    /// Exclude entries matching `text`, parsed like a single term of `parse` without the `-`.
+    pub fn exclude(mut self, text: &str, options: &QueryOptions, index_fields: &[String]) -> io::Result<Query> {
+        let term = try!(Term::parse(text, true, options, index_fields));
+        for terms in self.alternatives.iter_mut() {
+            terms.push(term.clone());
+        }
+        Ok(self)
+    }
+
+    fn from_alternatives(options: &QueryOptions, alternatives: Vec<Vec<Term>>) -> Query {
+        Query {
+            mode: options.mode,
+            case_sensitive: options.case_sensitive && options.mode != MatchMode::Fuzzy &&
+                options.mode != MatchMode::Phonetic,
+            alternatives: alternatives
+        }
+    }
+
+    /// Only match terms that don't name a column themselves against the given columns.
+    pub fn in_fields(mut self, fields: Vec<QueryField>) -> Query {
+        for term in self.alternatives.iter_mut().flat_map(|x| x.iter_mut()) {
+            if term.fields.is_empty() {
+                term.fields = fields.clone();
+            }
+        }
+        self
+    }
+
+    /// The search string for a grep-like program, as a basic regular expression with special
+    /// characters escaped. `None` if grep can't find all matches, for fuzzy, phonetic and regex
+    /// queries, whose syntax differs from grep's, alternatives and queries without terms that aren't
+    /// negated, such as the empty query. Of several terms only the first one that isn't negated is
+    /// passed.
+    pub fn grep_pattern(&self) -> Option<String> {
+        if self.alternatives.len() != 1 {
+            return None;
+        }
+        let text = match self.alternatives[0].iter().find(|x| !x.negated) {
+            Some(term) => &term.text,
+            None => return None
+        };
+        match self.mode {
+            MatchMode::Substring | MatchMode::Exact => Some(escape_basic_regex(text, !self.case_sensitive)),
+            MatchMode::Regex | MatchMode::Fuzzy | MatchMode::Phonetic => None
+        }
+    }
+
+    /// Whether results should be sorted by `score`.
+    pub fn is_ranked(&self) -> bool {
+        self.mode == MatchMode::Fuzzy
+    }
+
+    /// Whether a line of the index may match. Lines for which this is false never match.
+    pub fn matches_line(&self, line: &str) -> bool {
+        self.alternatives.iter().any(|terms| terms.iter().all(|x| x.matches_line(line)))
+    }
+
+    /// Whether an entry of the index, whose line matched, matches.
+    pub fn matches(&self, item: &IndexItem) -> bool {
+        self.alternatives.iter().any(|terms| terms.iter().all(|x| x.columns(item).iter().any(|c| x.is_match(c)) != x.negated))
+    }
+
+    /// How well `item` matches a fuzzy query, higher is better. Always 0 for other queries.
+    pub fn score(&self, item: &IndexItem) -> i32 {
+        self.alternatives.iter()
+            .map(|terms| terms.iter().filter(|x| !x.negated).map(|x| x.score(item)).sum())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Term {
+    /// Parse a single term of a query without the `-` of negated ones, see `Query::parse`. Negated
+    /// terms aren't fuzzy or phonetic, since that would leave out far too much.
+    fn parse(token: &str, negated: bool, options: &QueryOptions, index_fields: &[String]) -> io::Result<Term> {
+        let substring = QueryOptions::default();
+        let options = match options.mode {
+            MatchMode::Fuzzy | MatchMode::Phonetic if negated => &substring,
+            _ => options
+        };
+        let (fields, text) = match token.find(':') {
+            Some(i) => match QueryField::parse(&token[..i], index_fields) {
+                Some(field) => (vec![field], &token[i + 1..]),
+                None => (vec![], token)
+            },
+            None => (vec![], token)
+        };
+        let mut term = try!(Term::new(text, fields, options));
+        term.negated = negated;
+        Ok(term)
+    }
+
+    fn new(text: &str, fields: Vec<QueryField>, options: &QueryOptions) -> io::Result<Term> {
+        let pattern = match options.mode {
+            MatchMode::Substring | MatchMode::Exact if options.case_sensitive => regex::escape(text),
+            // Lines contain the folded name, so "jose" finds "José".
+            MatchMode::Substring => format!("{}|{}", regex::escape(text), regex::escape(&fold_diacritics(text))),
+            MatchMode::Exact => regex::escape(text),
+            MatchMode::Regex => text.to_owned(),
+            MatchMode::Fuzzy => return Ok(Term {
+                text: text.to_owned(),
+                fields: fields,
+                negated: false,
+                matcher: Matcher::Fuzzy(fold_diacritics(text).chars().collect())
+            }),
+            MatchMode::Phonetic => return Ok(Term {
+                text: text.to_owned(),
+                fields: fields,
+                negated: false,
+                matcher: Matcher::Phonetic(soundex_words(text))
+            })
+        };
+        let pattern = try!(RegexBuilder::new(&pattern)
+                           .case_insensitive(!options.case_sensitive)
+                           .build()
+                           .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid query: {}", e))));
+        let matcher = match options.mode {
+            MatchMode::Exact => Matcher::Exact {
+                lines: pattern,
+                text: if options.case_sensitive { text.to_owned() } else { text.to_lowercase() },
+                case_sensitive: options.case_sensitive
+            },
+            _ => Matcher::Pattern(pattern)
+        };
+        Ok(Term {
+            text: text.to_owned(),
+            fields: fields,
+            negated: false,
+            matcher: matcher
+        })
+    }
+
+    fn is_match(&self, haystack: &str) -> bool {
+        match self.matcher {
+            Matcher::Pattern(ref x) => x.is_match(haystack),
+            Matcher::Fuzzy(ref needle) => fuzzy_score(needle, haystack) >= fuzzy_threshold(needle),
+            Matcher::Exact { ref text, case_sensitive: true, .. } => haystack == text,
+            Matcher::Exact { ref text, .. } => haystack.to_lowercase() == *text,
+            Matcher::Phonetic(ref codes) => {
+                let words = soundex_words(haystack);
+                codes.iter().all(|x| words.contains(x))
+            }
+        }
+    }
+
+    fn matches_line(&self, line: &str) -> bool {
+        // The line also holds columns that aren't searched, so whether a negated term matches can't
+        // be told from it.
+        if self.negated {
+            return true;
+        }
+        let found = match self.matcher {
+            Matcher::Pattern(ref x) | Matcher::Exact { lines: ref x, .. } => x.is_match(line),
+            Matcher::Fuzzy(_) => line.split('\t').any(|x| self.is_match(x)),
+            // Phonetic terms only search some columns, which are checked later.
+            Matcher::Phonetic(_) => return true
+        };
+        found != self.negated
+    }
+
+    /// The columns of `item` this term is matched against. Phonetic terms search the name unless
+    /// restricted to other columns, other terms those shown to users.
+    fn columns(&self, item: &IndexItem) -> Vec<String> {
+        if let (true, &Matcher::Phonetic(_)) = (self.fields.is_empty(), &self.matcher) {
+            return vec![item.name.clone()];
+        }
+        if self.fields.is_empty() {
+            let mut rv = vec![item.email.clone(), item.name.clone(), item.folded_name.clone(), item.tel.clone()];
+            rv.extend(item.categories.iter().cloned());
+            rv.extend(item.fields.iter().cloned());
+            return rv;
+        }
+        let mut rv = vec![];
+        for field in self.fields.iter() {
+            match *field {
+                QueryField::Name => {
+                    rv.push(item.name.clone());
+                    rv.push(item.folded_name.clone());
+                },
+                QueryField::Email => rv.push(item.email.clone()),
+                QueryField::File => rv.extend(item.filepath.as_ref().map(|x| x.display().to_string())),
+                QueryField::Uid => rv.push(item.uid.clone()),
+                QueryField::Property(i) => rv.extend(item.fields.get(i).cloned())
+            }
+        }
+        rv
+    }
+
+    fn score(&self, item: &IndexItem) -> i32 {
+        match self.matcher {
+            Matcher::Pattern(_) | Matcher::Exact { .. } | Matcher::Phonetic(_) => 0,
+            Matcher::Fuzzy(ref needle) => self.columns(item).iter().map(|x| fuzzy_score(needle, x)).max().unwrap_or(0)
+        }
+    }
+}
+
+/// Split a query at whitespace outside of double quotes, and remove the quotes.
+fn split_terms(text: &str) -> Vec<String> {
+    let mut rv = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut in_term = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_term = true;
+            },
+            c if c.is_whitespace() && !quoted => {
+                if in_term {
+                    rv.push(mem::replace(&mut current, String::new()));
+                    in_term = false;
+                }
+            },
+            c => {
+                current.push(c);
+                in_term = true;
+            }
+        }
+    }
+    if in_term {
+        rv.push(current);
+    }
+    rv
+}
+
+const FUZZY_MATCH: i32 = 16;
+const FUZZY_WORD_START: i32 = 8;
+const FUZZY_MISMATCH: i32 = -8;
+const FUZZY_GAP: i32 = -6;
+
+/// The score a column needs to match `needle`, 60% of an exact match. This allows for about one
+/// typo in four characters.
+fn fuzzy_threshold(needle: &[char]) -> i32 {
+    FUZZY_MATCH * needle.len() as i32 * 6 / 10
+}
+
+/// Score of the best local alignment of `needle` in `haystack`. Matches at the start of words
+/// score higher.
+fn fuzzy_score(needle: &[char], haystack: &str) -> i32 {
+    if needle.is_empty() {
+        return 0;
+    }
+    let haystack: Vec<char> = fold_diacritics(haystack).chars().collect();
+    let mut best = 0;
+    // Scores of alignments ending at the previous and current character of the haystack.
+    let mut prev = vec![0; needle.len() + 1];
+    let mut cur = vec![0; needle.len() + 1];
+    for (j, &c) in haystack.iter().enumerate() {
+        let word_start = j == 0 || !haystack[j - 1].is_alphanumeric();
+        for i in 1..needle.len() + 1 {
+            let diagonal = if needle[i - 1] == c {
+                prev[i - 1] + FUZZY_MATCH + if word_start { FUZZY_WORD_START } else { 0 }
+            } else {
+                prev[i - 1] + FUZZY_MISMATCH
+            };
+            let score = *[0, diagonal, prev[i] + FUZZY_GAP, cur[i - 1] + FUZZY_GAP].iter().max().unwrap();
+            cur[i] = score;
+            best = cmp::max(best, score);
+        }
+        mem::swap(&mut prev, &mut cur);
+    }
+    best
+}
+
+/// The Soundex codes of the words in `s`, e.g. `M600` for both Meier and Mayr. Words without
+/// letters are skipped.
+fn soundex_words(s: &str) -> Vec<String> {
+    fold_diacritics(s).split(|c: char| !c.is_alphanumeric()).filter_map(soundex).collect()
+}
+
+/// The Soundex code of `word`: its first letter followed by three digits for the following
+/// consonants. `None` if it doesn't start with an ASCII letter.
+fn soundex(word: &str) -> Option<String> {
+    fn digit(c: char) -> Option<char> {
+        match c {
+            'b' | 'f' | 'p' | 'v' => Some('1'),
+            'c' | 'g' | 'j' | 'k' | 'q' | 's' | 'x' | 'z' => Some('2'),
+            'd' | 't' => Some('3'),
+            'l' => Some('4'),
+            'm' | 'n' => Some('5'),
+            'r' => Some('6'),
+            _ => None
+        }
+    }
+
+    let mut letters = word.chars().flat_map(|c| c.to_lowercase()).filter(|c| c.is_ascii_alphabetic());
+    let first = match letters.next() {
+        Some(x) if word.chars().next().map_or(false, |c| c.is_alphabetic()) => x,
+        _ => return None
+    };
+    let mut rv: String = first.to_uppercase().collect();
+    let mut last = digit(first);
+    for c in letters {
+        let d = digit(c);
+        if d.is_some() && d != last {
+            rv.extend(d);
+            if rv.len() == 4 {
+                break;
+            }
+        }
+        // Consonants with the same code separated by h or w are coded once, vowels separate them.
+        if c != 'h' && c != 'w' {
+            last = d;
+        }
+    }
+    while rv.len() < 4 {
+        rv.push('0');
+    }
+    Some(rv)
+}
+
+/// Escape `s` for use as a POSIX basic regular expression, which is what grep expects by default.
+/// With `ignore_case`, letters match both their lower and upper case.
+fn escape_basic_regex(s: &str, ignore_case: bool) -> String {
+    let mut rv = String::with_capacity(s.len());
+    for c in s.chars() {
+        let lower: String = c.to_lowercase().collect();
+        let upper: String = c.to_uppercase().collect();
+        if ignore_case && lower != upper && lower.chars().count() == 1 && upper.chars().count() == 1 {
+            rv.push('[');
+            rv.push_str(&lower);
+            rv.push_str(&upper);
+            rv.push(']');
+            continue;
+        }
+        if "\\.[]*^$".contains(c) {
+            rv.push('\\');
+        }
+        rv.push(c);
+    }
+    rv
+}
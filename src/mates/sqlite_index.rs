@@ -0,0 +1,210 @@
+//! SQLite backend for the index, enabled with the `sqlite` cargo feature.
+//!
+//! Contacts are stored in normalized tables, so `mates index` only needs to update changed rows.
+//! For searching, rows are rendered into the same lines the text index consists of.
+
+use std::io;
+use std::path;
+
+use rusqlite::{self, Connection};
+
+use utils::{Contact,NameOrder,by_preference,display_name,file_mtime,fold_diacritics,index_field_value,property_types,
+            rev_value,sort_name};
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS contacts (
+        path TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        uid TEXT,
+        mtime TEXT,
+        rev TEXT NOT NULL DEFAULT '',
+        collection TEXT NOT NULL DEFAULT '',
+        sort_name TEXT NOT NULL DEFAULT '',
+        fields TEXT NOT NULL DEFAULT ''
+    );
+    CREATE TABLE IF NOT EXISTS emails (
+        path TEXT NOT NULL REFERENCES contacts(path) ON DELETE CASCADE,
+        email TEXT NOT NULL,
+        type TEXT NOT NULL DEFAULT '',
+        position INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS phones (
+        path TEXT NOT NULL REFERENCES contacts(path) ON DELETE CASCADE,
+        tel TEXT NOT NULL,
+        type TEXT NOT NULL DEFAULT '',
+        position INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS categories (
+        path TEXT NOT NULL REFERENCES contacts(path) ON DELETE CASCADE,
+        category TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS emails_email ON emails(email);
+    CREATE INDEX IF NOT EXISTS phones_tel ON phones(tel);
+    CREATE INDEX IF NOT EXISTS categories_category ON categories(category);
+";
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("SQLite error: {}", e))
+}
+
+fn add_missing_column(conn: &Connection, table: &str, column: &str, decl: &str) -> io::Result<bool> {
+    let exists: bool = try!(conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        rusqlite::params![table, column], |row| row.get(0)).map_err(sql_error));
+    if !exists {
+        try!(conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, decl))
+             .map_err(sql_error));
+    }
+    Ok(!exists)
+}
+
+/// Open (and create if necessary) the index database.
+pub fn open(index_path: &path::Path) -> io::Result<Connection> {
+    let conn = try!(Connection::open(index_path).map_err(sql_error));
+    try!(conn.execute_batch("PRAGMA foreign_keys = ON;").map_err(sql_error));
+    try!(conn.execute_batch(SCHEMA).map_err(sql_error));
+
+    // Databases created by older versions lack some columns. All contacts are indexed again to
+    // fill them.
+    let added = try!(add_missing_column(&conn, "contacts", "mtime", "TEXT")) |
+        try!(add_missing_column(&conn, "phones", "type", "TEXT NOT NULL DEFAULT ''")) |
+        try!(add_missing_column(&conn, "emails", "type", "TEXT NOT NULL DEFAULT ''")) |
+        try!(add_missing_column(&conn, "contacts", "fields", "TEXT NOT NULL DEFAULT ''")) |
+        try!(add_missing_column(&conn, "contacts", "rev", "TEXT NOT NULL DEFAULT ''")) |
+        try!(add_missing_column(&conn, "contacts", "collection", "TEXT NOT NULL DEFAULT ''")) |
+        try!(add_missing_column(&conn, "contacts", "sort_name", "TEXT NOT NULL DEFAULT ''")) |
+        try!(add_missing_column(&conn, "emails", "position", "INTEGER NOT NULL DEFAULT 0")) |
+        try!(add_missing_column(&conn, "phones", "position", "INTEGER NOT NULL DEFAULT 0"));
+    if added {
+        try!(conn.execute_batch("UPDATE contacts SET mtime = NULL;").map_err(sql_error));
+    }
+    Ok(conn)
+}
+
+/// Whether the contact with the given filepath, as stored in the index, was indexed with the given
+/// modification time.
+pub fn is_current(conn: &Connection, filepath: &path::Path, mtime: &str) -> io::Result<bool> {
+    let filepath = filepath.display().to_string();
+    conn.query_row("SELECT COUNT(*) > 0 FROM contacts WHERE path = ?1 AND mtime = ?2",
+                   rusqlite::params![filepath, mtime], |row| row.get(0)).map_err(sql_error)
+}
+
+/// Insert or replace the rows of the given contact, stored under `filepath` in `collection`, with
+/// its name in the given order.
+pub fn upsert_contact(conn: &Connection, contact: &Contact, filepath: &path::Path, collection: &str,
+                      fields: &[String], order: NameOrder) -> io::Result<()> {
+    let name = match display_name(&contact.component, order) {
+        Some(name) => name,
+        None => return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "No name found.",
+        ))
+    };
+    let filepath = filepath.display().to_string();
+    let uid = contact.component.get_only("UID").map(|x| x.value_as_string());
+    let mtime = file_mtime(&contact.path).ok();
+    let rev = contact.component.get_only("REV").map(|x| rev_value(x)).unwrap_or_default();
+    let sort_key = sort_name(&contact.component, order);
+    // The values of `MATES_INDEX_FIELDS` are stored tab-separated, just like in the text index.
+    let field_values: Vec<String> = fields.iter().map(|x| index_field_value(&contact.component, x)).collect();
+
+    let tx = try!(conn.unchecked_transaction().map_err(sql_error));
+    try!(tx.execute("DELETE FROM contacts WHERE path = ?1", rusqlite::params![filepath]).map_err(sql_error));
+    try!(tx.execute("INSERT INTO contacts (path, name, uid, mtime, rev, collection, sort_name, fields)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    rusqlite::params![filepath, name, uid, mtime, rev, collection, sort_key, field_values.join("\t")])
+         .map_err(sql_error));
+    // Positions keep the preferred addresses first, as in the text index.
+    for (i, email) in by_preference(contact.component.get_all("EMAIL")).into_iter().enumerate() {
+        try!(tx.execute("INSERT INTO emails (path, email, type, position) VALUES (?1, ?2, ?3, ?4)",
+                        rusqlite::params![filepath, email.value_as_string(), property_types(email), i as i64])
+             .map_err(sql_error));
+    }
+    for (i, tel) in by_preference(contact.component.get_all("TEL")).into_iter().enumerate() {
+        try!(tx.execute("INSERT INTO phones (path, tel, type, position) VALUES (?1, ?2, ?3, ?4)",
+                        rusqlite::params![filepath, tel.value_as_string(), property_types(tel), i as i64])
+             .map_err(sql_error));
+    }
+    for prop in contact.component.get_all("CATEGORIES") {
+        if prop.raw_value.is_empty() {
+            continue;
+        }
+        try!(tx.execute("INSERT INTO categories (path, category) VALUES (?1, ?2)",
+                        rusqlite::params![filepath, prop.raw_value]).map_err(sql_error));
+    }
+    tx.commit().map_err(sql_error)
+}
+
+/// Remove the rows of the contact with the given filepath, as stored in the index.
+pub fn remove_contact(conn: &Connection, filepath: &path::Path) -> io::Result<()> {
+    let filepath = filepath.display().to_string();
+    try!(conn.execute("DELETE FROM contacts WHERE path = ?1", rusqlite::params![filepath]).map_err(sql_error));
+    Ok(())
+}
+
+/// Remove contacts whose filepath is not among the given ones.
+pub fn retain_contacts(conn: &Connection, filepaths: &[path::PathBuf]) -> io::Result<()> {
+    let keep: Vec<String> = filepaths.iter().map(|x| x.display().to_string()).collect();
+    let existing: Vec<String> = {
+        let mut stmt = try!(conn.prepare("SELECT path FROM contacts").map_err(sql_error));
+        let rows = try!(stmt.query_map([], |row| row.get(0)).map_err(sql_error));
+        try!(rows.collect::<Result<_, _>>().map_err(sql_error))
+    };
+    for filepath in existing {
+        if !keep.contains(&filepath) {
+            try!(conn.execute("DELETE FROM contacts WHERE path = ?1", rusqlite::params![filepath]).map_err(sql_error));
+        }
+    }
+    Ok(())
+}
+
+/// Render the database as lines of the text index format, the email addresses of each contact
+/// before its phone numbers, both with the preferred ones first.
+pub fn index_lines(conn: &Connection) -> io::Result<String> {
+    let mut stmt = try!(conn.prepare("
+        SELECT contacts.path, contacts.name,
+               COALESCE((SELECT group_concat(category, ',') FROM categories
+                         WHERE categories.path = contacts.path), ''),
+               emails.email, NULL, COALESCE(contacts.mtime, ''), emails.type, COALESCE(contacts.uid, ''), contacts.rev, contacts.collection, contacts.sort_name, contacts.fields,
+               0, emails.position
+        FROM contacts JOIN emails ON emails.path = contacts.path
+        UNION ALL
+        SELECT contacts.path, contacts.name,
+               COALESCE((SELECT group_concat(category, ',') FROM categories
+                         WHERE categories.path = contacts.path), ''),
+               NULL, phones.tel, COALESCE(contacts.mtime, ''), phones.type, COALESCE(contacts.uid, ''), contacts.rev, contacts.collection, contacts.sort_name, contacts.fields,
+               1, phones.position
+        FROM contacts JOIN phones ON phones.path = contacts.path
+        ORDER BY 1, 13, 14
+    ").map_err(sql_error));
+
+    let rows = try!(stmt.query_map([], |row| {
+        let filepath: String = try!(row.get(0));
+        let name: String = try!(row.get(1));
+        let categories: String = try!(row.get(2));
+        let email: Option<String> = try!(row.get(3));
+        let tel: Option<String> = try!(row.get(4));
+        let mtime: String = try!(row.get(5));
+        let types: String = try!(row.get(6));
+        let uid: String = try!(row.get(7));
+        let rev: String = try!(row.get(8));
+        let collection: String = try!(row.get(9));
+        let sort_key: String = try!(row.get(10));
+        let fields: String = try!(row.get(11));
+        let mut line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", email.unwrap_or_default(), name,
+                               filepath, categories, tel.unwrap_or_default(), mtime, types, uid,
+                               fold_diacritics(&name), rev, collection, sort_key);
+        if !fields.is_empty() {
+            line.push('\t');
+            line.push_str(&fields);
+        }
+        line.push('\n');
+        Ok(line)
+    }).map_err(sql_error));
+
+    let mut rv = String::new();
+    for line in rows {
+        rv.push_str(&try!(line.map_err(sql_error)));
+    }
+    Ok(rv)
+}
@@ -0,0 +1,166 @@
+//! The usage log, recording which email addresses were used and when.
+//!
+//! Each line holds an address, how often it was used and the Unix time it was last used,
+//! separated by tabs. Query commands rank addresses by a combination of both.
+//!
+//! The contact log, filled by scanning sent mail, holds the Unix time each address was last
+//! written to in the same format, without the count.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Read,Write};
+use std::path;
+
+use atomicwrites::{AtomicFile,AllowOverwrite};
+use chrono::Utc;
+
+use utils::IndexLock;
+
+struct Entry {
+    count: u64,
+    last_used: i64
+}
+
+pub struct UsageLog {
+    entries: HashMap<String, Entry>
+}
+
+impl UsageLog {
+    /// Read the usage log at `path`, which is empty if the file doesn't exist.
+    pub fn read(path: &path::Path) -> io::Result<UsageLog> {
+        let _lock = try!(IndexLock::shared(path));
+        UsageLog::read_unlocked(path)
+    }
+
+    fn read_unlocked(path: &path::Path) -> io::Result<UsageLog> {
+        let mut entries = HashMap::new();
+        for line in try!(read_lines(path)) {
+            let parts: Vec<&str> = line.split('\t').collect();
+            let entry = match (parts.get(1).and_then(|x| x.parse().ok()),
+                               parts.get(2).and_then(|x| x.parse().ok())) {
+                (Some(count), Some(last_used)) => Entry { count: count, last_used: last_used },
+                _ => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid line in usage log: {}", line),
+                ))
+            };
+            entries.insert(parts[0].to_lowercase(), entry);
+        }
+        Ok(UsageLog { entries: entries })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How much an address was used, by frequency weighted by how recently it was last used. Zero
+    /// for addresses that were never used.
+    pub fn score(&self, email: &str) -> u64 {
+        let entry = match self.entries.get(&email.to_lowercase()) {
+            Some(x) => x,
+            None => return 0
+        };
+        let days = (Utc::now().timestamp() - entry.last_used) / 86400;
+        let weight = match days {
+            d if d < 4 => 100,
+            d if d < 14 => 70,
+            d if d < 31 => 50,
+            d if d < 90 => 30,
+            _ => 10
+        };
+        entry.count * weight
+    }
+}
+
+/// Lines of the log at `path`, none if it doesn't exist.
+fn read_lines(path: &path::Path) -> io::Result<Vec<String>> {
+    let mut s = String::new();
+    match fs::File::open(path) {
+        Ok(mut f) => { try!(f.read_to_string(&mut s)); },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e)
+    };
+    Ok(s.lines().filter(|x| !x.trim().is_empty()).map(|x| x.to_owned()).collect())
+}
+
+pub struct ContactLog {
+    entries: HashMap<String, i64>
+}
+
+impl ContactLog {
+    /// Read the contact log at `path`, which is empty if the file doesn't exist.
+    pub fn read(path: &path::Path) -> io::Result<ContactLog> {
+        let _lock = try!(IndexLock::shared(path));
+        ContactLog::read_unlocked(path)
+    }
+
+    fn read_unlocked(path: &path::Path) -> io::Result<ContactLog> {
+        let mut entries = HashMap::new();
+        for line in try!(read_lines(path)) {
+            let mut parts = line.splitn(2, '\t');
+            match (parts.next(), parts.next().and_then(|x| x.parse().ok())) {
+                (Some(email), Some(time)) => { entries.insert(email.to_lowercase(), time); },
+                _ => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid line in contact log: {}", line),
+                ))
+            }
+        }
+        Ok(ContactLog { entries: entries })
+    }
+
+    /// The Unix time `email` was last written to, if ever.
+    pub fn last_contacted(&self, email: &str) -> Option<i64> {
+        self.entries.get(&email.to_lowercase()).cloned()
+    }
+}
+
+/// Record that each of the given addresses was written to at the given Unix time in the contact
+/// log at `path`. Earlier times than the recorded ones are ignored. Return the number of addresses
+/// in the log.
+pub fn record_contacts<I: Iterator<Item = (String, i64)>>(path: &path::Path, contacts: I) -> io::Result<usize> {
+    let _lock = try!(IndexLock::exclusive(path));
+    let mut log = try!(ContactLog::read_unlocked(path));
+    for (email, time) in contacts {
+        let entry = log.entries.entry(email.to_lowercase()).or_insert(time);
+        if *entry < time {
+            *entry = time;
+        }
+    }
+
+    let mut emails: Vec<_> = log.entries.keys().collect();
+    emails.sort();
+    let af = AtomicFile::new(path, AllowOverwrite);
+    try!(af.write(|f| {
+        for email in emails.iter() {
+            try!(write!(f, "{}\t{}\n", email, log.entries[*email]));
+        }
+        Ok(())
+    }));
+    Ok(emails.len())
+}
+
+/// Record a use of each of the given email addresses in the usage log at `path`.
+pub fn record<'a, I: Iterator<Item = &'a str>>(path: &path::Path, emails: I) -> io::Result<()> {
+    let _lock = try!(IndexLock::exclusive(path));
+    let mut log = try!(UsageLog::read_unlocked(path));
+    let now = Utc::now().timestamp();
+    for email in emails {
+        let entry = log.entries.entry(email.to_lowercase()).or_insert(Entry { count: 0, last_used: now });
+        entry.count += 1;
+        entry.last_used = now;
+    }
+
+    let mut emails: Vec<_> = log.entries.keys().collect();
+    emails.sort();
+    let af = AtomicFile::new(path, AllowOverwrite);
+    try!(af.write(|f| {
+        for email in emails {
+            let entry = &log.entries[email];
+            try!(write!(f, "{}\t{}\t{}\n", email, entry.count, entry.last_used));
+        }
+        Ok(())
+    }));
+    Ok(())
+}
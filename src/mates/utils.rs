@@ -1,18 +1,38 @@
 use std::borrow::ToOwned;
+use std::cmp;
 use std::collections::HashSet;
+use std::env;
 use std::fs;
-use std::io::{Read,Write};
+use std::io::{BufRead,Read,Write};
 use std::io;
+use std::mem;
+use std::panic;
 use std::path;
 use std::process;
+use std::thread;
 use std::convert::AsRef;
+use std::ffi::OsString;
+use std::time;
 
-use atomicwrites::{AtomicFile,DisallowOverwrite};
+use atomicwrites::{AtomicFile,AllowOverwrite,DisallowOverwrite};
+use chrono::{DateTime,NaiveDate,NaiveDateTime,Utc};
+use email::Address;
 use email::rfc5322::Rfc5322Parser;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
 use uuid::Uuid;
-use vobject::{Component,Property,parse_component,write_component};
+use vobject::{Component,Property,escape_chars,fold_line,parse_component,unescape_chars};
 
-use cli::Configuration;
+use binary_index;
+use query::Query;
+use vcard21;
+use cli::{Configuration,IndexBackend};
+#[cfg(feature = "sqlite")]
+use sqlite_index;
+
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(x) => x, None => return None })
+}
 
 pub trait CustomPathExt {
     fn metadata(&self) -> io::Result<fs::Metadata>;
@@ -39,27 +59,137 @@ impl CustomPathExt for path::Path {
     }
 }
 
-pub fn handle_process(process: &mut process::Child) -> io::Result<()> {
-    let exitcode = try!(process.wait());
-    if !exitcode.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("{}", exitcode)
-        ));
-    };
-    Ok(())
+/// An advisory lock on the index, released when dropped. Writers hold an exclusive lock, readers a
+/// shared one.
+///
+/// The index itself is replaced by renaming a new file over it, so the lock is taken on a separate
+/// file next to it, e.g. `index.lock`.
+pub struct IndexLock {
+    #[allow(dead_code)]
+    file: fs::File
+}
+
+impl IndexLock {
+    /// Block until a shared lock on the index at `index_path` is acquired.
+    pub fn shared(index_path: &path::Path) -> io::Result<IndexLock> {
+        IndexLock::acquire(index_path, false)
+    }
+
+    /// Block until an exclusive lock on the index at `index_path` is acquired.
+    pub fn exclusive(index_path: &path::Path) -> io::Result<IndexLock> {
+        IndexLock::acquire(index_path, true)
+    }
+
+    fn acquire(index_path: &path::Path, exclusive: bool) -> io::Result<IndexLock> {
+        let mut lock_path = OsString::from(index_path.as_os_str());
+        lock_path.push(".lock");
+        let file = try!(fs::OpenOptions::new().write(true).create(true).open(&lock_path));
+        try!(flock(&file, exclusive));
+        Ok(IndexLock { file: file })
+    }
+}
+
+/// Whether stdin and stdout are terminals, so the user can be asked to choose.
+#[cfg(unix)]
+pub fn is_interactive() -> bool {
+    unsafe { libc::isatty(0) == 1 && libc::isatty(1) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn is_interactive() -> bool {
+    false
+}
+
+/// Whether stdout is a terminal, so output may be colored.
+#[cfg(unix)]
+pub fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(1) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stdout_is_terminal() -> bool {
+    false
+}
+
+/// Whether the current user may write to the given path, taking ownership, ACLs and read-only
+/// mounts into account.
+#[cfg(unix)]
+pub fn is_writable(p: &path::Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    match CString::new(p.as_os_str().as_bytes()) {
+        Ok(x) => unsafe { libc::access(x.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false
+    }
+}
+
+#[cfg(not(unix))]
+pub fn is_writable(p: &path::Path) -> bool {
+    fs::metadata(p).map(|m| !m.permissions().readonly()).unwrap_or(false)
+}
+
+/// The width of the terminal stdout is printed to, or `COLUMNS` if set. None if stdout isn't a
+/// terminal.
+pub fn terminal_width() -> Option<usize> {
+    if let Some(x) = env::var("COLUMNS").ok().and_then(|x| x.parse().ok()) {
+        return Some(x);
+    }
+    terminal_width_ioctl()
+}
+
+#[cfg(unix)]
+fn terminal_width_ioctl() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    if unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+        Some(size.ws_col as usize)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn terminal_width_ioctl() -> Option<usize> {
+    None
 }
 
+#[cfg(unix)]
+fn flock(file: &fs::File, exclusive: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn flock(_file: &fs::File, _exclusive: bool) -> io::Result<()> {
+    Ok(())
+}
 
+/// Lazily parsed lines of the index. When reading from a grep process, it's waited for once all of
+/// its output has been read. A shared lock on the index is held until then.
 pub struct IndexIterator {
-    linebuffer: Vec<String>
+    lines: Box<Iterator<Item = io::Result<String>>>,
+    /// Lines not matching this query are skipped.
+    query: Option<Query>,
+    process: Option<process::Child>,
+    lock: Option<IndexLock>
 }
 
 impl IndexIterator {
-    fn new(output: &String) -> IndexIterator {
-        let rv = output.split('\n').map(|x| x.to_string()).collect();
+    fn new(lines: Box<Iterator<Item = io::Result<String>>>, query: Option<Query>, process: Option<process::Child>,
+           lock: Option<IndexLock>) -> IndexIterator {
         IndexIterator {
-            linebuffer: rv
+            lines: lines,
+            query: query,
+            process: process,
+            lock: lock
         }
     }
 }
@@ -68,31 +198,132 @@ impl Iterator for IndexIterator {
     type Item = IndexItem;
 
     fn next(&mut self) -> Option<IndexItem> {
-        match self.linebuffer.pop() {
-            Some(x) => Some(IndexItem::new(x)),
-            None => None
+        loop {
+            match self.lines.next() {
+                Some(Ok(ref x)) if x.is_empty() => continue,
+                Some(Ok(ref x)) if self.query.as_ref().map_or(false, |q| !q.matches_line(x)) => continue,
+                Some(Ok(x)) => {
+                    let item = IndexItem::new(x);
+                    if self.query.as_ref().map_or(true, |q| q.matches(&item)) {
+                        return Some(item);
+                    }
+                },
+                Some(Err(_)) | None => {
+                    // grep exits with 1 if nothing matched, errors were printed to stderr.
+                    if let Some(mut process) = self.process.take() {
+                        let _ = process.wait();
+                    }
+                    self.lock = None;
+                    return None;
+                }
+            }
         }
     }
 }
 
+/// Number of columns in a line of the index before the ones set by `MATES_INDEX_FIELDS`.
+pub const INDEX_COLUMNS: usize = 12;
+
+/// Properties that have columns of their own and can't be listed in `MATES_INDEX_FIELDS`.
+pub const FIXED_INDEX_FIELDS: &'static [&'static str] = &["EMAIL", "FN", "CATEGORIES", "TEL", "UID", "REV"];
+
 pub struct IndexItem {
     pub email: String,
     pub name: String,
-    pub filepath: Option<path::PathBuf>
+    pub filepath: Option<path::PathBuf>,
+    pub categories: Vec<String>,
+    pub tel: String,
+    /// Modification time of the contact file when it was indexed.
+    pub mtime: String,
+    /// `TYPE` parameter of the email address or phone number, e.g. `WORK` or `CELL`.
+    pub types: String,
+    /// The contact's `UID`, which stays the same when sync tools rename files.
+    pub uid: String,
+    /// The name, lowercased and without diacritics, for searching.
+    pub folded_name: String,
+    /// The contact's `REV`, formatted like `format_timestamp` if it could be parsed.
+    pub rev: String,
+    /// Name of the collection the contact is in, empty if `MATES_DIR` is a single directory.
+    pub collection: String,
+    /// The key contacts are sorted by name with, see `sort_name`.
+    pub sort_name: String,
+    /// Values of the properties listed in `MATES_INDEX_FIELDS`, one per property.
+    pub fields: Vec<String>
 }
 
 impl IndexItem {
-    fn new(line: String) -> IndexItem {
-        let mut parts = line.split('\t');
+    pub fn new(line: String) -> IndexItem {
+        IndexItem::from_columns(line.split('\t'))
+    }
 
+    fn from_columns<'a, I: Iterator<Item = &'a str>>(mut parts: I) -> IndexItem {
         IndexItem {
             email: parts.next().unwrap_or("").to_string(),
             name: parts.next().unwrap_or("").to_string(),
             filepath: match parts.next() {
                 Some(x) => Some(path::PathBuf::from(x)),
                 None => None
-            }
+            },
+            categories: match parts.next() {
+                Some(x) => split_list_value(x),
+                None => vec![]
+            },
+            tel: parts.next().unwrap_or("").to_string(),
+            mtime: parts.next().unwrap_or("").to_string(),
+            types: parts.next().unwrap_or("").to_string(),
+            uid: parts.next().unwrap_or("").to_string(),
+            folded_name: parts.next().unwrap_or("").to_string(),
+            rev: parts.next().unwrap_or("").to_string(),
+            collection: parts.next().unwrap_or("").to_string(),
+            sort_name: parts.next().unwrap_or("").to_string(),
+            fields: parts.map(|x| x.to_string()).collect()
+        }
+    }
+
+    /// Format the item as a line of the index, including the newline.
+    pub fn to_line(&self) -> String {
+        let categories: Vec<String> = self.categories.iter().map(|x| escape_chars(x)).collect();
+        let mut line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                self.email,
+                self.name,
+                self.filepath.as_ref().map(|x| x.display().to_string()).unwrap_or_else(String::new),
+                categories.join(","),
+                self.tel,
+                self.mtime,
+                self.types,
+                self.uid,
+                self.folded_name,
+                self.rev,
+                self.collection,
+                self.sort_name);
+        for field in self.fields.iter() {
+            line.push('\t');
+            line.push_str(field);
         }
+        line.push('\n');
+        line
+    }
+
+    /// When the contact was last changed: its `REV`, or the modification time of its file if it
+    /// has none.
+    pub fn changed_at(&self) -> Option<DateTime<Utc>> {
+        if let Some(x) = parse_timestamp(&self.rev) {
+            return Some(x);
+        }
+        let secs = try_opt!(self.mtime.split('.').next().and_then(|x| x.parse().ok()));
+        Some(DateTime::from_utc(try_opt!(NaiveDateTime::from_timestamp_opt(secs, 0)), Utc))
+    }
+
+    /// Whether the contact is in the given category, ignoring case.
+    pub fn in_category(&self, category: &str) -> bool {
+        let category = category.to_lowercase();
+        self.categories.iter().any(|x| x.to_lowercase() == category)
+    }
+
+    /// The contact's filepath. Paths in the index are relative to their collection in
+    /// `MATES_DIR`, absolute paths written by older versions are returned unchanged.
+    pub fn resolved_path(&self, config: &Configuration) -> Option<path::PathBuf> {
+        self.filepath.as_ref().map(|x| config.resolve_indexed_path(x))
     }
 }
 
@@ -102,12 +333,14 @@ pub struct Contact {
 }
 
 impl Contact {
+    /// Read the contact in the file at `path`. vCard 2.1 is converted into 3.0 on the way, see
+    /// `vcard21::decode_vcards`.
     pub fn from_file<P: AsRef<path::Path>>(path: P) -> io::Result<Contact> {
         let mut contact_file = try!(fs::File::open(&path));
         let contact_string = {
-            let mut x = String::new();
-            try!(contact_file.read_to_string(&mut x));
-            x
+            let mut x = vec![];
+            try!(contact_file.read_to_end(&mut x));
+            join_type_params(&vcard21::decode_vcards(&x))
         };
 
         let item = match parse_component(&contact_string[..]) {
@@ -121,24 +354,63 @@ impl Contact {
         Ok(Contact { component: item, path: path.as_ref().to_owned() })
     }
 
-    pub fn generate(fullname: Option<&str>, email: Option<&str>, dir: &path::Path) -> Contact {
-        let (uid, contact_path) = {
-            let mut uid;
-            let mut contact_path;
-            loop {
-                uid = Uuid::new_v4().hyphenated().to_string();
-                contact_path = dir.join(&format!("{}.vcf", uid));
-                if !(*contact_path).exists() {
-                    break
+    /// Generate a filepath in `dir` from a new random UID, and return both.
+    pub fn new_uid_path(dir: &path::Path) -> (String, path::PathBuf) {
+        loop {
+            let uid = Uuid::new_v4().hyphenated().to_string();
+            let contact_path = dir.join(&format!("{}.vcf", uid));
+            if !(*contact_path).exists() {
+                return (uid, contact_path);
+            }
+        }
+    }
+
+    pub fn generate(fullname: Option<&str>, email: Option<&str>, email_type: Option<&str>,
+                    rev: DateTime<Utc>, version: VcardVersion, dir: &path::Path) -> Contact {
+        let (uid, contact_path) = Contact::new_uid_path(dir);
+        let component = generate_component(uid.into(), fullname, email, email_type, rev, version);
+        Contact { path: contact_path, component: component }
+    }
+
+    /// Serialize and reparse the contact as `write_create` writes it, and check that the last
+    /// property of each given name is read back with the given value, with control characters
+    /// replaced like `escape_strictly` does. Properties that aren't are escaped more strictly and
+    /// checked again, properties given `None` are skipped.
+    pub fn check_roundtrip(&mut self, values: &[(&str, Option<&str>)]) -> io::Result<()> {
+        let reparsed = try!(reparse(&self.component));
+        let mut offending = vec![];
+        for &(prop_name, value) in values {
+            if let Some(value) = value {
+                if last_value(&reparsed, prop_name) != Some(representable_value(value)) {
+                    offending.push((prop_name, value));
                 }
-            };
-            (uid, contact_path)
-        };
-        Contact { path: contact_path, component: generate_component(uid.into(), fullname, email) }
+            }
+        }
+        if offending.is_empty() {
+            return Ok(());
+        }
+
+        for &(prop_name, value) in &offending {
+            if let Some(prop) = self.component.props.get_mut(prop_name).and_then(|x| x.last_mut()) {
+                prop.raw_value = escape_strictly(value);
+            }
+        }
+        let reparsed = try!(reparse(&self.component));
+        for &(prop_name, value) in &offending {
+            let after = last_value(&reparsed, prop_name);
+            if after != Some(representable_value(value)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Contact doesn't survive serialization: {} {:?} would be read back as {:?}",
+                            prop_name, value, after.unwrap_or_default())
+                ));
+            }
+        }
+        Ok(())
     }
 
     pub fn write_create(&self) -> io::Result<()> {
-        let string = write_component(&self.component);
+        let string = write_component_sorted(&self.component);
         let af = AtomicFile::new(&self.path, DisallowOverwrite);
 
         try!(af.write(|f| {
@@ -146,13 +418,136 @@ impl Contact {
         }));
         Ok(())
     }
+
+    /// Like `write_create`, but replace the existing file.
+    pub fn write_replace(&self) -> io::Result<()> {
+        let string = write_component_sorted(&self.component);
+        let af = AtomicFile::new(&self.path, AllowOverwrite);
+
+        try!(af.write(|f| {
+            f.write_all(string.as_bytes())
+        }));
+        Ok(())
+    }
 }
 
+/// Like `vobject::write_component`, but with a stable order: VERSION first, then the other
+/// properties sorted by name, parameters sorted by key.
+pub fn write_component_sorted(c: &Component) -> String {
+    fn inner(buf: &mut String, c: &Component) {
+        buf.push_str("BEGIN:");
+        buf.push_str(&c.name);
+        buf.push_str("\r\n");
 
-fn generate_component(uid: String, fullname: Option<&str>, email: Option<&str>) -> Component {
+        let mut prop_names: Vec<&String> = c.props.keys().collect();
+        prop_names.sort_by_key(|x| (&x[..] != "VERSION", *x));
+
+        for prop_name in prop_names {
+            for prop in c.get_all(prop_name) {
+                let mut line = String::new();
+                if let Some(ref x) = prop.prop_group {
+                    line.push_str(x);
+                    line.push('.');
+                };
+                line.push_str(prop_name);
+
+                let mut params: Vec<(&String, &String)> = prop.params.iter().collect();
+                params.sort();
+                for (param_key, param_value) in params {
+                    line.push(';');
+                    line.push_str(param_key);
+                    line.push('=');
+                    line.push_str(param_value);
+                }
+                line.push(':');
+                line.push_str(&prop.raw_value);
+                buf.push_str(&fold_line(&line));
+                buf.push_str("\r\n");
+            }
+        }
+
+        for subcomponent in &c.subcomponents {
+            inner(buf, subcomponent);
+        }
+
+        buf.push_str("END:");
+        buf.push_str(&c.name);
+        buf.push_str("\r\n");
+    }
+
+    let mut buf = String::new();
+    inner(&mut buf, c);
+    buf
+}
+
+/// Properties a vCard can contain only once.
+const SINGULAR_PROPS: &'static [&'static str] = &[
+    "FN", "N", "UID", "REV", "VERSION", "PRODID", "BDAY", "ANNIVERSARY", "GENDER", "KIND"
+];
+
+/// Copy the properties of `other` into `comp`. Singular properties like FN or UID are only copied
+/// if `comp` doesn't have them, others are added unless `comp` already has an identical one.
+pub fn merge_components(comp: &mut Component, other: &Component) {
+    for (prop_name, props) in other.props.iter() {
+        if SINGULAR_PROPS.contains(&&prop_name[..]) {
+            if comp.get_all(prop_name).is_empty() {
+                for prop in props {
+                    comp.push(prop.clone());
+                }
+            }
+            continue;
+        }
+
+        for prop in props {
+            let exists = comp.get_all(prop_name).iter().any(|x| {
+                x.raw_value == prop.raw_value && x.params == prop.params
+            });
+            if !exists {
+                comp.push(prop.clone());
+            }
+        }
+    }
+}
+
+fn reparse(comp: &Component) -> io::Result<Component> {
+    parse_component(&write_component_sorted(comp)[..]).map_err(|e| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Contact can't be parsed back: {}", e)
+    ))
+}
+
+fn last_value(comp: &Component, prop_name: &str) -> Option<String> {
+    comp.get_all(prop_name).last().map(|x| x.value_as_string())
+}
+
+/// Escape a property value for a line of its own. Unlike `escape_chars`, backslashes are always
+/// escaped, and carriage returns and other control characters become line breaks and spaces.
+pub fn escape_strictly(value: &str) -> String {
+    let mut rv = String::new();
+    for c in representable_value(value).chars() {
+        match c {
+            '\\' | ';' | ',' => { rv.push('\\'); rv.push(c); },
+            '\n' => rv.push_str("\\n"),
+            _ => rv.push(c)
+        }
+    }
+    rv
+}
+
+/// The value `escape_strictly` writes: carriage returns as line breaks and other control
+/// characters as spaces.
+fn representable_value(value: &str) -> String {
+    value.replace("\r\n", "\n")
+        .chars()
+        .map(|c| if c == '\r' { '\n' } else if c.is_control() && c != '\n' { ' ' } else { c })
+        .collect()
+}
+
+fn generate_component(uid: String, fullname: Option<&str>, email: Option<&str>,
+                      email_type: Option<&str>, rev: DateTime<Utc>, version: VcardVersion) -> Component {
     let mut comp = Component::new("VCARD");
 
-    comp.push(Property::new("VERSION", "3.0"));
+    comp.push(Property::new("VERSION", version.as_str()));
 
     match fullname {
         Some(x) => comp.push(Property::new("FN", x)),
@@ -160,26 +555,137 @@ fn generate_component(uid: String, fullname: Option<&str>, email: Option<&str>)
     };
 
     match email {
-        Some(x) => comp.push(Property::new("EMAIL", x)),
+        Some(x) => {
+            let mut prop = Property::new("EMAIL", x);
+            if let Some(t) = email_type {
+                set_property_types(&mut prop, t, version);
+            }
+            comp.push(prop)
+        },
         None => ()
     };
     comp.push(Property::new("UID", &uid[..]));
+    comp.push(Property::new("REV", &format_timestamp(&rev)[..]));
     comp
 }
 
-pub fn index_query<'a>(config: &Configuration, query: &str) -> io::Result<IndexIterator> {
+/// Parse a vCard timestamp as used by REV, in basic (`20150102T030405Z`) or extended
+/// (`2015-01-02T03:04:05Z`) format. Date-only values are taken as midnight UTC.
+pub fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    let s: String = s.trim().chars().filter(|&c| c != '-' && c != ':').collect();
+    let s = s.trim_right_matches('Z');
+    if let Ok(x) = NaiveDateTime::parse_from_str(s, "%Y%m%dT%H%M%S") {
+        return Some(DateTime::from_utc(x, Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y%m%d").ok()
+        .map(|x| DateTime::from_utc(x.and_hms(0, 0, 0), Utc))
+}
+
+/// Parse a BDAY or ANNIVERSARY value into `(year, month, day)`. The year is `None` for values like
+/// `--0412` that omit it.
+pub fn parse_date_value(s: &str) -> Option<(Option<i32>, u32, u32)> {
+    let s = s.trim();
+    let s = s.split('T').next().unwrap_or("");
+    let (has_year, digits) = if s.starts_with("--") {
+        (false, s[2..].replace("-", ""))
+    } else {
+        (true, s.replace("-", ""))
+    };
+    if !digits.chars().all(|c| c.is_digit(10)) {
+        return None;
+    }
+
+    let (year, rest) = match (has_year, digits.len()) {
+        (true, 8) => (Some(try_opt!(digits[..4].parse().ok())), &digits[4..]),
+        (false, 4) => (None, &digits[..]),
+        _ => return None
+    };
+    let month: u32 = try_opt!(rest[..2].parse().ok());
+    let day: u32 = try_opt!(rest[2..].parse().ok());
+    // Validate with a leap year so February 29 is allowed without a year.
+    try_opt!(NaiveDate::from_ymd_opt(year.unwrap_or(2000), month, day));
+    Some((year, month, day))
+}
+
+/// Derive the raw value of the structured N property from a formatted name. Both "Given Family" and
+/// "Family, Given" are understood.
+pub fn structured_name(fullname: &str) -> String {
+    let (family, given, additional) = match fullname.find(',') {
+        Some(i) => (fullname[..i].trim().to_owned(), fullname[i + 1..].trim().to_owned(), String::new()),
+        None => {
+            let mut words: Vec<&str> = fullname.split_whitespace().collect();
+            let family = words.pop().unwrap_or("").to_owned();
+            let given = if words.is_empty() { String::new() } else { words.remove(0).to_owned() };
+            (family, given, words.join(" "))
+        }
+    };
+    format!("{};{};{};;", escape_strictly(&family), escape_strictly(&given), escape_strictly(&additional))
+}
+
+/// Format a name and email address for a mail header, e.g. `"Doe, John" <john@example.com>`. The
+/// name is quoted if it contains special characters.
+pub fn format_address(name: &str, email: &str) -> String {
+    if name.chars().any(|c| "()<>[]:;@\\,.\"".contains(c)) {
+        format!("\"{}\" <{}>", name.replace('\\', "\\\\").replace('"', "\\\""), email)
+    } else {
+        format!("{} <{}>", name, email)
+    }
+}
+
+/// Format a timestamp the way vCard expects it for REV, e.g. `20150102T030405Z`.
+pub fn format_timestamp(dt: &DateTime<Utc>) -> String {
+    dt.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Search the index for entries matching `query`. If `MATES_GREP` is set and the index is a text
+/// file, that command is used to find candidate lines, which are checked against `query` again.
+///
+/// Results of fuzzy queries are sorted by how well they match, others are in the order of the
+/// index.
+pub fn index_query<'a>(config: &Configuration, query: &Query) -> io::Result<IndexIterator> {
+    if !query.is_ranked() {
+        return search_index(config, query);
+    }
+
+    let mut items: Vec<(i32, IndexItem)> = try!(search_index(config, query))
+        .map(|x| (query.score(&x), x))
+        .collect();
+    items.sort_by(|a, b| b.0.cmp(&a.0));
+    let lines: Vec<String> = items.into_iter().map(|(_, x)| x.to_line().trim_right_matches('\n').to_owned()).collect();
+    Ok(IndexIterator::new(Box::new(lines.into_iter().map(Ok)), None, None, None))
+}
+
+fn search_index(config: &Configuration, query: &Query) -> io::Result<IndexIterator> {
+    let (grep_cmd, pattern) = match (config.grep_cmd.as_ref(), query.grep_pattern()) {
+        (Some(x), Some(pattern)) if config.index_backend == IndexBackend::Text => (x, pattern),
+        _ => {
+            // A text index can be searched while reading it, others are decoded at once.
+            if config.index_backend == IndexBackend::Text {
+                let lock = try!(IndexLock::shared(&config.index_path));
+                let mut reader = io::BufReader::new(try!(fs::File::open(&config.index_path)));
+                if !binary_index::is_binary(try!(reader.fill_buf())) {
+                    return Ok(IndexIterator::new(Box::new(reader.lines()), Some(query.clone()),
+                                                 None, Some(lock)));
+                }
+            }
+            let index = try!(read_index_lines(config));
+            let lines: Vec<String> = index.lines().map(|x| x.to_owned()).collect();
+            return Ok(IndexIterator::new(Box::new(lines.into_iter().map(Ok)), Some(query.clone()),
+                                         None, None));
+        }
+    };
+
+    let lock = try!(IndexLock::shared(&config.index_path));
     let mut process = try!(
-        command_from_config(&config.grep_cmd[..])
-        .arg(&query[..])
+        command_from_config(grep_cmd)
+        .arg(&pattern)
         .arg(&config.index_path)
-        .stdin(process::Stdio::piped())
+        .stdin(process::Stdio::null())
         .stdout(process::Stdio::piped())
         .stderr(process::Stdio::inherit())
         .spawn());
 
-    try!(handle_process(&mut process));
-
-    let stream = match process.stdout.as_mut() {
+    let stream = match process.stdout.take() {
         Some(x) => x,
         None => return Err(io::Error::new(
             io::ErrorKind::Other,
@@ -187,38 +693,559 @@ pub fn index_query<'a>(config: &Configuration, query: &str) -> io::Result<IndexI
         ))
     };
 
-    let mut output = String::new();
-    try!(stream.read_to_string(&mut output));
-    Ok(IndexIterator::new(&output))
+    Ok(IndexIterator::new(Box::new(io::BufReader::new(stream).lines()), Some(query.clone()), Some(process), Some(lock)))
+}
+
+/// Whether the index is older than the vdir or any of its contact files. A missing index is
+/// always stale.
+pub fn index_is_stale(config: &Configuration) -> io::Result<bool> {
+    let index_mtime = match fs::metadata(&config.index_path) {
+        Ok(x) => try!(x.modified()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e)
+    };
+
+    // Removing a file only changes the mtime of the directory.
+    for dir in config.collections.iter() {
+        if try!(try!(fs::metadata(dir)).modified()) > index_mtime {
+            return Ok(true);
+        }
+    }
+    for filepath in try!(config.contact_files()) {
+        if try!(try!(fs::metadata(&filepath)).modified()) > index_mtime {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Parse the given contact files on several threads. The results are in the same order as
+/// `fpaths`.
+pub fn read_contacts_parallel(fpaths: &[path::PathBuf]) -> Vec<io::Result<Contact>> {
+    let threads = thread::available_parallelism().map(|x| x.get()).unwrap_or(1);
+    let chunk_size = cmp::max(1, (fpaths.len() + threads - 1) / threads);
+    let handles: Vec<_> = fpaths.chunks(chunk_size).map(|chunk| {
+        let chunk = chunk.to_vec();
+        thread::spawn(move || chunk.iter().map(Contact::from_file).collect::<Vec<_>>())
+    }).collect();
+
+    let mut rv = Vec::with_capacity(fpaths.len());
+    for handle in handles {
+        rv.extend(handle.join().unwrap_or_else(|e| panic::resume_unwind(e)));
+    }
+    rv
+}
+
+/// Return the paths of all vcf-files in `dir`, sorted by filename.
+pub fn list_contact_files(dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+    let mut rv = vec![];
+    for entry in try!(fs::read_dir(dir)) {
+        let pathbuf = try!(entry).path();
+        if pathbuf.str_extension().unwrap_or("") == "vcf" && pathbuf.is_file() {
+            rv.push(pathbuf);
+        }
+    }
+    rv.sort();
+    Ok(rv)
+}
+
+/// Return the paths of all messages in the Maildir `dir`, from its `cur` and `new` directories,
+/// sorted. A directory without these is taken to contain one message per file.
+pub fn list_maildir_messages(dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+    let subdirs: Vec<path::PathBuf> = ["cur", "new"].iter().map(|x| dir.join(x)).filter(|x| x.is_dir()).collect();
+    let subdirs = if subdirs.is_empty() { vec![dir.to_owned()] } else { subdirs };
+    let mut rv = vec![];
+    for subdir in subdirs {
+        for entry in try!(fs::read_dir(&subdir)) {
+            let pathbuf = try!(entry).path();
+            let hidden = pathbuf.file_name().and_then(|x| x.to_str()).map_or(true, |x| x.starts_with('.'));
+            if !hidden && pathbuf.is_file() {
+                rv.push(pathbuf);
+            }
+        }
+    }
+    rv.sort();
+    Ok(rv)
+}
+
+/// Read the header of the email at `fpath`, up to the first empty line. Invalid UTF-8 is
+/// replaced.
+pub fn read_email_header(fpath: &path::Path) -> io::Result<String> {
+    let mut reader = io::BufReader::new(try!(fs::File::open(fpath)));
+    let mut bytes = vec![];
+    loop {
+        let start = bytes.len();
+        if try!(reader.read_until(b'\n', &mut bytes)) == 0 {
+            break;
+        }
+        if bytes[start..] == b"\n"[..] || bytes[start..] == b"\r\n"[..] {
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Given an email, return the addresses in its To, Cc and Bcc headers as (name, email) tuples.
+pub fn read_recipients_from_email(email: &str) -> Vec<(Option<String>, String)> {
+    read_addresses_from_email(email, &["to", "cc", "bcc"])
+}
+
+/// Given an email, return the addresses in the headers with the given lowercase names as (name,
+/// email) tuples.
+pub fn read_addresses_from_email(email: &str, headers: &[&str]) -> Vec<(Option<String>, String)> {
+    let mut rv = vec![];
+    let mut parser = Rfc5322Parser::new(email);
+    while !parser.eof() {
+        let header = match parser.consume_header() {
+            Some(x) => x,
+            None => break
+        };
+        if !headers.contains(&&header.name.to_lowercase()[..]) {
+            continue;
+        }
+        for address in header.get_value::<Vec<Address>>().unwrap_or_default() {
+            match address {
+                Address::Mailbox(x) => rv.push((x.name, x.address)),
+                Address::Group(_, xs) => rv.extend(xs.into_iter().map(|x| (x.name, x.address)))
+            }
+        }
+    }
+    rv
+}
+
+/// Parse a comma-separated list of addresses like `"Doe, John" <john@example.com>, jane@example.com
+/// (Jane Roe)` into (name, email) tuples. Parts without an `@` are left out.
+pub fn parse_address_list(s: &str) -> Vec<(Option<String>, String)> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let (mut quoted, mut depth) = (false, 0);
+    for c in s.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '<' | '(' if !quoted => depth += 1,
+            '>' | ')' if !quoted && depth > 0 => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                parts.push(current.split_off(0));
+                continue;
+            },
+            _ => ()
+        }
+        current.push(c);
+    }
+    parts.push(current);
+
+    let unquote = |x: &str| -> Option<String> {
+        let x = x.trim().trim_matches('"').replace("\\\"", "\"").trim().to_owned();
+        if x.is_empty() { None } else { Some(x) }
+    };
+    let mut rv = vec![];
+    for part in parts.iter().map(|x| x.trim()) {
+        let (name, email) = match (part.rfind('<'), part.rfind('>')) {
+            (Some(i), Some(j)) if i < j => (unquote(&part[..i]), part[i + 1..j].trim().to_owned()),
+            _ => match (part.find('('), part.rfind(')')) {
+                (Some(i), Some(j)) if i < j => (unquote(&part[i + 1..j]), part[..i].trim().to_owned()),
+                _ => (None, part.to_owned())
+            }
+        };
+        if email.contains('@') {
+            rv.push((name, email));
+        }
+    }
+    rv
+}
+
+/// Read all bytes of an index file while holding a shared lock on it.
+fn read_index_bytes(index_path: &path::Path) -> io::Result<Vec<u8>> {
+    let _lock = try!(IndexLock::shared(index_path));
+    let mut bytes = vec![];
+    try!(try!(fs::File::open(index_path)).read_to_end(&mut bytes));
+    Ok(bytes)
+}
+
+/// Read an index file in the text format, decoding it if it's a binary index.
+pub fn read_index_file(index_path: &path::Path) -> io::Result<String> {
+    let bytes = try!(read_index_bytes(index_path));
+    if binary_index::is_binary(&bytes) {
+        let records = try!(binary_index::decode(&bytes));
+        return Ok(records.iter().map(|x| x.join("\t") + "\n").collect());
+    }
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Replace the contents of an index file with the given lines, in the binary format if `binary`
+/// is set. The lines are written to a temporary file first, which is then renamed over the index.
+pub fn write_index_file(index_path: &path::Path, lines: &str, binary: bool) -> io::Result<()> {
+    let mut bytes = vec![];
+    if binary {
+        bytes.extend_from_slice(binary_index::MAGIC);
+        binary_index::encode_lines(lines, &mut bytes);
+    } else {
+        bytes.extend_from_slice(lines.as_bytes());
+    }
+    let _lock = try!(IndexLock::exclusive(index_path));
+    let af = AtomicFile::new(index_path, AllowOverwrite);
+    try!(af.write::<(), io::Error, _>(|outf| outf.write_all(&bytes)));
+    Ok(())
+}
+
+/// Append lines to an index file, in the format the file already has. New files are created in
+/// the binary format if `binary` is set.
+pub fn append_to_index_file(index_path: &path::Path, lines: &str, binary: bool) -> io::Result<()> {
+    let _lock = try!(IndexLock::exclusive(index_path));
+    let mut index_fp = try!(fs::OpenOptions::new()
+                            .read(true)
+                            .append(true)
+                            .create(true)
+                            .open(index_path));
+    let mut head = vec![];
+    try!((&mut index_fp).take(binary_index::MAGIC.len() as u64).read_to_end(&mut head));
+
+    let mut bytes = vec![];
+    if head.is_empty() && binary || binary_index::is_binary(&head) {
+        if head.is_empty() {
+            bytes.extend_from_slice(binary_index::MAGIC);
+        }
+        binary_index::encode_lines(lines, &mut bytes);
+    } else {
+        bytes.extend_from_slice(lines.as_bytes());
+    }
+    index_fp.write_all(&bytes)
+}
+
+/// Return the contents of the index in the text format, whatever backend is used.
+pub fn read_index_lines(config: &Configuration) -> io::Result<String> {
+    #[cfg(feature = "sqlite")]
+    {
+        if config.index_backend == IndexBackend::Sqlite {
+            return sqlite_index::index_lines(&try!(sqlite_index::open(&config.index_path)));
+        }
+    }
+
+    read_index_file(&config.index_path)
+}
+
+/// Read all entries of the index without spawning grep, in the order they appear in the file.
+pub fn read_index(config: &Configuration) -> io::Result<Vec<IndexItem>> {
+    if config.index_backend == IndexBackend::Binary {
+        let bytes = try!(read_index_bytes(&config.index_path));
+        if binary_index::is_binary(&bytes) {
+            let records = try!(binary_index::decode(&bytes));
+            return Ok(records.iter()
+                      .map(|x| IndexItem::from_columns(x.iter().map(|x| &x[..])))
+                      .collect());
+        }
+    }
+
+    let output = try!(read_index_lines(config));
+    Ok(output.lines()
+       .filter(|x| !x.trim().is_empty())
+       .map(|x| IndexItem::new(x.to_owned()))
+       .collect())
 }
 
 /// Better than index_query if you're only interested in the filepath, as duplicate entries will be
 /// removed.
-pub fn file_query(config: &Configuration, query: &str) -> io::Result<HashSet<path::PathBuf>> {
+pub fn file_query(config: &Configuration, query: &Query) -> io::Result<HashSet<path::PathBuf>> {
     let mut rv: HashSet<path::PathBuf> = HashSet::new();
     rv.extend(
-        try!(index_query(config, query)).filter_map(|x| x.filepath)
+        try!(index_query(config, query)).filter_map(|x| x.resolved_path(config))
     );
     Ok(rv)
 }
 
-pub fn index_item_from_contact(contact: &Contact) -> io::Result<String> {
-    let name = match contact.component.get_only("FN") {
-        Some(name) => name.value_as_string(),
+/// Return `filepath` relative to `dir`, or `filepath` itself if it isn't inside `dir`.
+pub fn relative_to_dir(filepath: &path::Path, dir: &path::Path) -> path::PathBuf {
+    if let Ok(x) = filepath.strip_prefix(dir) {
+        return x.to_owned();
+    }
+    if let (Ok(abs_file), Ok(abs_dir)) = (fs::canonicalize(filepath), fs::canonicalize(dir)) {
+        if let Ok(x) = abs_file.strip_prefix(&abs_dir) {
+            return x.to_owned();
+        }
+    }
+    filepath.to_owned()
+}
+
+/// Modification time of a file, as stored in the index.
+pub fn file_mtime(filepath: &path::Path) -> io::Result<String> {
+    let mtime = try!(try!(fs::metadata(filepath)).modified());
+    Ok(match mtime.duration_since(time::UNIX_EPOCH) {
+        Ok(x) => format!("{}.{:09}", x.as_secs(), x.subsec_nanos()),
+        Err(_) => "0".to_owned()
+    })
+}
+
+/// Generate the index entries for the given contact, stored under `filepath` in `collection`, with a
+/// column for each of `fields` and names in the given order. There is one entry per email address
+/// and one per phone number.
+pub fn index_items_from_contact(contact: &Contact, filepath: &path::Path, collection: &str,
+                                fields: &[String], order: NameOrder) -> io::Result<Vec<IndexItem>> {
+    let name = match display_name(&contact.component, order) {
+        Some(name) => name,
         None => return Err(io::Error::new(
             io::ErrorKind::Other,
             "No name found.",
         ))
     };
 
-    let emails = contact.component.get_all("EMAIL");
-    let mut rv = String::new();
-    for email in emails.iter() {
-        rv.push_str(&format!("{}\t{}\t{}\n", email.value_as_string(), name, contact.path.display())[..]);
+    let mtime = file_mtime(&contact.path).unwrap_or_else(|_| String::new());
+    let categories: Vec<String> = contact.component.get_all("CATEGORIES").iter()
+        .flat_map(|x| split_list_value(&x.raw_value))
+        .collect();
+    let uid = contact.component.get_only("UID").map(|x| x.value_as_string()).unwrap_or_else(String::new);
+    let rev = contact.component.get_only("REV").map(|x| rev_value(x)).unwrap_or_else(String::new);
+    let field_values: Vec<String> = fields.iter().map(|x| index_field_value(&contact.component, x)).collect();
+    let sort_key = sort_name(&contact.component, order);
+    let item = |email: String, tel: String, types: String| IndexItem {
+        email: email,
+        name: name.clone(),
+        filepath: Some(filepath.to_owned()),
+        categories: categories.clone(),
+        tel: tel,
+        mtime: mtime.clone(),
+        types: types,
+        uid: uid.clone(),
+        folded_name: fold_diacritics(&name),
+        rev: rev.clone(),
+        collection: collection.to_owned(),
+        sort_name: sort_key.clone(),
+        fields: field_values.clone()
+    };
+
+    let mut rv = vec![];
+    for email in by_preference(contact.component.get_all("EMAIL")) {
+        rv.push(item(email.value_as_string(), String::new(), property_types(email)));
+    };
+    // Phone numbers get entries of their own, with an empty email.
+    for tel in by_preference(contact.component.get_all("TEL")) {
+        rv.push(item(String::new(), tel.value_as_string(), property_types(tel)));
     };
     Ok(rv)
 }
 
+/// The vCard version contacts are written in, set by `MATES_VCARD_VERSION`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VcardVersion {
+    V3,
+    V4
+}
+
+impl VcardVersion {
+    /// The version of a component by its `VERSION`. Everything before 4.0 is written like 3.0.
+    pub fn of(comp: &Component) -> VcardVersion {
+        match comp.get_only("VERSION").map(|x| x.value_as_string()) {
+            Some(ref x) if x.trim() == "4.0" => VcardVersion::V4,
+            _ => VcardVersion::V3
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            VcardVersion::V3 => "3.0",
+            VcardVersion::V4 => "4.0"
+        }
+    }
+}
+
+/// In which order the parts of names are displayed, set by `MATES_NAME_ORDER`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NameOrder {
+    /// The `FN` as written, usually "Given Family".
+    Given,
+    /// The family name first, followed by the given and additional names from `N`.
+    Family
+}
+
+/// The parts of the contact's `N`: family, given and additional names, prefixes and suffixes. Parts
+/// holding several names are joined with spaces.
+fn name_parts(comp: &Component) -> Vec<String> {
+    let raw_n = comp.get_only("N").map(|x| x.raw_value.clone()).unwrap_or_default();
+    raw_n.split(';').map(|x| split_list_value(x).join(" ")).collect()
+}
+
+/// The name of a contact as displayed in the given order, none if it has no `FN`. Contacts without a
+/// family name in `N` are displayed by their `FN` either way.
+pub fn display_name(comp: &Component, order: NameOrder) -> Option<String> {
+    let name = try_opt!(comp.get_only("FN")).value_as_string();
+    let parts = name_parts(comp);
+    if order == NameOrder::Family && parts.get(0).map_or(false, |x| !x.is_empty()) {
+        let names: Vec<&str> = parts.iter().take(3).map(|x| &x[..]).filter(|x| !x.is_empty()).collect();
+        return Some(names.join(" "));
+    }
+    Some(name)
+}
+
+/// The key a contact is sorted by name with: its `X-SORT-AS` or the `SORT-AS` parameter of its `N`
+/// if given, else the name displayed in the given order, folded like `fold_diacritics`.
+pub fn sort_name(comp: &Component, order: NameOrder) -> String {
+    let explicit = match comp.get_only("X-SORT-AS") {
+        Some(x) => x.value_as_string(),
+        None => comp.get_only("N").and_then(|x| x.params.get("SORT-AS"))
+            .map(|x| x.trim_matches('"').split(',').map(|x| x.trim()).collect::<Vec<_>>().join(" "))
+            .unwrap_or_default()
+    };
+    if explicit.trim().is_empty() {
+        fold_diacritics(&display_name(comp, order).unwrap_or_default())
+    } else {
+        fold_diacritics(explicit.trim())
+    }
+}
+
+/// The value of a `REV` property, normalized with `format_timestamp` if possible.
+pub fn rev_value(prop: &Property) -> String {
+    let raw = prop.value_as_string();
+    match parse_timestamp(&raw) {
+        Some(x) => format_timestamp(&x),
+        None => raw.trim().to_owned()
+    }
+}
+
+/// Lowercase `s` and strip diacritics, e.g. "José" becomes "jose".
+pub fn fold_diacritics(s: &str) -> String {
+    s.nfd().filter(|c| !is_combining_mark(*c)).collect::<String>().to_lowercase()
+}
+
+/// The values of all properties with the given name, as stored in an index column.
+pub fn index_field_value(component: &Component, name: &str) -> String {
+    let values: Vec<String> = component.get_all(name).iter()
+        .map(|x| if name == "ORG" { org_value(x) } else { x.value_as_string() })
+        .map(|x| x.replace(|c| c == '\t' || c == '\n' || c == '\r', " "))
+        .collect();
+    values.join(",")
+}
+
+/// The organization name and units of an `ORG` property, separated by commas.
+pub fn org_value(prop: &Property) -> String {
+    split_value(&prop.raw_value, ';').join(", ")
+}
+
+/// The `TYPE` parameter of a property, uppercased and without quotes. Properties with a vCard 4.0
+/// `PREF` parameter get the `PREF` type, like in vCard 3.0.
+pub fn property_types(prop: &Property) -> String {
+    let types = match prop.params.get("TYPE") {
+        Some(x) => x.trim_matches('"').to_uppercase(),
+        None => String::new()
+    };
+    if prop.params.contains_key("PREF") && !types.split(',').any(|x| x.trim() == "PREF") {
+        if types.is_empty() { "PREF".to_owned() } else { format!("{},PREF", types) }
+    } else {
+        types
+    }
+}
+
+/// How much a property is preferred over others of the same name, from 1 (most) to 100 as given
+/// by the `PREF` parameter of vCard 4.0. The `PREF` type of vCard 3.0 counts as 1, properties
+/// with neither as 101.
+pub fn preference(prop: &Property) -> u8 {
+    if let Some(x) = prop.params.get("PREF") {
+        return match x.trim_matches('"').parse() {
+            Ok(x) if x >= 1 && x <= 100 => x,
+            _ => 100
+        };
+    }
+    match prop.params.get("TYPE") {
+        Some(x) if x.trim_matches('"').split(',').any(|x| x.trim().to_uppercase() == "PREF") => 1,
+        _ => 101
+    }
+}
+
+/// The given properties with the preferred ones first, see `preference`, otherwise in the order
+/// of the file.
+pub fn by_preference(props: &[Property]) -> Vec<&Property> {
+    let mut rv: Vec<&Property> = props.iter().collect();
+    rv.sort_by_key(|x| preference(x));
+    rv
+}
+
+/// Set the `TYPE` parameter of `prop`, a comma-separated list like `HOME,PREF`, as written in the
+/// given vCard version: in vCard 4.0, `PREF` becomes `PREF=1` and the other types are lowercase.
+pub fn set_property_types(prop: &mut Property, types: &str, version: VcardVersion) {
+    let mut types: Vec<String> = types.split(',').map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect();
+    if version == VcardVersion::V4 {
+        let len = types.len();
+        types.retain(|x| x.to_uppercase() != "PREF");
+        if types.len() < len {
+            prop.params.insert("PREF".to_owned(), "1".to_owned());
+        }
+        types = types.iter().map(|x| x.to_lowercase()).collect();
+    }
+    if !types.is_empty() {
+        prop.params.insert("TYPE".to_owned(), types.join(","));
+    }
+}
+
+/// Join repeated `TYPE` parameters in the content lines of a vCard, e.g.
+/// `EMAIL;TYPE=INTERNET;TYPE=HOME:...` as written by Google and allowed by vCard 4.0, which would
+/// otherwise keep only one.
+pub fn join_type_params(input: &str) -> String {
+    let mut rv = String::with_capacity(input.len());
+    for line in input.lines() {
+        let line = line.trim_right_matches('\r');
+        let colon = if line.starts_with(' ') || line.starts_with('\t') || line.contains('"') {
+            None
+        } else {
+            line.find(':')
+        };
+        match colon {
+            Some(i) if line[..i].to_uppercase().matches(";TYPE=").count() > 1 => {
+                let mut params = vec![];
+                let mut types = vec![];
+                for param in line[..i].split(';') {
+                    if param.to_uppercase().starts_with("TYPE=") {
+                        types.push(&param[5..]);
+                    } else {
+                        params.push(param.to_owned());
+                    }
+                }
+                params.push(format!("TYPE={}", types.join(",")));
+                rv.push_str(&params.join(";"));
+                rv.push_str(&line[i..]);
+            },
+            _ => rv.push_str(line)
+        }
+        rv.push_str("\r\n");
+    }
+    rv
+}
+
+
+/// Generate the index lines for the given contact, see `index_items_from_contact`.
+pub fn index_item_from_contact(contact: &Contact, filepath: &path::Path, collection: &str,
+                               fields: &[String], order: NameOrder) -> io::Result<String> {
+    let items = try!(index_items_from_contact(contact, filepath, collection, fields, order));
+    Ok(items.iter().map(|x| x.to_line()).collect())
+}
+
+/// Split a raw, comma-separated property value such as CATEGORIES into its unescaped parts.
+pub fn split_list_value(raw_value: &str) -> Vec<String> {
+    split_value(raw_value, ',')
+}
+
+/// Split a raw property value on unescaped `sep` characters, unescaping and trimming the parts.
+/// Empty parts are dropped.
+pub fn split_value(raw_value: &str, sep: char) -> Vec<String> {
+    let mut rv = vec![];
+    let mut current = String::new();
+    let mut chars = raw_value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+            },
+            x if x == sep => rv.push(current.split_off(0)),
+            _ => current.push(c)
+        }
+    }
+    rv.push(current);
+    rv.into_iter()
+        .map(|x| unescape_chars(x.trim()))
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
 /// Return a tuple (fullname, email)
 pub fn parse_from_header<'a>(s: &'a String) -> (Option<&'a str>, Option<&'a str>) {
     let mut split = s.rsplitn(2, '<');
@@ -246,8 +1273,28 @@ pub fn read_sender_from_email(email: &str) -> Option<String> {
     None
 }
 
-/// Write sender from given email as .vcf file to given directory.
-pub fn add_contact_from_email(contact_dir: &path::Path, email_input: &str) -> io::Result<Contact> {
+/// Given an email, return the parsed value of the Date header.
+pub fn read_date_from_email(email: &str) -> Option<DateTime<Utc>> {
+    let mut parser = Rfc5322Parser::new(email);
+    while !parser.eof() {
+        match parser.consume_header() {
+            Some(header) => {
+                if header.name == "Date" {
+                    return header.get_value().ok()
+                };
+            },
+            None => return None
+        };
+    };
+    None
+}
+
+/// Write sender from given email as .vcf file to `MATES_DIR`.
+///
+/// If `rev_from_date` is set, the email's Date header is used as the contact's REV, falling back
+/// to the current time if it's missing or can't be parsed.
+pub fn add_contact_from_email(config: &Configuration, email_input: &str,
+                              rev_from_date: bool) -> io::Result<Contact> {
     let from_header = match read_sender_from_email(email_input) {
         Some(x) => x,
         None => return Err(io::Error::new(
@@ -256,17 +1303,258 @@ pub fn add_contact_from_email(contact_dir: &path::Path, email_input: &str) -> io
         ))
     };
     let (fullname, email) = parse_from_header(&from_header);
-    let contact = Contact::generate(fullname, email, contact_dir);
+    let rev = if rev_from_date {
+        read_date_from_email(email_input).unwrap_or_else(Utc::now)
+    } else {
+        Utc::now()
+    };
+    let email_type = config.default_email_type.as_ref().map(|x| &x[..]);
+    let mut contact = Contact::generate(fullname, email, email_type, rev, config.vcard_version, &config.vdir_path);
+    try!(contact.check_roundtrip(&[("FN", fullname), ("EMAIL", email)]));
     try!(contact.write_create());
     Ok(contact)
 }
 
+/// Move the given file into the trash directory of its collection and record it in the trash log.
+/// Return the new path of the file.
+pub fn trash_file(config: &Configuration, filepath: &path::Path) -> io::Result<path::PathBuf> {
+    let trash_dir = config.trash_path(filepath);
+    try!(fs::create_dir_all(&trash_dir));
+
+    let filename = match filepath.file_name() {
+        Some(x) => x.to_string_lossy().into_owned(),
+        None => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Not a file: {}", filepath.display()),
+        ))
+    };
+
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
+    let trashed_path = {
+        let mut i = 0;
+        loop {
+            let candidate = trash_dir.join(&format!("{}-{}-{}", timestamp, i, filename));
+            if !(*candidate).exists() {
+                break candidate
+            }
+            i += 1;
+        }
+    };
+
+    // Remember the absolute path, the working directory might be a different one when restoring.
+    let original_path = try!(fs::canonicalize(filepath));
+    try!(fs::rename(&original_path, &trashed_path));
+
+    let log_path = config.trash_log_path();
+    if let Some(x) = log_path.parent() {
+        try!(fs::create_dir_all(x));
+    }
+    let mut log = try!(fs::OpenOptions::new()
+                       .append(true)
+                       .create(true)
+                       .open(&log_path));
+    try!(log.write_all(format!("{}\t{}\t{}\n",
+                               timestamp,
+                               original_path.display(),
+                               trashed_path.display()).as_bytes()));
+    Ok(trashed_path)
+}
+
+/// Move the most recently trashed file back to where it came from. Return its path, or `None` if
+/// the trash is empty.
+pub fn restore_from_trash(config: &Configuration) -> io::Result<Option<path::PathBuf>> {
+    let log_path = config.trash_log_path();
+    if !(*log_path).exists() {
+        return Ok(None);
+    }
+
+    let log = {
+        let mut x = String::new();
+        try!(try!(fs::File::open(&log_path)).read_to_string(&mut x));
+        x
+    };
 
-fn command_from_config(config_val: &str) -> process::Command {
-    let mut parts = config_val.split(' ');
-    let main = parts.next().unwrap();
-    let rest: Vec<_> = parts.map(|x| x.to_string()).collect();
-    let mut rv = process::Command::new(main);
-    rv.args(&rest[..]);
+    let mut lines: Vec<&str> = log.lines().filter(|x| !x.trim().is_empty()).collect();
+    let (original_path, trashed_path) = match lines.pop() {
+        Some(line) => {
+            let mut parts = line.split('\t').skip(1);
+            match (parts.next(), parts.next()) {
+                (Some(a), Some(b)) => (path::PathBuf::from(a), path::PathBuf::from(b)),
+                _ => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid line in trash log: {}", line),
+                ))
+            }
+        },
+        None => return Ok(None)
+    };
+
+    if (*original_path).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Can't restore, file already exists: {}", original_path.display()),
+        ));
+    }
+    try!(fs::rename(&trashed_path, &original_path));
+
+    let af = AtomicFile::new(&log_path, AllowOverwrite);
+    try!(af.write(|f| {
+        for line in lines.iter() {
+            try!(f.write_all(line.as_bytes()));
+            try!(f.write_all(b"\n"));
+        }
+        Ok(())
+    }));
+    Ok(Some(original_path))
+}
+
+/// Rough syntax check for an email address: a non-empty local part and domain, separated by `@`,
+/// without whitespace.
+pub fn is_valid_email(email: &str) -> bool {
+    let mut parts = email.rsplitn(2, '@');
+    let domain = parts.next().unwrap_or("");
+    let local = parts.next().unwrap_or("");
+    !local.is_empty() && !domain.is_empty() && !domain.contains('@') &&
+        !email.chars().any(|c| c.is_whitespace()) &&
+        !domain.starts_with('.') && !domain.ends_with('.')
+}
+
+/// Levenshtein distance between two strings, in chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = *[prev[j + 1] + 1, cur[j] + 1, prev[j] + cost].iter().min().unwrap();
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Normalize a name for comparisons: lowercase, without punctuation, words sorted.
+pub fn name_key(name: &str) -> String {
+    let lower = name.to_lowercase();
+    let mut words: Vec<String> = lower
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_owned())
+        .collect();
+    words.sort();
+    words.join(" ")
+}
+
+
+/// Split a command line into words like a shell would, honouring single and double quotes and
+/// backslash escapes. Fails for unterminated quotes.
+pub fn split_shell_words(s: &str) -> Result<Vec<String>, String> {
+    let mut rv = vec![];
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(x) => current.push(x),
+                        None => return Err("Unterminated single quote.".to_owned())
+                    }
+                }
+            },
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(x) if x == '"' || x == '\\' => current.push(x),
+                            Some(x) => { current.push('\\'); current.push(x); },
+                            None => return Err("Unterminated double quote.".to_owned())
+                        },
+                        Some(x) => current.push(x),
+                        None => return Err("Unterminated double quote.".to_owned())
+                    }
+                }
+            },
+            '\\' => {
+                in_word = true;
+                current.extend(chars.next());
+            },
+            c if c.is_whitespace() => {
+                if in_word {
+                    rv.push(mem::replace(&mut current, String::new()));
+                    in_word = false;
+                }
+            },
+            c => {
+                in_word = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_word {
+        rv.push(current);
+    }
+    Ok(rv)
+}
+
+/// A command from a configured program and its arguments, as split by `split_shell_words`.
+pub fn command_from_config(words: &[String]) -> process::Command {
+    let mut rv = process::Command::new(&words[0]);
+    rv.args(&words[1..]);
     rv
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use chrono::Utc;
+    use vobject::parse_component;
+
+    use super::{Contact,VcardVersion,write_component_sorted};
+
+    fn roundtrip(fullname: &str) -> Contact {
+        let email = "john@example.com";
+        let mut contact = Contact::generate(Some(fullname), Some(email), None, Utc::now(), VcardVersion::V4,
+                                            &env::temp_dir());
+        contact.check_roundtrip(&[("FN", Some(fullname)), ("EMAIL", Some(email))]).unwrap();
+        contact
+    }
+
+    fn written_fullname(contact: &Contact) -> String {
+        let reparsed = parse_component(&write_component_sorted(&contact.component)[..]).unwrap();
+        reparsed.get_only("FN").unwrap().value_as_string()
+    }
+
+    #[test]
+    fn special_characters_in_display_names() {
+        for fullname in &["Doe, John", "Doe; John", "John \\ Doe", "Doe\\, John;", "\\", "C:\\Users\\john"] {
+            assert_eq!(&written_fullname(&roundtrip(fullname)), fullname);
+        }
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        let contact = roundtrip("John\rDoe\t,");
+        assert_eq!(contact.component.get_only("FN").unwrap().raw_value, "John\\nDoe \\,");
+        assert_eq!(written_fullname(&contact), "John\nDoe ,");
+    }
+
+    #[test]
+    fn unrepresentable_display_names_fail() {
+        let fullname = "C:\\Nina";
+        let mut contact = Contact::generate(Some(fullname), None, None, Utc::now(), VcardVersion::V4,
+                                            &env::temp_dir());
+        assert!(contact.check_roundtrip(&[("FN", Some(fullname))]).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+//! Reading vCard 2.1, as exported by old phones.
+//!
+//! vCard 2.1 values may be encoded with `ENCODING=QUOTED-PRINTABLE`, whose lines are continued
+//! with a trailing `=` instead of a leading space, and in another charset than UTF-8, given with
+//! `CHARSET`. Types are written as bare parameters like `TEL;HOME;VOICE`. Such vCards are
+//! converted into vCard 3.0 before they are parsed, so they are imported and indexed like any
+//! other contact.
+//!
+//! Files starting with a byte order mark are decoded by it, since older versions of macOS
+//! Contacts export vCards as UTF-16.
+
+use std::str;
+
+/// Decode the contents of a file with one or several vCards into vCard 3.0 text. Files that are
+/// valid UTF-8 and contain no vCard 2.1 are returned unchanged, other byte values than UTF-8 are
+/// read as Latin-1.
+pub fn decode_vcards(bytes: &[u8]) -> String {
+    if bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff]) {
+        return decode_vcards(decode_utf16(&bytes[2..], bytes[0] == 0xfe).as_bytes());
+    }
+    let bytes = if bytes.starts_with(b"\xef\xbb\xbf") { &bytes[3..] } else { bytes };
+
+    if let Ok(s) = str::from_utf8(bytes) {
+        if !s.lines().any(is_version_21) {
+            return s.to_owned();
+        }
+    }
+
+    let mut rv = String::new();
+    let mut card: Vec<&[u8]> = vec![];
+    let mut depth = 0;
+    for line in bytes.split(|&b| b == b'\n') {
+        let line = trim_cr(line);
+        let upper = decode_text(line, None).to_uppercase();
+        if upper.starts_with("BEGIN:") {
+            depth += 1;
+        }
+        if depth > 0 {
+            card.push(line);
+        } else {
+            rv.push_str(&decode_text(line, None));
+            rv.push_str("\r\n");
+        }
+        if upper.starts_with("END:") && depth > 0 {
+            depth -= 1;
+            if depth == 0 {
+                rv.push_str(&decode_card(&card));
+                card.clear();
+            }
+        }
+    }
+    rv.push_str(&decode_card(&card));
+    rv
+}
+
+fn decode_utf16(bytes: &[u8], big_endian: bool) -> String {
+    let units: Vec<u16> = bytes.chunks(2)
+        .filter(|x| x.len() == 2)
+        .map(|x| if big_endian { (x[0] as u16) << 8 | x[1] as u16 } else { (x[1] as u16) << 8 | x[0] as u16 })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn is_version_21(line: &str) -> bool {
+    line.trim().to_uppercase() == "VERSION:2.1"
+}
+
+fn trim_cr(line: &[u8]) -> &[u8] {
+    match line.last() {
+        Some(&b'\r') => &line[..line.len() - 1],
+        _ => line
+    }
+}
+
+/// Convert the lines of one vCard. vCards of other versions than 2.1 are only decoded.
+fn decode_card(lines: &[&[u8]]) -> String {
+    let mut rv = String::new();
+    if !lines.iter().any(|x| is_version_21(&decode_text(x, None))) {
+        for line in lines {
+            rv.push_str(&decode_text(line, None));
+            rv.push_str("\r\n");
+        }
+        return rv;
+    }
+
+    let mut logical: Vec<Vec<u8>> = vec![];
+    let mut continued = false;
+    for line in lines {
+        if continued || line.first().map_or(false, |&b| b == b' ' || b == b'\t') {
+            if let Some(last) = logical.last_mut() {
+                last.extend_from_slice(if continued { line } else { &line[1..] });
+            }
+        } else if !line.is_empty() {
+            logical.push(line.to_vec());
+        }
+        // Quoted-printable lines ending with `=` are soft line breaks.
+        continued = match logical.last() {
+            Some(x) => x.last() == Some(&b'=') && is_quoted_printable(x),
+            None => false
+        };
+        if continued {
+            logical.last_mut().unwrap().pop();
+        }
+    }
+
+    for line in logical {
+        rv.push_str(&convert_line(&line));
+        rv.push_str("\r\n");
+    }
+    rv
+}
+
+/// Split a content line into the part before the first unquoted colon and the value.
+fn split_line(line: &[u8]) -> (&[u8], &[u8]) {
+    let mut quoted = false;
+    for (i, &b) in line.iter().enumerate() {
+        match b {
+            b'"' => quoted = !quoted,
+            b':' if !quoted => return (&line[..i], &line[i + 1..]),
+            _ => ()
+        }
+    }
+    (line, &[])
+}
+
+fn is_quoted_printable(line: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(split_line(line).0).to_uppercase();
+    head.split(';').skip(1).any(|x| x == "QUOTED-PRINTABLE" || x == "ENCODING=QUOTED-PRINTABLE")
+}
+
+/// Convert one unfolded vCard 2.1 line into vCard 3.0: decode its value, turn bare parameters
+/// into `TYPE` and drop `ENCODING` and `CHARSET` parameters that no longer apply.
+fn convert_line(line: &[u8]) -> String {
+    let (head, value) = split_line(line);
+    let head = decode_text(head, None);
+    let mut params = head.split(';');
+    let name = params.next().unwrap_or("").to_owned();
+
+    let mut types: Vec<String> = vec![];
+    let mut other = vec![];
+    let mut quoted_printable = false;
+    let mut charset = None;
+    for param in params.filter(|x| !x.is_empty()) {
+        let (key, val) = match param.find('=') {
+            Some(i) => (param[..i].to_uppercase(), param[i + 1..].to_owned()),
+            None => ("".to_owned(), param.to_owned())
+        };
+        match (&key[..], &val.to_uppercase()[..]) {
+            ("", "QUOTED-PRINTABLE") | ("ENCODING", "QUOTED-PRINTABLE") => quoted_printable = true,
+            ("", "8BIT") | ("", "7BIT") | ("ENCODING", "8BIT") | ("ENCODING", "7BIT") => (),
+            ("", "BASE64") | ("ENCODING", "BASE64") => other.push("ENCODING=b".to_owned()),
+            ("CHARSET", _) => charset = Some(val.to_lowercase()),
+            ("", _) | ("TYPE", _) => types.extend(val.split(',').map(|x| x.to_owned())),
+            _ => other.push(param.to_owned())
+        }
+    }
+
+    let value = if quoted_printable { decode_quoted_printable(value) } else { value.to_vec() };
+    let mut value = decode_text(&value, charset.as_ref().map(|x| &x[..]))
+        .replace("\r\n", "\\n")
+        .replace("\n", "\\n");
+    if name.to_uppercase() == "VERSION" {
+        value = "3.0".to_owned();
+    }
+
+    let mut rv = name;
+    if !types.is_empty() {
+        rv.push_str(";TYPE=");
+        rv.push_str(&types.join(","));
+    }
+    for param in other {
+        rv.push(';');
+        rv.push_str(&param);
+    }
+    rv.push(':');
+    rv.push_str(&value);
+    rv
+}
+
+fn decode_quoted_printable(value: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|x| x as u8);
+    let mut rv = vec![];
+    let mut i = 0;
+    while i < value.len() {
+        if value[i] == b'=' && i + 2 < value.len() {
+            if let (Some(a), Some(b)) = (hex(value[i + 1]), hex(value[i + 2])) {
+                rv.push(a * 16 + b);
+                i += 3;
+                continue;
+            }
+        }
+        rv.push(value[i]);
+        i += 1;
+    }
+    rv
+}
+
+/// Decode bytes in the given charset, by default UTF-8. Bytes that aren't valid UTF-8 are read as
+/// Latin-1, as are other charsets than Windows-1252.
+fn decode_text(bytes: &[u8], charset: Option<&str>) -> String {
+    match charset {
+        None | Some("utf-8") | Some("utf8") | Some("us-ascii") => match str::from_utf8(bytes) {
+            Ok(x) => x.to_owned(),
+            Err(_) => bytes.iter().map(|&b| b as char).collect()
+        },
+        Some("windows-1252") | Some("cp1252") => bytes.iter().map(|&b| windows_1252_char(b)).collect(),
+        Some(_) => bytes.iter().map(|&b| b as char).collect()
+    }
+}
+
+/// The character of a Windows-1252 byte, which differs from Latin-1 in 0x80 to 0x9F.
+fn windows_1252_char(b: u8) -> char {
+    const HIGH: &'static str = "€\u{81}‚ƒ„…†‡ˆ‰Š‹Œ\u{8d}Ž\u{8f}\u{90}‘’“”•–—˜™š›œ\u{9d}žŸ";
+    match b {
+        0x80..=0x9f => HIGH.chars().nth((b - 0x80) as usize).unwrap(),
+        _ => b as char
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_vcards;
+
+    fn decode_21(lines: &[&str]) -> Vec<String> {
+        let mut input = "BEGIN:VCARD\r\nVERSION:2.1\r\n".to_owned();
+        for line in lines {
+            input.push_str(line);
+            input.push_str("\r\n");
+        }
+        input.push_str("END:VCARD\r\n");
+        decode_vcards(input.as_bytes()).lines()
+            .filter(|x| !x.is_empty() && !x.starts_with("BEGIN:") && !x.starts_with("END:") &&
+                    !x.starts_with("VERSION:"))
+            .map(|x| x.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn quoted_printable_soft_line_breaks() {
+        assert_eq!(decode_21(&["NOTE;ENCODING=QUOTED-PRINTABLE:first=", "second=", "third"]),
+                   vec!["NOTE:firstsecondthird"]);
+        // A soft line break may split an encoded character.
+        assert_eq!(decode_21(&["FN;CHARSET=UTF-8;QUOTED-PRINTABLE:Jos=C3=", "=A9"]), vec!["FN:José"]);
+    }
+
+    #[test]
+    fn quoted_printable_at_end_of_value() {
+        assert_eq!(decode_21(&["FN;ENCODING=QUOTED-PRINTABLE:Jos=C3=A9"]), vec!["FN:José"]);
+        // Encoded line breaks are escaped, equals signs without two hex digits are kept.
+        assert_eq!(decode_21(&["NOTE;ENCODING=QUOTED-PRINTABLE:a=0D=0Ab=zz=4"]), vec!["NOTE:a\\nb=zz=4"]);
+    }
+
+    #[test]
+    fn trailing_equals_without_quoted_printable() {
+        assert_eq!(decode_21(&["NOTE:a=", "FN:Bob"]), vec!["NOTE:a=", "FN:Bob"]);
+    }
+
+    #[test]
+    fn charsets() {
+        assert_eq!(decode_21(&["FN;CHARSET=ISO-8859-1;ENCODING=QUOTED-PRINTABLE:Jos=E9"]), vec!["FN:José"]);
+        assert_eq!(decode_21(&["NOTE;CHARSET=WINDOWS-1252;ENCODING=QUOTED-PRINTABLE:=80 =93x=94"]),
+                   vec!["NOTE:€ “x”"]);
+        // Unencoded Latin-1 bytes, as written by some phones.
+        let input = b"BEGIN:VCARD\r\nVERSION:2.1\r\nFN;CHARSET=ISO-8859-1:Jos\xe9\r\nEND:VCARD\r\n";
+        assert!(decode_vcards(input).contains("\r\nFN:José\r\n"));
+    }
+
+    #[test]
+    fn bare_type_parameters() {
+        assert_eq!(decode_21(&["TEL;HOME;VOICE:123", "EMAIL;INTERNET;TYPE=PREF:a@example.com"]),
+                   vec!["TEL;TYPE=HOME,VOICE:123", "EMAIL;TYPE=INTERNET,PREF:a@example.com"]);
+    }
+
+    #[test]
+    fn other_versions_unchanged() {
+        let input = "BEGIN:VCARD\r\nVERSION:3.0\r\nNOTE:a=\r\nEND:VCARD\r\n";
+        assert_eq!(decode_vcards(input.as_bytes()), input);
+    }
+
+    #[test]
+    fn utf16() {
+        let text = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:José\r\nEND:VCARD\r\n";
+        let mut input = vec![0xff, 0xfe];
+        for unit in text.encode_utf16() {
+            input.push(unit as u8);
+            input.push((unit >> 8) as u8);
+        }
+        assert_eq!(decode_vcards(&input), text);
+    }
+}